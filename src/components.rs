@@ -1,6 +1,11 @@
+use std::f32::consts::{PI, TAU};
+
 use anyhow::Result;
 
-use bevy::prelude::*;
+use bevy::{prelude::*, tasks::{ComputeTaskPool, ParallelSliceMut, TaskPool}};
+use serde::Deserialize;
+
+use crate::consts::{AGENT_DESIRED_SPEED, QUEUE_SPACING};
 
 
 
@@ -10,6 +15,57 @@ pub struct Agent;
 #[derive(Component)]
 pub struct Speed(pub Vec2);
 
+/// Walking speed of an agent when it differs from `AGENT_DESIRED_SPEED`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct DesiredSpeed(pub f32);
+
+impl DesiredSpeed {
+    /// Walking speed of an agent that may have a `DesiredSpeed`.
+    pub fn of(desired_speed: Option<&DesiredSpeed>) -> f32 {
+        desired_speed.map_or(AGENT_DESIRED_SPEED, |desired_speed| desired_speed.0)
+    }
+
+    /// Factor turning a vector of a field, sized for `AGENT_DESIRED_SPEED`,
+    /// into one sized for the agent.
+    pub fn field_scale(desired_speed: Option<&DesiredSpeed>) -> f32 {
+        Self::of(desired_speed) / AGENT_DESIRED_SPEED
+    }
+}
+
+/// Distribution a per-agent parameter or a duration is drawn from.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum Distribution {
+    Constant(f32),
+    Uniform { min: f32, max: f32 },
+    Normal { mean: f32, std_dev: f32 },
+}
+
+impl Distribution {
+    pub fn sample(&self, rng: &mut fastrand::Rng) -> f32 {
+        match *self {
+            Distribution::Constant(value) => value,
+            Distribution::Uniform { min, max } => min + (max - min) * rng.f32(),
+            Distribution::Normal { mean, std_dev } => {
+                // Box-Muller transform.
+                let radius = (-2. * (1. - rng.f32()).ln()).sqrt();
+                mean + std_dev * radius * (TAU * rng.f32()).cos()
+            },
+        }
+    }
+
+    pub fn mean(&self) -> f32 {
+        match *self {
+            Distribution::Constant(value) => value,
+            Distribution::Uniform { min, max } => (min + max) / 2.,
+            Distribution::Normal { mean, .. } => mean,
+        }
+    }
+}
+
+/// Agent standing in a region with no path to its destination.
+#[derive(Component)]
+pub struct Trapped;
+
 #[derive(Component)]
 pub struct MotivationForce(pub Vec2);
 
@@ -19,39 +75,471 @@ pub struct ObstacleForce(pub Vec2);
 #[derive(Component)]
 pub struct RepulsiveForce(pub Vec2);
 
+/// Index of the agent's entry in `NavigationClasses`. Agents without it follow
+/// the shared flow field.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AgentClass(pub usize);
+
 #[derive(Component)]
 pub struct Objective;
 
+/// Objective attached to another entity, such as a tour guide or a vehicle
+/// door. The objective only jumps to the entity's position when `update`
+/// finishes, which bounds how often its flow field is rebuilt.
+#[derive(Component)]
+pub struct Follow {
+    pub entity: Entity,
+    pub offset: Vec2,
+    pub update: Timer,
+}
+
+impl Follow {
+    pub fn new(entity: Entity, offset: Vec2, update_seconds: f32) -> Self {
+        Self {
+            entity,
+            offset,
+            update: Timer::from_seconds(update_seconds, TimerMode::Repeating),
+        }
+    }
+}
+
+/// Objective whose flow field the agent follows.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Destination(pub Entity);
+
+/// How an agent picks its `Destination` among the objectives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RouteChoiceModel {
+    /// Shortest route according to the flow field.
+    Nearest,
+    /// Keep to `preferred` unless its route is more than `tolerance` times
+    /// longer than the shortest one.
+    Familiar { preferred: Entity, tolerance: f32 },
+    /// Shortest estimated travel time, including the time spent queueing
+    /// behind the agents already waiting at the objective.
+    TravelTime,
+}
+
+/// Agent that chooses its destination itself, re-evaluated periodically.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct RouteChoice(pub RouteChoiceModel);
+
+#[derive(Clone, Copy, Debug)]
+pub struct ItineraryStop {
+    pub destination: Entity,
+    /// Time the agent waits at the stop before leaving for the next one.
+    pub dwell_seconds: f32,
+}
+
+/// Ordered stops an agent visits. The agent is only removed once it reaches
+/// the last one.
+#[derive(Component, Clone, Debug)]
+pub struct Itinerary {
+    pub stops: Vec<ItineraryStop>,
+    pub next_stop: usize,
+}
+
+impl Itinerary {
+    pub fn current_stop(&self) -> Option<&ItineraryStop> {
+        self.stops.get(self.next_stop)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next_stop >= self.stops.len()
+    }
+}
+
+/// Agent waiting at an itinerary stop.
+#[derive(Component)]
+pub struct Dwell(pub Timer);
+
+/// Objective letting agents through at a limited rate, such as a door or a
+/// row of turnstiles. Agents reaching it line up and each of its `servers`
+/// lets one of them through at a time.
+#[derive(Component, Clone, Debug)]
+pub struct Sink {
+    pub servers: usize,
+    /// Time a server takes to let one agent through.
+    pub service_seconds: Distribution,
+    /// Waiting agents, the first in line first. The first ones, as many as
+    /// there are free servers, are admitted and walk up to the sink.
+    pub queue: Vec<Entity>,
+    /// Agents going through and their remaining service time.
+    pub in_service: Vec<(Entity, f32)>,
+    /// Direction, away from the sink, in which the line grows.
+    pub queue_direction: Vec2,
+}
+
+impl Sink {
+    pub fn new(servers: usize, service_seconds: Distribution) -> Self {
+        Self {
+            servers,
+            service_seconds,
+            queue: Vec::new(),
+            in_service: Vec::new(),
+            queue_direction: Vec2::X,
+        }
+    }
+
+    /// Door letting at most `rate` agents through per second.
+    pub fn with_throughput(rate: f32) -> Self {
+        Self::new(1, Distribution::Constant(1. / rate))
+    }
+
+    /// Average number of agents let through per second.
+    pub fn throughput(&self) -> f32 {
+        self.servers as f32 / self.service_seconds.mean().max(f32::EPSILON)
+    }
+
+    pub fn free_servers(&self) -> usize {
+        self.servers.saturating_sub(self.in_service.len())
+    }
+
+    /// Where the first `count` waiting agents stand, counting from the first
+    /// one that is not admitted yet.
+    ///
+    /// The line starts along `queue_direction` and bends around the places
+    /// rejected by `is_free`, such as walls: each place is taken on the
+    /// current heading, or on the heading turned the least either way that
+    /// lands on a free place away from the rest of the line. Places with no
+    /// such heading stay straight ahead.
+    pub fn waiting_positions(&self, center: Vec2, shape: &Shape, count: usize, is_free: impl Fn(Vec2) -> bool) -> Vec<Vec2> {
+
+        let mut positions: Vec<Vec2> = Vec::with_capacity(count);
+        let mut heading = self.queue_direction;
+
+        for _ in 0..count {
+            let place = |direction: Vec2| match positions.last() {
+                Some(&previous) => previous + direction * QUEUE_SPACING,
+                None => center + direction * (shape.extent(direction) + QUEUE_SPACING),
+            };
+
+            let is_available = |position: Vec2| is_free(position)
+                && positions.iter().all(|other| other.distance(position) >= QUEUE_SPACING / 2.);
+
+            let direction = (0..=QUEUE_TURNS)
+                .flat_map(|turn| [turn as f32, -(turn as f32)])
+                .map(|turn| Vec2::from_angle(turn * PI / 8.).rotate(heading))
+                .find(|&direction| is_available(place(direction)))
+                .unwrap_or(heading);
+
+            let position = place(direction);
+
+            heading = direction;
+            positions.push(position);
+        }
+
+        positions
+    }
+}
+
+/// Eighths of a half turn a queue may bend by at each place.
+const QUEUE_TURNS: usize = 6;
+
+/// Agent lined up at a `Sink`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Queued(pub Entity);
+
 #[derive(Component)]
 pub struct Obstacle;
 
+/// Labelled area, such as "staff_only" or "stairs", that navigation classes can
+/// forbid or make more expensive to cross.
 #[derive(Component)]
+pub struct Zone{
+    pub label: String,
+}
+
+/// Footprint of an entity, relative to its position.
+#[derive(Component, Clone, Debug, Deserialize)]
 pub enum Shape {
     Circle(f32),
+    /// Axis-aligned rectangle of the given size, centred on the position.
+    Rectangle(Vec2),
+    /// Closed outline.
+    Polygon(Vec<Vec2>),
+    /// Open line of the given thickness, such as a wall.
+    Polyline { points: Vec<Vec2>, thickness: f32 },
 }
 
 impl Shape {
+    /// Shape of an outline in world coordinates, and its position. Outlines
+    /// that are not `solid` become lines of `thickness`, looping back to their
+    /// start when `closed`, such as the walls of a room.
+    pub fn from_outline(mut points: Vec<Vec2>, solid: bool, closed: bool, thickness: f32) -> Option<(Vec2, Shape)> {
+
+        points.dedup();
+
+        if closed && points.len() > 1 && points.first() == points.last() {
+            points.pop();
+        }
+
+        let bounds = bounds(&points)?;
+        let center = bounds.center();
+
+        let is_axis_aligned = segments(&points, true)
+            .all(|(a, b)| (a.x - b.x).abs() < 1e-4 || (a.y - b.y).abs() < 1e-4);
+
+        if solid && points.len() == 4 && is_axis_aligned {
+            return Some((center, Shape::Rectangle(bounds.size())));
+        }
+
+        let mut local: Vec<Vec2> = points.iter().map(|&point| point - center).collect();
+
+        match solid {
+            true if local.len() >= 3 => Some((center, Shape::Polygon(local))),
+            _ if local.len() >= 2 => {
+                if closed {
+                    local.push(local[0]);
+                }
+
+                Some((center, Shape::Polyline { points: local, thickness }))
+            },
+            _ => None,
+        }
+    }
+
     pub fn get_rectangle_with_center(&self, center: Vec2) -> Rect{
         match self {
             Shape::Circle(r) => Rect::from_center_half_size(center, Vec2::new(*r, *r)),
+            Shape::Rectangle(size) => Rect::from_center_size(center, *size),
+            Shape::Polygon(points) => bounds(points).map_or(Rect::from_center_size(center, Vec2::ZERO), |rect| offset_rect(rect, center)),
+            Shape::Polyline { points, thickness } => bounds(points)
+                .map_or(Rect::from_center_size(center, Vec2::ZERO), |rect| offset_rect(rect, center).inflate(thickness / 2.)),
         }
     }
+
+    /// Area covered by the shape, leaving out the rounded ends of lines.
+    pub fn area(&self) -> f32 {
+        match self {
+            Shape::Circle(r) => PI * r * r,
+            Shape::Rectangle(size) => size.x.max(0.) * size.y.max(0.),
+            Shape::Polygon(points) => segments(points, true).map(|(a, b)| a.perp_dot(b)).sum::<f32>().abs() / 2.,
+            Shape::Polyline { points, thickness } => segments(points, false).map(|(a, b)| a.distance(b)).sum::<f32>() * thickness.max(0.),
+        }
+    }
+
+    pub fn contains(&self, center: Vec2, point: Vec2) -> bool {
+        match self {
+            Shape::Circle(r) => point.distance_squared(center) <= r * r,
+            Shape::Rectangle(size) => ((point - center).abs() - *size / 2.).max_element() <= 0.,
+            Shape::Polygon(points) => contains_point(points, point - center),
+            Shape::Polyline { .. } => self.distance(center, point) <= 0.,
+        }
+    }
+
+    /// Signed distance from `point` to the outline, negative inside.
+    pub fn distance(&self, center: Vec2, point: Vec2) -> f32 {
+        self.distance_with_normal(center, point).0
+    }
+
+    /// Signed distance from `point` to the outline, negative inside, and the
+    /// direction pointing away from the shape at `point`.
+    pub fn distance_with_normal(&self, center: Vec2, point: Vec2) -> (f32, Vec2) {
+        let local = point - center;
+
+        match self {
+            Shape::Circle(r) => (local.length() - r, local.normalize_or_zero()),
+            Shape::Rectangle(size) => {
+                let half = *size / 2.;
+                Shape::Polygon(vec![-half, Vec2::new(half.x, -half.y), half, Vec2::new(-half.x, half.y)]).distance_with_normal(Vec2::ZERO, local)
+            },
+            Shape::Polygon(points) => {
+                let (distance, normal) = distance_to_segments(segments(points, true), local);
+
+                match contains_point(points, local) {
+                    true => (-distance, -normal),
+                    false => (distance, normal),
+                }
+            },
+            Shape::Polyline { points, thickness } => {
+                let (distance, normal) = distance_to_segments(segments(points, false), local);
+                (distance - thickness / 2., normal)
+            },
+        }
+    }
+
+    /// Distance from the position of the shape to its outline along
+    /// `direction`.
+    pub fn extent(&self, direction: Vec2) -> f32 {
+        let mut distance = 0.;
+
+        for _ in 0..32 {
+            let step = -self.distance(Vec2::ZERO, direction * distance);
+
+            if step <= 1e-3 {
+                break;
+            }
+
+            distance += step;
+        }
+
+        distance
+    }
+}
+
+pub(crate) fn bounds(points: &[Vec2]) -> Option<Rect> {
+    let first = *points.first()?;
+    Some(points.iter().fold(Rect::from_center_size(first, Vec2::ZERO), |rect, &point| rect.union_point(point)))
+}
+
+fn offset_rect(rect: Rect, offset: Vec2) -> Rect {
+    Rect { min: rect.min + offset, max: rect.max + offset }
+}
+
+/// Edges of a polyline, closed back to its first point when `closed`.
+fn segments(points: &[Vec2], closed: bool) -> impl Iterator<Item = (Vec2, Vec2)> + '_ {
+    let closing = match (closed, points.first(), points.last()) {
+        (true, Some(&first), Some(&last)) if points.len() > 2 => Some((last, first)),
+        _ => None,
+    };
+
+    points.windows(2)
+        .map(|pair| (pair[0], pair[1]))
+        .chain(closing)
+}
+
+fn distance_to_segments(segments: impl Iterator<Item = (Vec2, Vec2)>, point: Vec2) -> (f32, Vec2) {
+    segments
+        .map(|(start, end)| {
+            let along = end - start;
+            let t = ((point - start).dot(along) / along.length_squared().max(f32::EPSILON)).clamp(0., 1.);
+            let away = point - (start + along * t);
+
+            (away.length(), away.normalize_or_zero())
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap_or((f32::INFINITY, Vec2::ZERO))
+}
+
+/// Even-odd test of `point` against a closed outline.
+pub(crate) fn contains_point(points: &[Vec2], point: Vec2) -> bool {
+    segments(points, true)
+        .filter(|(start, end)| (start.y > point.y) != (end.y > point.y)
+            && point.x < start.x + (point.y - start.y) / (end.y - start.y) * (end.x - start.x))
+        .count() % 2 == 1
+}
+
+/// Values that can be blended when sampling a [`GridMap`] between cell centres.
+pub trait Interpolate: Copy {
+    /// Whether the value can take part in an interpolation.
+    fn is_valid_sample(&self) -> bool;
+
+    fn weighted_sum(samples: &[(Self, f32)]) -> Self;
 }
 
-#[derive(Resource)]
+impl Interpolate for f32 {
+    fn is_valid_sample(&self) -> bool {
+        self.is_finite()
+    }
+
+    fn weighted_sum(samples: &[(Self, f32)]) -> Self {
+        samples.iter().fold(0., |acc, (value, weight)| acc + value * weight)
+    }
+}
+
+impl Interpolate for Vec2 {
+    fn is_valid_sample(&self) -> bool {
+        self.is_finite()
+    }
+
+    fn weighted_sum(samples: &[(Self, f32)]) -> Self {
+        samples.iter().fold(Vec2::ZERO, |acc, (value, weight)| acc + *value * *weight)
+    }
+}
+
+/// Offsets of the cells sharing an edge with a cell.
+pub const NEIGHBOURS_4: [IVec2; 4] = [
+    IVec2::new(1, 0),
+    IVec2::new(-1, 0),
+    IVec2::new(0, 1),
+    IVec2::new(0, -1),
+];
+
+/// Offsets of the cells sharing an edge or a corner with a cell.
+pub const NEIGHBOURS_8: [IVec2; 8] = [
+    IVec2::new(-1, -1),
+    IVec2::new(-1, 0),
+    IVec2::new(-1, 1),
+    IVec2::new(0, -1),
+    IVec2::new(0, 1),
+    IVec2::new(1, -1),
+    IVec2::new(1, 0),
+    IVec2::new(1, 1),
+];
+
+/// Values of a [`GridMap`].
+#[derive(Clone)]
+enum GridStorage<T> where T: Clone + Copy{
+    /// One value per cell, row by row.
+    Dense(Vec<T>),
+    /// Square chunks of `size` x `size` cells, row by row, allocated on their
+    /// first write. Cells of chunks that were never written hold `default`.
+    Chunked {
+        size: usize,
+        chunk_columns: usize,
+        chunks: Vec<Option<Box<[T]>>>,
+        default: T,
+    },
+}
+
+#[derive(Resource, Clone)]
 pub struct GridMap<T> where T: Clone + Copy{
 
-    grid: Vec<T>,
+    grid: GridStorage<T>,
     pub cell_dimentions: Vec2,
     pub columns: usize,
     pub rows:usize,
     pub area: Rect,
 
+    dirty_region: Option<IRect>,
 }
 
 impl<T> GridMap<T> where T: Clone + Copy{
     pub fn new(columns: usize, rows: usize, area: Rect, default_value: T) -> Self {
-        
+
+        let total_cels = columns * rows;
+
+        Self::with_storage(columns, rows, area, GridStorage::Dense(vec![default_value; total_cels]))
+    }
+
+    /// Dense map, or chunked map when `chunk_size` is set.
+    pub fn new_layer(columns: usize, rows: usize, area: Rect, default_value: T, chunk_size: Option<usize>) -> Self {
+        match chunk_size {
+            Some(chunk_size) => Self::new_chunked(columns, rows, area, default_value, chunk_size),
+            None => Self::new(columns, rows, area, default_value),
+        }
+    }
+
+    /// Map storing its cells in square chunks of `chunk_size` cells per side.
+    ///
+    /// Chunks are only allocated when one of their cells is written, so maps
+    /// covering large, mostly untouched areas stay cheap. Reading a cell of an
+    /// unallocated chunk returns `default_value`.
+    pub fn new_chunked(columns: usize, rows: usize, area: Rect, default_value: T, chunk_size: usize) -> Self {
+
+        let size = chunk_size.max(1);
+        let chunk_columns = columns.div_ceil(size);
+        let chunk_count = chunk_columns * rows.div_ceil(size);
+
+        Self::with_storage(columns, rows, area, GridStorage::Chunked {
+            size,
+            chunk_columns,
+            chunks: vec![None; chunk_count],
+            default: default_value,
+        })
+    }
+
+    /// Empty map with the same shape and kind of storage as this one.
+    pub fn new_like<U>(&self, default_value: U) -> GridMap<U> where U: Clone + Copy{
+        match self.grid {
+            GridStorage::Dense(_) => GridMap::new(self.columns, self.rows, self.area, default_value),
+            GridStorage::Chunked { size, .. } => GridMap::new_chunked(self.columns, self.rows, self.area, default_value, size),
+        }
+    }
+
+    fn with_storage(columns: usize, rows: usize, area: Rect, grid: GridStorage<T>) -> Self {
+
         let lengths = (area.max - area.min).abs();
 
         let cell_length = lengths.x / columns as f32;
@@ -59,43 +547,265 @@ impl<T> GridMap<T> where T: Clone + Copy{
 
         let cell_dimentions = Vec2::new(cell_length, cell_height);
 
-        let total_cels = columns * rows;
-
-        let grid = vec![default_value; total_cels];
-
         Self { 
             grid,
             cell_dimentions,
             columns,
             rows,
-            area
+            area,
+            dirty_region: Some(IRect::new(0, 0, columns as i32, rows as i32)),
         }
     }
 
     pub fn reset(&mut self, default_value: T){
-        for i in 0..self.grid.len(){
-            self.grid[i] = default_value;
+        match &mut self.grid {
+            GridStorage::Dense(values) => values.fill(default_value),
+            GridStorage::Chunked { chunks, default, .. } => {
+                chunks.iter_mut().for_each(|chunk| *chunk = None);
+                *default = default_value;
+            },
         }
+
+        self.mark_dirty(self.full_region());
     }
-    pub fn get_cell(&self, pos: Vec2) -> Option<IVec2>{
 
-        let coords = self.get_cell_unsafe(pos);
+    /// Regions of the map that may hold values other than the default one:
+    /// the allocated chunks of a chunked map, or the whole of a dense map.
+    /// Systems can skip the cells outside of them.
+    pub fn chunk_regions(&self) -> Vec<IRect>{
+        match &self.grid {
+            GridStorage::Dense(_) => vec![self.full_region()],
+            GridStorage::Chunked { size, chunk_columns, chunks, .. } => chunks.iter()
+                .enumerate()
+                .filter(|(_, chunk)| chunk.is_some())
+                .map(|(i, _)| {
+                    let min = IVec2::new((i % chunk_columns * size) as i32, (i / chunk_columns * size) as i32);
+                    self.clamp_region(IRect::from_corners(min, min + IVec2::splat(*size as i32)))
+                })
+                .collect(),
+        }
+    }
 
-        self.check_bounds(IVec2::new(coords.x, coords.y))
+    /// Side of the chunks of a chunked map, in cells.
+    pub fn chunk_size(&self) -> Option<usize>{
+        match &self.grid {
+            GridStorage::Dense(_) => None,
+            GridStorage::Chunked { size, .. } => Some(*size),
+        }
     }
 
-    pub fn get_coord(&self, cell: IVec2) -> Vec2{
-        let mim_coord = (cell.as_vec2() - Vec2::new(self.columns as f32, self.rows as f32) / 2.) * self.cell_dimentions * 2.;
-        
-        let half_cell_offset = self.cell_dimentions / 2.;
+    /// Whether the value of `cell` is stored, rather than being the default
+    /// value of an unallocated chunk. Always true for the cells of a dense map.
+    pub fn is_allocated(&self, cell: IVec2) -> bool{
+        let Some(cell) = self.check_bounds(cell) else {
+            return false;
+        };
+
+        match &self.grid {
+            GridStorage::Dense(_) => true,
+            GridStorage::Chunked { size, chunk_columns, chunks, .. } => chunks[chunk_index(cell, *size, *chunk_columns).0].is_some(),
+        }
+    }
 
-        self.area.center() + mim_coord / 2. + half_cell_offset
+    /// Region covering every cell of the map. `max` is exclusive.
+    pub fn full_region(&self) -> IRect{
+        IRect::new(0, 0, self.columns as i32, self.rows as i32)
+    }
+
+    /// Restricts `region` to the cells that exist in the map.
+    pub fn clamp_region(&self, region: IRect) -> IRect{
+        region.intersect(self.full_region())
+    }
+
+    /// Grows the dirty region so it includes `region`.
+    pub fn mark_dirty(&mut self, region: IRect){
+        let region = self.clamp_region(region);
+
+        if region.is_empty(){
+            return;
+        }
+
+        self.dirty_region = match self.dirty_region {
+            Some(dirty) => Some(dirty.union(region)),
+            None => Some(region),
+        };
+    }
+
+    pub fn is_fully_dirty(&self) -> bool{
+        self.dirty_region == Some(self.full_region())
+    }
+
+    /// Cells modified since the last call to `take_dirty_region`.
+    pub fn dirty_region(&self) -> Option<IRect>{
+        self.dirty_region
+    }
+
+    /// Returns the cells modified since the last call and clears the tracking.
+    pub fn take_dirty_region(&mut self) -> Option<IRect>{
+        self.dirty_region.take()
+    }
+
+    /// Every cell of the map, column by column.
+    pub fn cells(&self) -> impl Iterator<Item = IVec2>{
+        self.cells_in_region(self.full_region())
+    }
+
+    /// Cells of `region` that exist in the map, column by column.
+    pub fn cells_in_region(&self, region: IRect) -> impl Iterator<Item = IVec2>{
+        let region = self.clamp_region(region);
+
+        (region.min.x..region.max.x).flat_map(move |x| (region.min.y..region.max.y).map(move |y| IVec2::new(x, y)))
+    }
+
+    /// Cells whose centre is closer than `radius` to `center`.
+    pub fn cells_in_circle(&self, center: Vec2, radius: f32) -> impl Iterator<Item = IVec2> + '_{
+        let region = self.cells_within_rect(Rect::from_center_half_size(center, Vec2::splat(radius))).unwrap_or_default();
+
+        self.cells_in_region(region).filter(move |&cell| (self.get_coord(cell) - center).length() < radius)
+    }
+
+    /// Cells of the map sharing an edge with `cell`.
+    pub fn neighbours_4(&self, cell: IVec2) -> impl Iterator<Item = IVec2> + '_{
+        NEIGHBOURS_4.into_iter().filter_map(move |offset| self.check_bounds(cell + offset))
+    }
+
+    /// Cells of the map sharing an edge or a corner with `cell`.
+    pub fn neighbours_8(&self, cell: IVec2) -> impl Iterator<Item = IVec2> + '_{
+        NEIGHBOURS_8.into_iter().filter_map(move |offset| self.check_bounds(cell + offset))
+    }
+
+    /// New layer of the same shape and storage holding `f` of every value.
+    pub fn map<U>(&self, f: impl Fn(T) -> U) -> GridMap<U> where U: Clone + Copy{
+
+        let grid = match &self.grid {
+            GridStorage::Dense(values) => GridStorage::Dense(values.iter().map(|&value| f(value)).collect()),
+            GridStorage::Chunked { size, chunk_columns, chunks, default } => GridStorage::Chunked {
+                size: *size,
+                chunk_columns: *chunk_columns,
+                chunks: chunks.iter()
+                    .map(|chunk| Some(chunk.as_ref()?.iter().map(|&value| f(value)).collect()))
+                    .collect(),
+                default: f(*default),
+            },
+        };
+
+        GridMap::with_storage(self.columns, self.rows, self.area, grid)
+    }
+
+    /// New layer combining the values of this layer and `other` cell by cell.
+    /// Both layers must have the same number of columns and rows. The result
+    /// is chunked when both layers are chunked alike, dense otherwise.
+    pub fn zip_with<U, V>(&self, other: &GridMap<U>, f: impl Fn(T, U) -> V) -> GridMap<V> where U: Clone + Copy, V: Clone + Copy{
+        assert_eq!((self.columns, self.rows), (other.columns, other.rows), "zipped layers must have the same size");
+
+        match (&self.grid, &other.grid) {
+            (GridStorage::Chunked { size, default, .. }, GridStorage::Chunked { size: other_size, default: other_default, .. }) if size == other_size => {
+                let mut result = self.new_like(f(*default, *other_default));
+
+                for region in self.chunk_regions().into_iter().chain(other.chunk_regions()) {
+                    for cell in self.cells_in_region(region) {
+                        *result.value_mut(cell) = f(self.value(cell), other.value(cell));
+                    }
+                }
+
+                result
+            },
+            _ => {
+                let values = (0..self.columns * self.rows)
+                    .map(|i| self.index_cell(i))
+                    .map(|cell| f(self.value(cell), other.value(cell)))
+                    .collect();
+
+                GridMap::with_storage(self.columns, self.rows, self.area, GridStorage::Dense(values))
+            },
+        }
+    }
+
+    /// Replaces every value by `f(cell, value)`, with the rows (or chunks)
+    /// spread over the compute task pool. The whole map is marked dirty. The
+    /// unallocated chunks of a chunked map stay unallocated unless `f` gives
+    /// one of their cells a value other than the default one.
+    pub fn par_update(&mut self, f: impl Fn(IVec2, T) -> T + Send + Sync) where T: Send + Sync + PartialEq{
+        let pool = ComputeTaskPool::get_or_init(TaskPool::default);
+        let columns = self.columns;
+        let rows = self.rows;
+
+        match &mut self.grid {
+            GridStorage::Dense(values) => {
+                values.par_chunk_map_mut(pool, columns.max(1), |y, row| {
+                    for (x, value) in row.iter_mut().enumerate() {
+                        *value = f(IVec2::new(x as i32, y as i32), *value);
+                    }
+                });
+            },
+            GridStorage::Chunked { size, chunk_columns, chunks, default } => {
+                let (size, chunk_columns, default) = (*size, *chunk_columns, *default);
+
+                chunks.par_chunk_map_mut(pool, 1, |i, chunk| {
+                    let origin = IVec2::new((i % chunk_columns * size) as i32, (i / chunk_columns * size) as i32);
+                    let allocated = chunk[0].is_some();
+                    let mut values = chunk[0].take().unwrap_or_else(|| vec![default; size * size].into_boxed_slice());
+
+                    for (offset, value) in values.iter_mut().enumerate() {
+                        let cell = origin + IVec2::new((offset % size) as i32, (offset / size) as i32);
+
+                        if cell.x < columns as i32 && cell.y < rows as i32 {
+                            *value = f(cell, *value);
+                        }
+                    }
+
+                    if allocated || values.iter().any(|value| *value != default) {
+                        chunk[0] = Some(values);
+                    }
+                });
+            },
+        }
+
+        self.mark_dirty(self.full_region());
+    }
+
+    /// Converts a world position to fractional grid coordinates.
+    ///
+    /// Cell `(x, y)` covers `[x, x + 1) x [y, y + 1)` in grid coordinates, so
+    /// its centre is at `(x + 0.5, y + 0.5)`. This and [`Self::grid_to_world`]
+    /// are the only transforms between world and grid space; every other
+    /// position helper is built on them.
+    pub fn world_to_grid(&self, pos: Vec2) -> Vec2{
+        (pos - self.area.min) / self.cell_dimentions
+    }
+
+    /// Converts fractional grid coordinates back to a world position.
+    pub fn grid_to_world(&self, grid_pos: Vec2) -> Vec2{
+        self.area.min + grid_pos * self.cell_dimentions
+    }
+
+    /// World space rectangle covered by `cell`.
+    pub fn get_cell_rect(&self, cell: IVec2) -> Rect{
+        Rect::from_corners(self.grid_to_world(cell.as_vec2()), self.grid_to_world(cell.as_vec2() + Vec2::ONE))
+    }
+
+    /// Cell containing `pos`. Points on an edge between two cells belong to
+    /// the cell after it, except on the far edges of the area, which belong
+    /// to the last row or column.
+    pub fn get_cell(&self, pos: Vec2) -> Option<IVec2>{
+        let cell = self.get_cell_unsafe(pos);
+        let last = IVec2::new(self.columns as i32, self.rows as i32) - IVec2::ONE;
+
+        match self.area.contains(pos) {
+            true => Some(cell.min(last)),
+            false => self.check_bounds(cell),
+        }
+    }
+
+    /// World position of the centre of `cell`.
+    pub fn get_coord(&self, cell: IVec2) -> Vec2{
+        self.grid_to_world(cell.as_vec2() + Vec2::splat(0.5))
     }
 
     pub fn get_value_at_cell(&self, pos: IVec2) -> Option<T>{
 
         if let Some(pos) = self.check_bounds(pos){
-            return Some(self.grid[pos.x as usize + pos.y as usize * self.columns]);
+            return Some(self.value(pos));
         }
 
         None
@@ -108,7 +818,8 @@ impl<T> GridMap<T> where T: Clone + Copy{
     pub fn set_value(&mut self, pos: IVec2, value: T) -> Result<(), ()>{
         
         if let Some(pos) = self.check_bounds(pos){
-            self.grid[pos.x as usize + pos.y as usize * self.columns] = value;
+            *self.value_mut(pos) = value;
+            self.mark_dirty(IRect::from_corners(pos, pos + IVec2::ONE));
             Result::Ok(())
         } else {
             Err(())
@@ -120,7 +831,7 @@ impl<T> GridMap<T> where T: Clone + Copy{
             return None;
         }
 
-        Some(self.grid[i])
+        Some(self.value(self.index_cell(i)))
     }
 
     pub fn set_value_by_index(&mut self, i: usize, value: T) -> Result<(), ()>{
@@ -128,30 +839,154 @@ impl<T> GridMap<T> where T: Clone + Copy{
             return Err(());
         }
 
-        self.grid[i] = value;
+        let pos = self.index_cell(i);
+
+        *self.value_mut(pos) = value;
+        self.mark_dirty(IRect::from_corners(pos, pos + IVec2::ONE));
+
         return Result::Ok(())
     }
 
+    /// Cells overlapping `search_area`, which may extend past the map.
     pub fn cells_within_rect(&self, search_area: Rect) -> Option<IRect>{
 
-        let search_center = self.get_cell_unsafe(search_area.center());
-        
-        let size = (search_area.size() / (self.cell_dimentions))
-        .ceil()
-        .as_ivec2()
-        .clamp(IVec2::ZERO, IVec2::new(self.columns as i32, self.rows as i32));
+        if search_area.size() == Vec2::ZERO{
+            return None;
+        }
+
+        let min = self.get_cell_unsafe(search_area.min);
+        let max = self.get_cell_unsafe(search_area.max);
+
+        Some(IRect::from_corners(min.min(max), min.max(max) + IVec2::ONE))
+    }
+
+    /// Bilinear interpolation between the four cell centres around `pos`.
+    ///
+    /// Cells rejected by `is_walkable`, outside the map or holding an invalid
+    /// value are left out and the weights of the remaining ones are
+    /// renormalized. Returns `None` when none of the four cells can be used.
+    pub fn get_interpolated_value_at(&self, pos: Vec2, is_walkable: impl Fn(IVec2) -> bool) -> Option<T> where T: Interpolate{
+
+        let relative_pos = self.world_to_grid(pos) - Vec2::splat(0.5);
+        let base = relative_pos.floor();
+        let fraction = relative_pos - base;
+        let base = base.as_ivec2();
+
+        let mut samples = [(None, 0.); 4];
+
+        for (i, (offset, weight)) in [
+            (IVec2::new(0, 0), (1. - fraction.x) * (1. - fraction.y)),
+            (IVec2::new(1, 0), fraction.x * (1. - fraction.y)),
+            (IVec2::new(0, 1), (1. - fraction.x) * fraction.y),
+            (IVec2::new(1, 1), fraction.x * fraction.y),
+        ].into_iter().enumerate() {
+            let cell = base + offset;
 
-        if size.length_squared() == 0{
+            if !is_walkable(cell) {
+                continue;
+            }
+
+            samples[i] = match self.get_value_at_cell(cell) {
+                Some(value) if value.is_valid_sample() => (Some(value), weight),
+                _ => (None, 0.),
+            };
+        }
+
+        let total_weight: f32 = samples.iter().map(|(_, weight)| weight).sum();
+
+        if total_weight <= f32::EPSILON {
             return None;
         }
 
-        Some(IRect::from_center_size(search_center, size + IVec2::ONE))
+        let samples: Vec<_> = samples.iter()
+            .filter_map(|(value, weight)| Some(((*value)?, weight / total_weight)))
+            .collect();
+
+        Some(T::weighted_sum(&samples))
+    }
+
+    /// Whether the segment between the centres of `from` and `to` only crosses
+    /// cells accepted by `is_clear`.
+    ///
+    /// Every cell the segment touches is visited (supercover traversal), and
+    /// when it passes exactly through a corner both cells beside the corner
+    /// must be clear, so the line never slips through a diagonal gap. The
+    /// `from` cell itself is not checked.
+    pub fn has_line_of_sight(&self, from: IVec2, to: IVec2, is_clear: impl Fn(T) -> bool) -> bool{
+
+        let clear = |cell: IVec2| self.get_value_at_cell(cell).is_some_and(&is_clear);
+
+        let delta = to - from;
+        let steps = delta.abs();
+        let sign = delta.signum();
+
+        let mut cell = from;
+        let mut taken = IVec2::ZERO;
+
+        while taken.x < steps.x || taken.y < steps.y {
+            let decision = (1 + 2 * taken.x) * steps.y - (1 + 2 * taken.y) * steps.x;
+
+            if decision == 0 {
+                if !clear(cell + IVec2::new(sign.x, 0)) || !clear(cell + IVec2::new(0, sign.y)) {
+                    return false;
+                }
+
+                cell += sign;
+                taken += IVec2::ONE;
+            } else if decision < 0 {
+                cell.x += sign.x;
+                taken.x += 1;
+            } else {
+                cell.y += sign.y;
+                taken.y += 1;
+            }
+
+            if !clear(cell) {
+                return false;
+            }
+        }
+
+        true
     }
 
+    /// Cell containing `pos`, which may be outside the map. Grid coordinates
+    /// within [`GRID_EPSILON`] of a cell edge are snapped to it, so edges
+    /// computed by [`Self::grid_to_world`] map back to their cell despite
+    /// rounding.
     fn get_cell_unsafe(&self, pos: Vec2) -> IVec2 {
-        let relative_pos = pos - self.area.min;
+        let grid_pos = self.world_to_grid(pos);
+        let snapped = grid_pos.round();
 
-        (relative_pos / self.cell_dimentions).floor().as_ivec2()
+        Vec2::select((grid_pos - snapped).abs().cmplt(Vec2::splat(GRID_EPSILON)), snapped, grid_pos).floor().as_ivec2()
+    }
+
+    /// Cell stored at index `i` of a dense map, the cells being stored row by row.
+    fn index_cell(&self, i: usize) -> IVec2{
+        IVec2::new((i % self.columns) as i32, (i / self.columns) as i32)
+    }
+
+    /// Value of a cell known to be inside the map.
+    fn value(&self, cell: IVec2) -> T{
+        match &self.grid {
+            GridStorage::Dense(values) => values[cell.x as usize + cell.y as usize * self.columns],
+            GridStorage::Chunked { size, chunk_columns, chunks, default } => {
+                let (chunk, offset) = chunk_index(cell, *size, *chunk_columns);
+                chunks[chunk].as_ref().map_or(*default, |values| values[offset])
+            },
+        }
+    }
+
+    /// Slot of a cell known to be inside the map, allocating its chunk if needed.
+    fn value_mut(&mut self, cell: IVec2) -> &mut T{
+        match &mut self.grid {
+            GridStorage::Dense(values) => &mut values[cell.x as usize + cell.y as usize * self.columns],
+            GridStorage::Chunked { size, chunk_columns, chunks, default } => {
+                let (chunk, offset) = chunk_index(cell, *size, *chunk_columns);
+                let values = chunks[chunk].get_or_insert_with(|| vec![*default; *size * *size].into_boxed_slice());
+
+                &mut values[offset]
+            },
+        }
     }
 
     fn check_bounds(&self, pos: IVec2) -> Option<IVec2>{
@@ -164,5 +999,210 @@ impl<T> GridMap<T> where T: Clone + Copy{
     }
 }
 
+/// Distance, in cells, under which a position counts as lying on a cell edge.
+const GRID_EPSILON: f32 = 1e-4;
+
+/// Index of the chunk holding `cell` and index of the cell inside the chunk.
+fn chunk_index(cell: IVec2, size: usize, chunk_columns: usize) -> (usize, usize){
+    let (x, y) = (cell.x as usize, cell.y as usize);
+
+    (x / size + y / size * chunk_columns, x % size + y % size * size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Maps laid out the way the flow field plugin builds them: as many cells
+    /// of `cell_size` as needed to cover the area, stretched to fit it.
+    fn maps() -> Vec<GridMap<u8>> {
+        [
+            (Rect::new(0., 0., 10., 10.), 1.),
+            (Rect::new(-5., -2.5, 5., 2.5), 0.5),
+            (Rect::new(-7.3, 1.1, 12.9, 6.4), 0.7),
+            (Rect::new(0., 0., 10., 10.), 3.),
+        ].into_iter()
+            .flat_map(|(area, cell_size)| {
+                let cells = (area.size() / cell_size).ceil();
+                let (columns, rows) = (cells.x as usize, cells.y as usize);
+
+                [GridMap::new(columns, rows, area, 0), GridMap::new_chunked(columns, rows, area, 0, 4)]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cells_round_trip() {
+        for map in maps() {
+            for cell in map.cells() {
+                assert_eq!(map.get_cell(map.get_coord(cell)), Some(cell));
+                assert!(map.world_to_grid(map.grid_to_world(cell.as_vec2())).abs_diff_eq(cell.as_vec2(), GRID_EPSILON));
+            }
+        }
+    }
+
+    #[test]
+    fn cell_edges_belong_to_the_next_cell() {
+        for map in maps() {
+            for cell in map.cells() {
+                let rect = map.get_cell_rect(cell);
+
+                assert_eq!(map.get_cell(rect.min), Some(cell), "min corner of {cell} in {:?}", map.area);
+                assert_eq!(map.get_cell(Vec2::new(rect.min.x, rect.center().y)), Some(cell));
+                assert_eq!(map.get_cell(Vec2::new(rect.center().x, rect.min.y)), Some(cell));
+            }
+        }
+    }
+
+    #[test]
+    fn area_bounds() {
+        for map in maps() {
+            let last = IVec2::new(map.columns as i32 - 1, map.rows as i32 - 1);
+
+            assert_eq!(map.get_cell(map.area.min), Some(IVec2::ZERO));
+            assert_eq!(map.get_cell(map.area.max), Some(last));
+            assert_eq!(map.get_cell(Vec2::new(map.area.min.x, map.area.max.y)), Some(IVec2::new(0, last.y)));
+            assert_eq!(map.get_cell(map.area.min - Vec2::splat(0.01)), None);
+            assert_eq!(map.get_cell(map.area.max + Vec2::splat(0.01)), None);
+
+            assert_eq!(map.get_cell_rect(IVec2::ZERO).min, map.area.min);
+            assert!(map.get_cell_rect(last).max.abs_diff_eq(map.area.max, 1e-4));
+            assert_eq!(map.cells_within_rect(map.area).map(|region| map.clamp_region(region)), Some(map.full_region()));
+        }
+    }
+
+    /// 4 x 4 map of unit cells holding `x + 10 y`, which bilinear
+    /// interpolation reproduces exactly between cell centres.
+    fn interpolation_map() -> GridMap<f32> {
+
+        let mut map = GridMap::new(4, 4, Rect::new(0., 0., 4., 4.), 0.);
+
+        for cell in map.cells().collect::<Vec<_>>() {
+            map.set_value(cell, (cell.x + 10 * cell.y) as f32).unwrap();
+        }
+
+        map
+    }
+
+    #[test]
+    fn interpolation_blends_between_cell_centres() {
 
+        let map = interpolation_map();
+        let sample = |pos: Vec2| map.get_interpolated_value_at(pos, |_| true).unwrap();
 
+        for cell in map.cells() {
+            assert_eq!(sample(map.get_coord(cell)), map.get_value_at_cell(cell).unwrap(), "centre of {cell}");
+        }
+
+        assert_eq!(sample(Vec2::new(2., 1.5)), 11.5);
+        assert_eq!(sample(Vec2::new(1.5, 2.)), 16.);
+        assert_eq!(sample(Vec2::new(2., 2.)), 16.5);
+        assert_eq!(sample(Vec2::new(1.75, 1.5)), 11.25);
+
+        // Past the outer centres only the cells inside the map are used.
+        assert_eq!(sample(Vec2::new(0.2, 0.2)), 0.);
+        assert_eq!(sample(Vec2::new(3.9, 0.5)), 3.);
+    }
+
+    #[test]
+    fn interpolation_leaves_out_unusable_cells() {
+
+        let mut map = interpolation_map();
+        let rejected = IVec2::new(2, 1);
+        let walkable = |cell: IVec2| cell != rejected;
+
+        assert_eq!(map.get_interpolated_value_at(Vec2::new(2., 1.5), walkable), Some(11.));
+        assert_eq!(map.get_interpolated_value_at(Vec2::new(2., 2.), walkable), Some(18.));
+        assert_eq!(map.get_interpolated_value_at(map.get_coord(rejected), walkable), None);
+
+        map.set_value(IVec2::new(1, 1), f32::NAN).unwrap();
+
+        assert_eq!(map.get_interpolated_value_at(Vec2::new(2., 1.5), |_| true), Some(12.));
+        assert_eq!(map.get_interpolated_value_at(Vec2::new(2., 1.5), walkable), None);
+    }
+
+    /// 6 x 4 map of unit cells, clear apart from `blocked`.
+    fn sight_map(blocked: &[IVec2]) -> GridMap<bool> {
+
+        let mut map = GridMap::new(6, 4, Rect::new(0., 0., 6., 4.), false);
+
+        for &cell in blocked {
+            map.set_value(cell, true).unwrap();
+        }
+
+        map
+    }
+
+    fn sees(blocked: &[IVec2], from: IVec2, to: IVec2) -> bool {
+        sight_map(blocked).has_line_of_sight(from, to, |blocked| !blocked)
+    }
+
+    #[test]
+    fn axis_aligned_sight_lines() {
+
+        let (from, to) = (IVec2::new(0, 1), IVec2::new(5, 1));
+
+        assert!(sees(&[], from, to));
+        assert!(sees(&[], to, from));
+        assert!(sees(&[IVec2::new(2, 0), IVec2::new(2, 2)], from, to));
+        assert!(!sees(&[IVec2::new(2, 1)], from, to));
+        assert!(!sees(&[IVec2::new(2, 1)], to, from));
+        assert!(!sees(&[IVec2::new(3, 2)], IVec2::new(3, 0), IVec2::new(3, 3)));
+        assert!(sees(&[IVec2::new(3, 1)], IVec2::new(3, 1), IVec2::new(3, 3)), "the start cell is not checked");
+    }
+
+    #[test]
+    fn sight_lines_through_a_corner_need_both_sides() {
+
+        // From (0, 0) to (3, 1) the line goes exactly through the corner
+        // between (1, 0), (2, 0), (1, 1) and (2, 1).
+        let (from, to) = (IVec2::new(0, 0), IVec2::new(3, 1));
+
+        assert!(sees(&[IVec2::new(0, 1), IVec2::new(3, 0)], from, to));
+        assert!(!sees(&[IVec2::new(2, 0)], from, to));
+        assert!(!sees(&[IVec2::new(1, 1)], from, to));
+        assert!(!sees(&[IVec2::new(1, 1)], to, from));
+
+        // Pure diagonals go through a corner at every step.
+        assert!(sees(&[IVec2::new(0, 3), IVec2::new(3, 0)], IVec2::ZERO, IVec2::new(3, 3)));
+        assert!(!sees(&[IVec2::new(2, 1)], IVec2::ZERO, IVec2::new(3, 3)));
+        assert!(!sees(&[IVec2::new(1, 2)], IVec2::new(3, 3), IVec2::ZERO));
+    }
+
+    #[test]
+    fn sight_lines_graze_every_touched_cell() {
+
+        // From (0, 0) to (4, 1) the line moves up a row halfway through
+        // column 2, so it touches (2, 0) and (2, 1) but neither (1, 1) nor
+        // (3, 0).
+        let (from, to) = (IVec2::new(0, 0), IVec2::new(4, 1));
+
+        assert!(sees(&[IVec2::new(1, 1), IVec2::new(3, 0)], from, to));
+        assert!(!sees(&[IVec2::new(2, 0)], from, to));
+        assert!(!sees(&[IVec2::new(2, 1)], from, to));
+        assert!(!sees(&[IVec2::new(2, 1)], to, from));
+    }
+
+    #[test]
+    fn sight_lines_leaving_the_map_are_blocked() {
+
+        assert!(!sees(&[], IVec2::new(2, 1), IVec2::new(8, 1)));
+        assert!(!sees(&[], IVec2::new(2, 1), IVec2::new(2, -1)));
+        assert!(!sees(&[], IVec2::new(0, 3), IVec2::new(5, 5)));
+        assert!(!sees(&[], IVec2::new(-3, 0), IVec2::new(3, 0)));
+
+        // Only the cells after the start are checked.
+        assert!(sees(&[], IVec2::new(-1, 0), IVec2::new(3, 0)));
+    }
+
+    #[test]
+    fn par_update_allocates_changed_chunks_only() {
+
+        let mut map = GridMap::new_chunked(10, 10, Rect::new(0., 0., 10., 10.), 0_u8, 4);
+        map.par_update(|cell, value| if cell == IVec2::new(5, 9) { 1 } else { value });
+
+        assert_eq!(map.chunk_regions(), vec![IRect::new(4, 8, 8, 10)]);
+        assert_eq!(map.get_value_at_cell(IVec2::new(5, 9)), Some(1));
+        assert_eq!(map.get_value_at_cell(IVec2::new(5, 8)), Some(0));
+    }
+}
@@ -2,4 +2,6 @@ pub const PIXELS_PER_METER : f32 = 50.;
 
 pub const AGENT_DESIRED_SPEED : f32 = 0.8;
 pub const AGENT_RADIUS : f32 = 10.;
-pub const AGENT_MASS: f32 = 80.;
\ No newline at end of file
+pub const AGENT_MASS: f32 = 80.;
+/// Distance between two agents waiting in line at a sink.
+pub const QUEUE_SPACING: f32 = 2.5 * AGENT_RADIUS;
\ No newline at end of file
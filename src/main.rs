@@ -1,35 +1,53 @@
+// Bevy systems take their resources and queries as arguments, so long
+// parameter lists and nested query types are the norm.
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
 mod components;
 mod systems;
 mod consts;
 mod plugins;
 
-use bevy::{
-    color::palettes::tailwind::*,
-    math::vec2,
-    prelude::*,
-    sprite::{MaterialMesh2dBundle, Mesh2dHandle},
-};
+use std::path::PathBuf;
+
+use bevy::prelude::*;
 use components::*;
-use consts::*;
 
 
-use plugins::{flow_field_pathfinding::plugin::FlowFieldPathfindingPlugin, simulation_area::plugin::SimulationAreaPlugin};
+use plugins::{flow_field_pathfinding::{plugin::FlowFieldPathfindingPlugin, systems::apply_vector_map}, navmesh::{plugin::NavMeshPlugin, systems::apply_navmesh_directions}, scenario::{models::Scenario, plugin::{scenario_asset_plugin, ScenarioPlugin}}, simulation_area::plugin::SimulationAreaPlugin};
 
 use systems::*;
 
 fn main() {
 
+    let scenario_file = std::env::args().nth(1).map(PathBuf::from);
+
+    let scenario = match &scenario_file {
+        Some(path) => Scenario::load(path).unwrap_or_else(|error| {
+            eprintln!("error: {error:#}");
+            std::process::exit(1);
+        }),
+        None => Scenario::demo(),
+    };
+
     let mut app = App::new();
-    app.add_plugins((DefaultPlugins,))
+    app.add_plugins((DefaultPlugins.set(scenario_asset_plugin(scenario_file.as_deref())),))
     .add_plugins((SimulationAreaPlugin{
-        simulation_area: Rect::from_center_size(Vec2::ZERO * 361.415, 700. * Vec2::ONE)
+        simulation_area: scenario.area.rect()
+    },))
+    .add_plugins((FlowFieldPathfindingPlugin{
+        cell_size: scenario.cell_size,
+        sector_size: scenario.sector_size,
+        floor_plan: scenario.floor_plan.clone(),
+        chunk_size: scenario.chunk_size,
     },))
-    .add_plugins((FlowFieldPathfindingPlugin{ cell_size: 5.},))
+    .add_plugins((NavMeshPlugin,))
+    .add_plugins((ScenarioPlugin{ scenario, file: scenario_file },))
     
         .add_systems(Startup, setup)
         // .add_systems(Startup, create_colision_map.after(setup))
         
         .add_systems(FixedUpdate, input_system)
+        .add_systems(Update, follow_system)
         
         //.add_systems(FixedUpdate, motivation_force_system.before(apply_social_foces))
         .add_systems(FixedUpdate, obstacle_force.before(apply_social_foces))
@@ -43,7 +61,12 @@ fn main() {
 
         .add_systems(FixedUpdate, velocity_sytem.after(apply_social_foces))
 
+        .add_systems(FixedUpdate, start_itineraries.before(agent_araived_at_destination_system))
         .add_systems(FixedUpdate, agent_araived_at_destination_system.after(velocity_sytem))
+        .add_systems(FixedUpdate, dwell_system.after(agent_max_speed_system).before(velocity_sytem))
+        .add_systems(FixedUpdate, join_sink_queues.after(agent_araived_at_destination_system))
+        .add_systems(FixedUpdate, sink_service_system.after(agent_max_speed_system).before(velocity_sytem))
+        .add_systems(Update, queue_steering_system.after(apply_vector_map).after(apply_navmesh_directions))
         // .add_systems(FixedUpdate, show_social_forces.after(apply_social_foces))
         ;
         
@@ -51,106 +74,6 @@ fn main() {
     app.run();
 }
 
-fn setup(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-) {
+fn setup(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
-
-
-
-    for x in (-500..-100).step_by(50) {
-        for y in (-400..400).step_by(50) {
-            commands.spawn((
-                Agent,
-                Speed(vec2(0., 0.)),
-                ObstacleForce(vec2(0.,0.)),
-                MotivationForce(vec2(0.,0.)),
-                RepulsiveForce(vec2(0.,0.)),
-                MaterialMesh2dBundle {
-                    mesh: Mesh2dHandle(meshes.add(Circle { radius: AGENT_RADIUS })),
-                    material: materials.add(Color::from(CYAN_500)),
-                    transform: Transform::from_xyz(x as f32, y as f32, 0.1),
-                    ..default()
-                },
-            ));
-        }
-    }
-
-    commands.spawn((
-        Objective,
-        Shape::Circle(20.),
-        MaterialMesh2dBundle {
-            mesh: Mesh2dHandle(meshes.add(Circle { radius: 20.0 })),
-            material: materials.add(Color::from(RED_500)),
-            transform: Transform::from_xyz(300.0, 0.0, 0.0),
-            ..default()
-        },
-    ));
-
-    commands.spawn((
-        Obstacle,
-        Shape::Circle(50.),
-        MaterialMesh2dBundle {
-            mesh: Mesh2dHandle(meshes.add(Circle { radius: 50.0 })),
-            material: materials.add(Color::from(GRAY_500)),
-            transform: Transform::from_xyz(100.0, 0.0, -0.5),
-            ..default()
-        },
-    ));
-
-    commands.spawn((
-        Obstacle,
-        Shape::Circle(50.),
-        MaterialMesh2dBundle {
-            mesh: Mesh2dHandle(meshes.add(Circle { radius: 50.0 })),
-            material: materials.add(Color::from(GRAY_500)),
-            transform: Transform::from_xyz(0.0, 0., -0.5),
-            ..default()
-        },
-    ));
-
-    commands.spawn((
-        Obstacle,
-        Shape::Circle(50.),
-        MaterialMesh2dBundle {
-            mesh: Mesh2dHandle(meshes.add(Circle { radius: 50.0 })),
-            material: materials.add(Color::from(GRAY_500)),
-            transform: Transform::from_xyz(100.0, 100., -0.5),
-            ..default()
-        },
-    ));
-    commands.spawn((
-        Obstacle,
-        Shape::Circle(50.),
-        MaterialMesh2dBundle {
-            mesh: Mesh2dHandle(meshes.add(Circle { radius: 50.0 })),
-            material: materials.add(Color::from(GRAY_500)),
-            transform: Transform::from_xyz(100.0, 200., -0.5),
-            ..default()
-        },
-    ));
-    commands.spawn((
-        Obstacle,
-        Shape::Circle(50.),
-        MaterialMesh2dBundle {
-            mesh: Mesh2dHandle(meshes.add(Circle { radius: 50.0 })),
-            material: materials.add(Color::from(GRAY_500)),
-            transform: Transform::from_xyz(100.0, 300., -0.5),
-            ..default()
-        },
-    ));
-    commands.spawn((
-        Obstacle,
-        Shape::Circle(50.),
-        MaterialMesh2dBundle {
-            mesh: Mesh2dHandle(meshes.add(Circle { radius: 50.0 })),
-            material: materials.add(Color::from(GRAY_500)),
-            transform: Transform::from_xyz(-0., 300., -0.5),
-            ..default()
-        },
-    ));
 }
-
-
@@ -0,0 +1,388 @@
+use std::{collections::{HashMap, HashSet}, fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bevy::{
+    color::{Color, ColorToPacked, Luminance},
+    math::{IVec2, Rect, Vec2},
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::{CompressedImageFormats, Image, ImageSampler, ImageType},
+    },
+};
+
+use crate::components::{GridMap, Shape, NEIGHBOURS_4};
+
+use super::models::BlockedStatus;
+
+/// Layers read from a floor plan image.
+pub struct FloorPlan {
+    pub obstacles: GridMap<BlockedStatus>,
+    pub exit_cells: Vec<IVec2>,
+    pub spawn_cells: Vec<IVec2>,
+}
+
+/// Meaning of a pixel of a floor plan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FloorPlanPixel {
+    Floor,
+    Wall,
+    Exit,
+    Spawn,
+}
+
+impl FloorPlanPixel {
+    /// Black is a wall, green an exit, red a spawn zone and any other colour
+    /// is walkable floor.
+    fn from_rgb([r, g, b]: [u8; 3]) -> Self {
+        match (r >= 128, g >= 128, b >= 128) {
+            _ if r.max(g).max(b) < 64 => Self::Wall,
+            (false, true, false) => Self::Exit,
+            (true, false, false) => Self::Spawn,
+            _ => Self::Floor,
+        }
+    }
+}
+
+/// RGB pixels of an image, stored row by row from the top.
+struct Pixels {
+    width: usize,
+    height: usize,
+    rgb: Vec<[u8; 3]>,
+}
+
+/// Reads a PNG or PGM floor plan and samples it at the centre of every cell
+/// of a `columns` x `rows` grid covering `area`. The image is stretched over
+/// the whole area, its top row lying at the top of the area.
+pub fn load_floor_plan(path: &Path, columns: usize, rows: usize, area: Rect, chunk_size: Option<usize>) -> Result<FloorPlan> {
+
+    let bytes = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+    decode_floor_plan(&bytes, path, columns, rows, area, chunk_size)
+}
+
+/// Same as [`load_floor_plan`], given the content of the file at `path`.
+pub fn decode_floor_plan(bytes: &[u8], path: &Path, columns: usize, rows: usize, area: Rect, chunk_size: Option<usize>) -> Result<FloorPlan> {
+
+    let pixels = match extension(path).as_str() {
+        "pgm" => decode_pgm(bytes)?,
+        _ => decode_image(bytes, &extension(path))?,
+    };
+
+    let mut obstacles = GridMap::new_layer(columns, rows, area, BlockedStatus::Empty, chunk_size);
+    let mut exit_cells = Vec::new();
+    let mut spawn_cells = Vec::new();
+
+    for x in 0..columns {
+        for y in 0..rows {
+            let cell = IVec2::new(x as i32, y as i32);
+
+            let u = (x as f32 + 0.5) / columns as f32;
+            let v = 1. - (y as f32 + 0.5) / rows as f32;
+
+            let px = ((u * pixels.width as f32) as usize).min(pixels.width - 1);
+            let py = ((v * pixels.height as f32) as usize).min(pixels.height - 1);
+
+            match FloorPlanPixel::from_rgb(pixels.rgb[px + py * pixels.width]) {
+                FloorPlanPixel::Floor => {},
+                FloorPlanPixel::Wall => {
+                    obstacles.set_value(cell, BlockedStatus::Blocked).ok();
+                },
+                FloorPlanPixel::Exit => exit_cells.push(cell),
+                FloorPlanPixel::Spawn => spawn_cells.push(cell),
+            }
+        }
+    }
+
+    Ok(FloorPlan { obstacles, exit_cells, spawn_cells })
+}
+
+/// World shape of every group of `cells` connected through their edges, and
+/// its position. Groups are outlined along the edges of their cells in
+/// `map`; holes in a group are filled.
+pub fn cell_areas<T>(cells: &[IVec2], map: &GridMap<T>) -> Vec<(Vec2, Shape)> where T: Clone + Copy {
+
+    let mut remaining: HashSet<IVec2> = cells.iter().copied().collect();
+    let mut areas = Vec::new();
+
+    for &start in cells {
+        if !remaining.remove(&start) {
+            continue;
+        }
+
+        let mut group = HashSet::from([start]);
+        let mut open_list = vec![start];
+
+        while let Some(cell) = open_list.pop() {
+            for neighbour in NEIGHBOURS_4.map(|offset| cell + offset) {
+                if remaining.remove(&neighbour) {
+                    group.insert(neighbour);
+                    open_list.push(neighbour);
+                }
+            }
+        }
+
+        let points = outline(&group).into_iter().map(|corner| map.grid_to_world(corner.as_vec2())).collect();
+        areas.extend(Shape::from_outline(points, true, true, 0.));
+    }
+
+    areas
+}
+
+/// Corners of the outer boundary of a group of cells, counterclockwise, with
+/// the corners along straight runs left out.
+fn outline(group: &HashSet<IVec2>) -> Vec<IVec2> {
+
+    // Boundary edges, each going counterclockwise around its cell, keyed by
+    // their start corner.
+    let mut edges: HashMap<IVec2, Vec<IVec2>> = HashMap::new();
+
+    for &cell in group {
+        let corners = [cell, cell + IVec2::X, cell + IVec2::ONE, cell + IVec2::Y];
+
+        for (side, offset) in [IVec2::NEG_Y, IVec2::X, IVec2::Y, IVec2::NEG_X].into_iter().enumerate() {
+            if !group.contains(&(cell + offset)) {
+                edges.entry(corners[side]).or_default().push(corners[(side + 1) % 4]);
+            }
+        }
+    }
+
+    let mut loops: Vec<Vec<IVec2>> = Vec::new();
+
+    // Loops start on a corner left by a single edge, so they are not cut
+    // short where they touch themselves.
+    while let Some(start) = edges.iter().find(|(_, ends)| ends.len() == 1).or(edges.iter().next()).map(|(&corner, _)| corner) {
+        let mut corners = vec![start];
+        let mut direction = IVec2::ZERO;
+        let mut corner = start;
+
+        while let Some(ends) = edges.get_mut(&corner) {
+            // Where two cells only touch by a corner, turn left so the outline
+            // never crosses itself.
+            let next = (0..ends.len())
+                .max_by_key(|&i| direction.perp_dot(ends[i] - corner))
+                .unwrap_or(0);
+            let end = ends.swap_remove(next);
+
+            if ends.is_empty() {
+                edges.remove(&corner);
+            }
+
+            direction = end - corner;
+            corner = end;
+
+            if corner == start {
+                break;
+            }
+
+            corners.push(corner);
+        }
+
+        loops.push(corners);
+    }
+
+    // Holes wind clockwise, the outer boundary has the largest area.
+    let twice_area = |corners: &Vec<IVec2>| (0..corners.len())
+        .map(|i| corners[i].perp_dot(corners[(i + 1) % corners.len()]))
+        .sum::<i32>();
+
+    let Some(boundary) = loops.into_iter().max_by_key(twice_area) else {
+        return Vec::new();
+    };
+
+    let count = boundary.len();
+
+    (0..count)
+        .filter(|&i| {
+            let (previous, corner, next) = (boundary[(i + count - 1) % count], boundary[i], boundary[(i + 1) % count]);
+            (corner - previous).perp_dot(next - corner) != 0
+        })
+        .map(|i| boundary[i])
+        .collect()
+}
+
+/// Writes `map` as an image with one pixel per cell, coloured by `colour`.
+///
+/// PGM files are written as grey levels, any other extension goes through the
+/// image encoder of the renderer (e.g. PNG).
+pub fn export_layer<T>(map: &GridMap<T>, path: &Path, colour: impl Fn(T) -> Color) -> Result<()> where T: Clone + Copy {
+
+    let colours = map.map(|value| colour(value).to_srgba().to_u8_array());
+    let mut rgba = Vec::with_capacity(map.columns * map.rows * 4);
+
+    for y in (0..map.rows).rev() {
+        for x in 0..map.columns {
+            rgba.extend_from_slice(&colours.get_value_at_cell(IVec2::new(x as i32, y as i32)).unwrap_or_default());
+        }
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if extension(path) == "pgm" {
+        let mut bytes = format!("P5\n{} {}\n255\n", map.columns, map.rows).into_bytes();
+        bytes.extend(rgba.chunks_exact(4).map(|p| (Color::srgb_u8(p[0], p[1], p[2]).luminance() * 255.).round() as u8));
+
+        return fs::write(path, bytes).with_context(|| format!("writing {}", path.display()));
+    }
+
+    let image = Image::new(
+        Extent3d { width: map.columns as u32, height: map.rows as u32, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        rgba,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    );
+
+    image.try_into_dynamic()?
+        .save(path)
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+fn decode_image(bytes: &[u8], extension: &str) -> Result<Pixels> {
+
+    let image = Image::from_buffer(
+        bytes,
+        ImageType::Extension(extension),
+        CompressedImageFormats::NONE,
+        true,
+        ImageSampler::Default,
+        RenderAssetUsages::default(),
+    )?;
+
+    let image = image.convert(TextureFormat::Rgba8UnormSrgb)
+        .ok_or_else(|| anyhow!("unsupported pixel format {:?}", image.texture_descriptor.format))?;
+
+    Ok(Pixels {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        rgb: image.data.chunks_exact(4).map(|p| [p[0], p[1], p[2]]).collect(),
+    })
+}
+
+/// Decodes a binary (P5) or plain (P2) greyscale PGM file.
+fn decode_pgm(bytes: &[u8]) -> Result<Pixels> {
+
+    let mut position = 0;
+
+    let mut next_token = || -> Result<String> {
+        loop {
+            match bytes.get(position) {
+                Some(b'#') => while bytes.get(position).is_some_and(|&b| b != b'\n') {
+                    position += 1;
+                },
+                Some(b) if b.is_ascii_whitespace() => position += 1,
+                Some(_) => break,
+                None => bail!("unexpected end of PGM file"),
+            }
+        }
+
+        let start = position;
+
+        while bytes.get(position).is_some_and(|b| !b.is_ascii_whitespace()) {
+            position += 1;
+        }
+
+        Ok(String::from_utf8_lossy(&bytes[start..position]).into_owned())
+    };
+
+    let magic = next_token()?;
+    let width: usize = next_token()?.parse()?;
+    let height: usize = next_token()?.parse()?;
+    let max_value: u32 = next_token()?.parse()?;
+
+    if width == 0 || height == 0 || max_value == 0 || max_value > 65535 {
+        bail!("invalid PGM header");
+    }
+
+    let scale = |value: u32| (value.min(max_value) * 255 / max_value) as u8;
+
+    let grey: Vec<u8> = match magic.as_str() {
+        "P2" => (0..width * height)
+            .map(|_| Ok(scale(next_token()?.parse()?)))
+            .collect::<Result<_>>()?,
+        "P5" => {
+            let data = bytes.get(position + 1..).unwrap_or_default();
+            let sample_size = if max_value < 256 { 1 } else { 2 };
+
+            if data.len() < width * height * sample_size {
+                bail!("PGM file is shorter than its header says");
+            }
+
+            data.chunks_exact(sample_size)
+                .take(width * height)
+                .map(|sample| scale(sample.iter().fold(0, |acc, &b| acc << 8 | b as u32)))
+                .collect()
+        },
+        _ => bail!("not a PGM file"),
+    };
+
+    Ok(Pixels { width, height, rgb: grey.into_iter().map(|g| [g, g, g]).collect() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid() -> GridMap<BlockedStatus> {
+        GridMap::new(10, 10, Rect::new(0., 0., 20., 20.), BlockedStatus::Empty)
+    }
+
+    #[test]
+    fn touching_cells_become_one_area() {
+
+        // An L of three cells and a cell on its own.
+        let cells = [IVec2::new(1, 1), IVec2::new(2, 1), IVec2::new(1, 2), IVec2::new(5, 5)];
+        let areas = cell_areas(&cells, &grid());
+
+        assert_eq!(areas.len(), 2);
+
+        let (position, shape) = &areas[0];
+        assert!(matches!(shape, Shape::Polygon(points) if points.len() == 6));
+        assert_eq!(shape.area(), 12.);
+
+        for cell in cells[..3].iter() {
+            assert!(shape.contains(*position, grid().get_coord(*cell)));
+        }
+
+        assert!(!shape.contains(*position, grid().get_coord(IVec2::new(2, 2))));
+        assert!(matches!(areas[1].1, Shape::Rectangle(size) if size == Vec2::splat(2.)));
+    }
+
+    #[test]
+    fn holes_are_filled() {
+
+        let ring: Vec<IVec2> = (0..3).flat_map(|x| (0..3).map(move |y| IVec2::new(x, y)))
+            .filter(|&cell| cell != IVec2::ONE)
+            .collect();
+
+        let areas = cell_areas(&ring, &grid());
+
+        assert_eq!(areas.len(), 1);
+        assert!(matches!(areas[0].1, Shape::Rectangle(size) if size == Vec2::splat(6.)));
+    }
+
+    #[test]
+    fn cells_touching_by_a_corner_stay_apart() {
+
+        let cells = [IVec2::new(0, 0), IVec2::new(1, 1)];
+
+        assert_eq!(cell_areas(&cells, &grid()).len(), 2);
+
+        // Joined through other cells, the outline passes the shared corner
+        // twice.
+        let cells = [IVec2::new(0, 0), IVec2::new(1, 1), IVec2::new(0, 1), IVec2::new(0, 2), IVec2::new(1, 2), IVec2::new(2, 2), IVec2::new(2, 1), IVec2::new(2, 0)];
+        let areas = cell_areas(&cells, &grid());
+
+        assert_eq!(areas.len(), 1);
+        assert_eq!(areas[0].1.area(), 32.);
+    }
+}
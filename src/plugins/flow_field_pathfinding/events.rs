@@ -0,0 +1,8 @@
+use bevy::{ecs::{entity::Entity, event::Event}, math::Vec2};
+
+/// Sent when an agent ends up in a region with no path to its destination.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct AgentTrapped {
+    pub agent: Entity,
+    pub position: Vec2,
+}
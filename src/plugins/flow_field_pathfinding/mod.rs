@@ -2,3 +2,5 @@ pub mod plugin;
 pub mod resources;
 pub mod systems;
 pub mod models;
+pub mod events;
+pub mod bitmap;
@@ -1,3 +1,9 @@
+use bevy::math::{IVec2, Vec2};
+
+use crate::components::GridMap;
+
+use super::resources::SectorGraph;
+
 pub trait CellStatus : Default + Send + Sync + Copy + PartialEq{
     fn get_non_default_value() -> Self;
 }
@@ -6,7 +12,9 @@ pub trait CellStatus : Default + Send + Sync + Copy + PartialEq{
 pub enum BlockedStatus {
     #[default]
     Empty,
-    Blocked
+    Blocked,
+    /// Free cell closer to an obstacle than the agents' clearance.
+    Clearance
 }
 
 impl CellStatus for BlockedStatus {
@@ -28,6 +36,21 @@ impl CellStatus for TargetStatus {
     }
 }
 
+/// Distance in world units from a cell centre to the closest blocked cell
+/// centre. Cells farther than the distance the layers look at hold that
+/// distance instead, the default value of the layer.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct WallDistance(pub f32);
+
+/// Connected walkable region a cell belongs to. Blocked cells and cells
+/// inside the clearance band have no region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct RegionLabel(pub Option<u32>);
+
+/// Room of `RoomGraph` a cell belongs to. Blocked cells have no room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct RoomLabel(pub Option<usize>);
+
 #[derive(Clone, Copy, Debug)]
 pub enum TargetProximity {
     Unreachable,
@@ -35,3 +58,55 @@ pub enum TargetProximity {
     Computed(f32)
 }
 
+
+/// One side of a portal: a run of walkable cells on the border of a sector
+/// that leads into the neighbouring sector.
+#[derive(Clone, Debug)]
+pub struct PortalNode {
+    pub sector: IVec2,
+    pub cells: Vec<IVec2>,
+    /// Index of the node on the other side of the portal.
+    pub pair: usize,
+}
+
+/// Navigation layers of one agent class.
+pub struct ClassField {
+    pub obstacles: GridMap<BlockedStatus>,
+    /// Product of the cost multipliers of the zones covering each cell.
+    pub zone_cost: GridMap<f32>,
+    pub proximity: GridMap<TargetProximity>,
+    pub vectors: GridMap<Vec2>,
+    /// Sector graph of the class when the hierarchical flow field is used,
+    /// in which case `proximity` and `vectors` are only built for the sectors
+    /// its agents occupy.
+    pub sectors: Option<SectorGraph>,
+}
+
+/// Navigation layers leading to a single objective, for one agent class or
+/// for the agents without one.
+#[derive(Clone)]
+pub struct DestinationField {
+    pub targets: GridMap<TargetStatus>,
+    pub proximity: GridMap<TargetProximity>,
+    pub vectors: GridMap<Vec2>,
+    /// Sector graph of the objective, as for [`ClassField::sectors`].
+    pub sectors: Option<SectorGraph>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Room {
+    /// Walkable area in square world units.
+    pub area: f32,
+    pub center: Vec2,
+    /// Largest distance from a cell of the room to a wall.
+    pub depth: f32,
+}
+
+/// Narrowing between two rooms.
+#[derive(Clone, Debug)]
+pub struct Door {
+    pub rooms: (usize, usize),
+    pub position: Vec2,
+    /// Free width in world units at the door's widest crossing point.
+    pub width: f32,
+}
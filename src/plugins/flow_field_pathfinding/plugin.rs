@@ -1,46 +1,101 @@
+use std::path::{Path, PathBuf};
+
 use bevy::prelude::*;
 
-use crate::{components::GridMap, plugins::simulation_area::resources::SimulationArea, systems::apply_social_foces, Objective, Obstacle};
+use crate::{components::{Agent, GridMap}, plugins::{navmesh::resources::NavigationBackend, simulation_area::resources::SimulationArea}, systems::apply_social_foces, Objective, Obstacle};
 
-use super::{models::{BlockedStatus, TargetProximity, TargetStatus}, resources::*, systems::*};
+use super::{bitmap::load_floor_plan, events::AgentTrapped, models::{BlockedStatus, RegionLabel, RoomLabel, TargetProximity, TargetStatus, WallDistance}, resources::*, systems::*};
 
 pub struct FlowFieldPathfindingPlugin{
-    pub cell_size: f32
+    pub cell_size: f32,
+    /// When set, the grid is split in sectors of this many cells per side and
+    /// flow fields are only built for the sectors agents occupy.
+    pub sector_size: Option<usize>,
+    /// PNG or PGM image whose walls are loaded as the static part of the
+    /// obstacle map. Its exits and spawn zones are read by the scenario.
+    pub floor_plan: Option<PathBuf>,
+    /// When set, the layers store their cells in lazily allocated chunks of
+    /// this many cells per side instead of one dense array, for large and
+    /// mostly empty areas.
+    pub chunk_size: Option<usize>,
 }
 
 impl Plugin for FlowFieldPathfindingPlugin {
     fn build(&self, app: &mut App) {
 
         let cell_size = self.cell_size;
+        let sector_size = self.sector_size;
+        let floor_plan = self.floor_plan.clone();
+        let chunk_size = self.chunk_size;
 
         app
         .insert_state(PathFindingOverlayState::ShowNone)
-        .insert_state(ShowGridState::HideGrid);
+        .insert_state(ShowGridState::HideGrid)
+        .init_resource::<VectorFieldSampling>()
+        .init_resource::<VectorFieldMode>()
+        .init_resource::<Clearance>()
+        .init_resource::<WallAvoidance>()
+        .init_resource::<NavigationClasses>()
+        .init_resource::<ClassFields>()
+        .init_resource::<OverlayClass>()
+        .init_resource::<DestinationFields>()
+        .init_resource::<RouteChoiceSettings>()
+        .init_resource::<LookAhead>()
+        .init_resource::<FlowFieldExecution>()
+        .init_resource::<NavigationBackend>()
+        .init_resource::<ReachableRegions>()
+        .init_resource::<RoomGraph>()
+        .add_event::<AgentTrapped>();
+
+        app.add_systems(Startup, move |simulation_area: Res<SimulationArea>, mut commands: Commands| {
+            if let Some(sector_size) = sector_size {
+                commands.insert_resource(SectorGraph::new(sector_size));
+            }
 
-        app.add_systems(Startup, move |simulation_area: Res<SimulationArea>, commands: Commands| {
-            setup(simulation_area, commands, cell_size);
+            setup(simulation_area, commands, cell_size, chunk_size, floor_plan.as_deref());
         })
 
         .add_systems(First, handle_grid_state_inputs)
         .add_systems(First, handle_overlay_inputs)
+        .add_systems(First, handle_vector_field_inputs)
+        .add_systems(First, handle_overlay_class_inputs)
+        .add_systems(First, handle_export_inputs)
         
         .add_systems(PreUpdate, create_colision_map::<BlockedStatus, Obstacle>)
         .add_systems(PreUpdate, create_colision_map::<TargetStatus, Objective>)
-        .add_systems(PreUpdate, compute_proximity_map.after(create_colision_map::<BlockedStatus, Obstacle>).after(create_colision_map::<TargetStatus, Objective>))
-        .add_systems(PreUpdate, create_vector_map.after(compute_proximity_map))
+        .add_systems(PreUpdate, inflate_obstacles.after(create_colision_map::<BlockedStatus, Obstacle>))
+        .add_systems(PreUpdate, compute_proximity_map.after(inflate_obstacles).after(create_colision_map::<TargetStatus, Objective>).run_if(not(resource_exists::<SectorGraph>)).run_if(resource_equals(FlowFieldExecution::Synchronous)).run_if(resource_equals(NavigationBackend::FlowField)))
+        .add_systems(PreUpdate, create_vector_map.after(compute_proximity_map).run_if(not(resource_exists::<SectorGraph>)).run_if(resource_equals(FlowFieldExecution::Synchronous)).run_if(resource_equals(NavigationBackend::FlowField)))
+        
+        .add_systems(PreUpdate, update_flow_field_task.after(inflate_obstacles).after(create_colision_map::<TargetStatus, Objective>).run_if(not(resource_exists::<SectorGraph>)).run_if(resource_equals(FlowFieldExecution::Asynchronous)).run_if(resource_equals(NavigationBackend::FlowField)))
+        
+        .add_systems(PreUpdate, update_sector_graph.after(inflate_obstacles).after(create_colision_map::<TargetStatus, Objective>).run_if(resource_exists::<SectorGraph>).run_if(resource_equals(NavigationBackend::FlowField)))
+        .add_systems(PreUpdate, build_occupied_sectors.after(update_sector_graph).after(update_destination_fields).run_if(resource_exists::<SectorGraph>))
         
-        .add_systems(Update, apply_vector_map.before(apply_social_foces))
+        // Destination fields read the obstacle changes before the shared field takes them.
+        .add_systems(PreUpdate, update_destination_fields.after(update_class_fields).before(compute_proximity_map).before(update_flow_field_task).before(update_sector_graph))
+        .add_systems(PreUpdate, update_class_fields.after(inflate_obstacles).after(create_colision_map::<TargetStatus, Objective>))
+        
+        .add_systems(PreUpdate, label_regions.after(inflate_obstacles).after(create_colision_map::<TargetStatus, Objective>))
+        .add_systems(PreUpdate, segment_rooms.after(inflate_obstacles))
+        .add_systems(PreUpdate, report_unreachable_agents.after(label_regions).run_if(any_with_component::<Agent>.and_then(run_once())))
+        
+        .add_systems(Update, detect_trapped_agents)
+        .add_systems(Update, log_trapped_agents.after(detect_trapped_agents))
+        .add_systems(Update, route_choice_system.before(apply_vector_map))
+        .add_systems(Update, apply_vector_map.before(apply_social_foces).run_if(resource_equals(NavigationBackend::FlowField)))
         
         .add_systems(PostUpdate, draw_grid.run_if(in_state(ShowGridState::ShowGrid)))
 
         .add_systems(PostUpdate, draw_obstacles.run_if(in_state(PathFindingOverlayState::ShowObstacles)))
         .add_systems(PostUpdate, draw_targets.run_if(in_state(PathFindingOverlayState::ShowTargets)))
         .add_systems(PostUpdate, draw_proximity.run_if(in_state(PathFindingOverlayState::ShowProimity)))
-        .add_systems(PostUpdate, draw_vectors.run_if(in_state(PathFindingOverlayState::ShowVectorField)));
+        .add_systems(PostUpdate, draw_vectors.run_if(in_state(PathFindingOverlayState::ShowVectorField)))
+        .add_systems(PostUpdate, draw_rooms.run_if(in_state(PathFindingOverlayState::ShowRooms)));
     }
 }
 
-fn setup(simulation_area: Res<SimulationArea>, mut commands: Commands, cell_size: f32){
+fn setup(simulation_area: Res<SimulationArea>, mut commands: Commands, cell_size: f32, chunk_size: Option<usize>, floor_plan: Option<&Path>){
 
     let ratio: Vec2 = simulation_area.0.size() / cell_size * Vec2::ONE;
     println!("{:?}", ratio);
@@ -48,38 +103,81 @@ fn setup(simulation_area: Res<SimulationArea>, mut commands: Commands, cell_size
     let rows = ratio.y.ceil() as usize;
 
     commands.insert_resource(
-        GridMap::new(
+        GridMap::new_layer(
+            columns, 
+            rows, 
+            simulation_area.0, 
+            BlockedStatus::Empty,
+            chunk_size
+        )
+    );
+
+    commands.insert_resource(
+        GridMap::new_layer(
+            columns, 
+            rows, 
+            simulation_area.0, 
+            TargetStatus::NotTarget,
+            chunk_size
+        )
+    );
+
+    commands.insert_resource(
+        GridMap::new_layer(
+            columns, 
+            rows, 
+            simulation_area.0, 
+            TargetProximity::NotComputed,
+            chunk_size
+        )
+    );
+
+    commands.insert_resource(
+        GridMap::new_layer(
             columns, 
             rows, 
             simulation_area.0, 
-            BlockedStatus::Empty
+            WallDistance::default(),
+            chunk_size
         )
     );
 
     commands.insert_resource(
-        GridMap::new(
+        GridMap::new_layer(
             columns, 
             rows, 
             simulation_area.0, 
-            TargetStatus::NotTarget
+            RegionLabel::default(),
+            chunk_size
         )
     );
 
     commands.insert_resource(
-        GridMap::new(
+        GridMap::new_layer(
             columns, 
             rows, 
             simulation_area.0, 
-            TargetProximity::NotComputed
+            RoomLabel::default(),
+            chunk_size
         )
     );
 
     commands.insert_resource(
-        GridMap::new(
+        GridMap::new_layer(
             columns, 
             rows, 
-            simulation_area.0,
-            Vec2::ZERO
+            simulation_area.0, 
+            Vec2::ZERO,
+            chunk_size
         )
     );
-}
\ No newline at end of file
+
+    let Some(path) = floor_plan else {
+        return;
+    };
+
+    match load_floor_plan(path, columns, rows, simulation_area.0, chunk_size) {
+        Ok(plan) => commands.insert_resource(BaseLayer(plan.obstacles)),
+        Err(error) => error!("Could not load floor plan {}: {error:#}", path.display()),
+    }
+}
@@ -1,4 +1,11 @@
-use bevy::state::state::States;
+use std::collections::{HashMap, HashSet};
+
+use bevy::{ecs::{entity::Entity, system::Resource}, math::{IRect, IVec2, Vec2}, state::state::States, tasks::{block_on, poll_once, AsyncComputeTaskPool, Task}};
+use serde::Deserialize;
+
+use crate::{components::GridMap, consts::AGENT_RADIUS};
+
+use super::models::{ClassField, DestinationField, Door, PortalNode, Room};
 
 
 #[derive(States, Debug, Clone, PartialEq, Eq, Hash)]
@@ -13,5 +20,302 @@ pub enum PathFindingOverlayState{
     ShowTargets,
     ShowObstacles,
     ShowProimity,
-    ShowVectorField
-}
\ No newline at end of file
+    ShowVectorField,
+    ShowRooms
+}
+
+/// Minimum distance in world units agents keep from obstacles.
+///
+/// Free cells closer than this to a blocked cell are marked as
+/// [`BlockedStatus::Clearance`] and left out of the flow field.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct Clearance(pub f32);
+
+impl Default for Clearance {
+    fn default() -> Self {
+        Self(AGENT_RADIUS)
+    }
+}
+
+/// Static content of a layer, e.g. the walls and exits of a floor plan.
+///
+/// Collision maps are cleared to this layer instead of the default value
+/// before the entities covering them are drawn again.
+#[derive(Resource)]
+pub struct BaseLayer<T>(pub GridMap<T>) where T: Clone + Copy;
+
+/// Extra cost of walking close to obstacles, so flow fields round corners at
+/// a comfortable distance instead of grazing them.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WallAvoidance {
+    /// Distance in world units beyond the clearance over which the extra cost
+    /// fades out.
+    pub margin: f32,
+    /// Extra cost of a step taken right at the clearance boundary, relative
+    /// to a step in open space.
+    pub weight: f32,
+}
+
+impl Default for WallAvoidance {
+    fn default() -> Self {
+        Self { margin: AGENT_RADIUS, weight: 1. }
+    }
+}
+
+impl WallAvoidance {
+    /// Cost multiplier of a cell `distance` away from the closest obstacle,
+    /// for agents keeping `clearance` from obstacles.
+    pub fn cost(&self, distance: f32, clearance: f32) -> f32 {
+        if self.margin <= 0. {
+            return 1.;
+        }
+
+        1. + self.weight * (1. - (distance - clearance) / self.margin).clamp(0., 1.)
+    }
+
+    /// Number of cells around a changed obstacle whose cost may change.
+    pub fn reach(&self, cell_dimentions: Vec2) -> i32 {
+        (self.margin / cell_dimentions.min_element()).ceil() as i32 + 1
+    }
+}
+
+/// Navigation rules of one kind of agent, e.g. wheelchair users or staff.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct NavigationClass {
+    pub name: String,
+    /// Distance in world units the agents keep from obstacles.
+    pub clearance: f32,
+    /// Labels of the zones these agents may not enter.
+    #[serde(default)]
+    pub forbidden_zones: Vec<String>,
+    /// Cost multiplier for crossing zones with the given label.
+    #[serde(default)]
+    pub cost_multipliers: HashMap<String, f32>,
+}
+
+/// Classes referenced by the `AgentClass` component.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct NavigationClasses(pub Vec<NavigationClass>);
+
+/// Navigation layers of each entry of [`NavigationClasses`], in the same order.
+#[derive(Resource, Default)]
+pub struct ClassFields(pub Vec<ClassField>);
+
+/// Flow fields leading to the objectives agents head for, keyed by objective
+/// and by index in [`NavigationClasses`] (`None` for agents without a class).
+/// Agents with a `Destination` follow the field of their destination and
+/// class, `RouteChoice` agents compare those of every objective for their
+/// class.
+#[derive(Resource, Default)]
+pub struct DestinationFields(pub HashMap<(Entity, Option<usize>), DestinationField>);
+
+/// Parameters shared by the `RouteChoice` agents.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RouteChoiceSettings {
+    /// Seconds between two evaluations of the agents' choices.
+    pub interval_seconds: f32,
+    /// Agents closer than this to an objective are counted as queueing there.
+    pub queue_radius: f32,
+    /// Agents an objective that is not a `Sink` lets through per second.
+    pub service_rate: f32,
+}
+
+impl Default for RouteChoiceSettings {
+    fn default() -> Self {
+        Self {
+            interval_seconds: 2.,
+            queue_radius: 60.,
+            service_rate: 1.,
+        }
+    }
+}
+
+/// Regions of `GridMap<RegionLabel>` from which targets can be reached.
+#[derive(Resource, Debug, Default)]
+pub struct ReachableRegions {
+    /// Regions containing at least one target cell.
+    pub any_target: HashSet<u32>,
+    /// Regions touching each objective.
+    pub per_objective: HashMap<Entity, HashSet<u32>>,
+}
+
+/// Rooms and doors of the walkable space. Cells are mapped to rooms by
+/// `GridMap<RoomLabel>`.
+#[derive(Resource, Debug, Default)]
+pub struct RoomGraph {
+    pub rooms: Vec<Room>,
+    pub doors: Vec<Door>,
+}
+
+/// Class whose layers the overlays draw. `None` draws the shared layers.
+#[derive(Resource, Debug, Clone, Copy, Default)]
+pub struct OverlayClass(pub Option<usize>);
+
+/// Any-angle steering: agents head straight for the farthest cell, up to
+/// `max_cells` steps down the flow field, that they can see. Zero disables it.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LookAhead{
+    pub max_cells: usize
+}
+
+/// Where the flow fields, the layers derived from the obstacles (wall
+/// distance, rooms, class and destination fields) and the navigation mesh are
+/// computed.
+///
+/// The hierarchical flow field ignores it: the shared sector graph and the
+/// sectors agents step into are always built in `PreUpdate`, since agents
+/// need the sector they stand in during the same frame.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlowFieldExecution{
+    /// In `PreUpdate`, during the frame the maps changed.
+    Synchronous,
+    /// On the async compute task pool from a snapshot of the maps. Agents keep
+    /// following the previous layers until the new ones are swapped in.
+    #[default]
+    Asynchronous
+}
+
+/// Layer computed on the async compute task pool, held by the system that
+/// swaps it in.
+pub struct BackgroundTask<T>{
+    task: Option<Task<T>>
+}
+
+impl<T> Default for BackgroundTask<T> {
+    fn default() -> Self {
+        Self { task: None }
+    }
+}
+
+impl<T: Send + 'static> BackgroundTask<T> {
+    pub fn is_running(&self) -> bool {
+        self.task.is_some()
+    }
+
+    /// Result of the task, once it is done.
+    pub fn poll(&mut self) -> Option<T> {
+        let result = block_on(poll_once(self.task.as_mut()?))?;
+        self.task = None;
+        Some(result)
+    }
+
+    /// Spawns `work`, or runs it right away and returns its result when the
+    /// execution is synchronous.
+    pub fn start(&mut self, execution: FlowFieldExecution, work: impl FnOnce() -> T + Send + 'static) -> Option<T> {
+        match execution {
+            FlowFieldExecution::Synchronous => Some(work()),
+            FlowFieldExecution::Asynchronous => {
+                self.task = Some(AsyncComputeTaskPool::get().spawn(async move { work() }));
+                None
+            },
+        }
+    }
+}
+
+/// How `apply_vector_map` reads the vector field at an agent's position.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VectorFieldSampling{
+    /// Value of the cell containing the agent.
+    Nearest,
+    /// Bilinear blend of the four closest cells, skipping blocked ones.
+    #[default]
+    Bilinear
+}
+
+/// How the vector field is derived from the proximity map.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VectorFieldMode{
+    /// Sum of the neighbour directions weighted by their inverse distance.
+    /// Targets, plateaus and isolated cells produce NaN vectors.
+    Weighted,
+    /// Steepest descent towards the neighbour with the lowest distance.
+    /// Every cell gets a finite vector.
+    #[default]
+    Gradient
+}
+
+/// Coarse graph over the grid used by the hierarchical flow field.
+///
+/// The grid is split in square sectors of `sector_size` cells. Portals between
+/// neighbouring sectors are the nodes of the graph. `target_distance` holds
+/// the distance from each node to the closest target of its own sector and
+/// `node_distance` the distance to the closest target overall. Cell level
+/// fields are only built for the sectors listed in `built_sectors`.
+#[derive(Resource, Clone)]
+pub struct SectorGraph {
+    pub sector_size: usize,
+    pub nodes: Vec<PortalNode>,
+    pub edges: Vec<Vec<(usize, f32)>>,
+    pub sector_nodes: HashMap<IVec2, Vec<usize>>,
+    pub target_distance: Vec<f32>,
+    pub node_distance: Vec<f32>,
+    pub built_sectors: HashSet<IVec2>,
+}
+
+impl SectorGraph {
+    pub fn new(sector_size: usize) -> Self {
+        Self {
+            sector_size,
+            nodes: Vec::new(),
+            edges: Vec::new(),
+            sector_nodes: HashMap::new(),
+            target_distance: Vec::new(),
+            node_distance: Vec::new(),
+            built_sectors: HashSet::new(),
+        }
+    }
+
+    /// Removes the portals with a side in one of `sectors`, with their edges.
+    /// The other nodes are renumbered.
+    pub fn remove_portals(&mut self, sectors: &HashSet<IVec2>) {
+
+        let kept: Vec<bool> = self.nodes.iter()
+            .map(|node| !sectors.contains(&node.sector) && !sectors.contains(&self.nodes[node.pair].sector))
+            .collect();
+
+        let mut index = vec![None; self.nodes.len()];
+        let mut next = 0;
+
+        for (node, kept) in kept.iter().enumerate() {
+            if *kept {
+                index[node] = Some(next);
+                next += 1;
+            }
+        }
+
+        let nodes = std::mem::take(&mut self.nodes);
+        let edges = std::mem::take(&mut self.edges);
+        let target_distance = std::mem::take(&mut self.target_distance);
+        let node_distance = std::mem::take(&mut self.node_distance);
+
+        for (node, ((mut portal, node_edges), (target, distance))) in nodes.into_iter().zip(edges).zip(target_distance.into_iter().zip(node_distance)).enumerate() {
+            if !kept[node] {
+                continue;
+            }
+
+            portal.pair = index[portal.pair].expect("both sides of a portal are removed together");
+            self.nodes.push(portal);
+            self.edges.push(node_edges.into_iter().filter_map(|(to, cost)| Some((index[to]?, cost))).collect());
+            self.target_distance.push(target);
+            self.node_distance.push(distance);
+        }
+
+        self.sector_nodes.clear();
+
+        for (node, portal) in self.nodes.iter().enumerate() {
+            self.sector_nodes.entry(portal.sector).or_default().push(node);
+        }
+    }
+
+    pub fn get_sector(&self, cell: IVec2) -> IVec2 {
+        cell.div_euclid(IVec2::splat(self.sector_size as i32))
+    }
+
+    /// Cells covered by `sector`. `max` is exclusive and may lie outside the grid.
+    pub fn sector_region(&self, sector: IVec2) -> IRect {
+        let min = sector * self.sector_size as i32;
+        IRect::from_corners(min, min + IVec2::splat(self.sector_size as i32))
+    }
+}
@@ -1,10 +1,20 @@
-use std::collections::VecDeque;
+use std::{collections::{HashMap, HashSet, VecDeque}, ops::DerefMut, path::Path};
 
-use bevy::{color::palettes::tailwind::{GREEN_500, PURPLE_500, RED_500}, prelude::*, state::state};
+use bevy::{color::palettes::tailwind::{GREEN_500, ORANGE_500, PURPLE_500, RED_500}, ecs::system::SystemParam, prelude::*, state::state};
 
-use crate::{ components::{Agent, MotivationForce, Speed}, consts::{AGENT_DESIRED_SPEED, AGENT_MASS}, plugins::simulation_area::resources::SimulationArea, GridMap, Shape};
+use crate::{ components::{NEIGHBOURS_8, Agent, AgentClass, DesiredSpeed, Destination, Follow, Itinerary, MotivationForce, Objective, Queued, RouteChoice, RouteChoiceModel, Sink, Speed, Trapped, Zone}, consts::{AGENT_DESIRED_SPEED, AGENT_MASS}, plugins::{navmesh::resources::NavigationBackend, simulation_area::resources::SimulationArea}, GridMap, Shape};
 
-use super::{models::*, resources::{PathFindingOverlayState, ShowGridState}};
+use super::{bitmap::export_layer, events::AgentTrapped, models::*, resources::{BackgroundTask, BaseLayer, ClassFields, Clearance, DestinationFields, FlowFieldExecution, ReachableRegions, RoomGraph, LookAhead, WallAvoidance, RouteChoiceSettings, NavigationClass, NavigationClasses, OverlayClass, PathFindingOverlayState, SectorGraph, ShowGridState, VectorFieldMode, VectorFieldSampling}};
+
+/// Settings the class and destination fields are built with.
+#[derive(SystemParam)]
+pub struct FieldSettings<'w> {
+    pub clearance: Res<'w, Clearance>,
+    pub avoidance: Res<'w, WallAvoidance>,
+    pub mode: Res<'w, VectorFieldMode>,
+    pub sectors: Option<Res<'w, SectorGraph>>,
+    pub execution: Res<'w, FlowFieldExecution>,
+}
 
 pub fn setup(simulation_area: Res<SimulationArea>, mut commands: Commands){
 
@@ -47,324 +57,2463 @@ pub fn setup(simulation_area: Res<SimulationArea>, mut commands: Commands){
     );
 }
 
-pub fn create_colision_map<T, U>(mut map: ResMut<GridMap<T>>, targets: Query<(&Transform, &Shape), (With<U>, Changed<Transform>)> ) where T: CellStatus + 'static, U: Component{
-    
-    if !targets.is_empty(){
-        map.reset(T::default());
+pub fn create_colision_map<T, U>(
+    mut map: ResMut<GridMap<T>>,
+    mut footprints: Local<HashMap<Entity, IRect>>,
+    changed: Query<Entity, (With<U>, Changed<Transform>)>,
+    mut removed: RemovedComponents<U>,
+    targets: Query<(Entity, &Transform, &Shape), With<U>>,
+    base: Option<Res<BaseLayer<T>>>,
+) where T: CellStatus + 'static, U: Component{
+
+    let mut dirty: Option<IRect> = None;
+
+    if base.as_ref().is_some_and(|base| base.is_changed()) {
+        dirty = Some(map.full_region());
     }
-    
-    for (transform, shape) in &targets {
 
-        let Shape::Circle(radius) = shape;
+    let mut extend_dirty = |region: IRect| {
+        dirty = Some(match dirty {
+            Some(dirty) => dirty.union(region),
+            None => region,
+        });
+    };
+
+    for entity in removed.read() {
+        if let Some(footprint) = footprints.remove(&entity){
+            extend_dirty(footprint);
+        }
+    }
 
-        let center = transform.translation.truncate();
-        let rect = shape.get_rectangle_with_center(center);
+    for entity in &changed {
+        if let Some(footprint) = footprints.remove(&entity){
+            extend_dirty(footprint);
+        }
 
-        let region = map.cells_within_rect(rect);
-        
-        let region = match region {
-            Some(v) => v,
-            None => continue,
+        let Ok((_, transform, shape)) = targets.get(entity) else {
+            continue;
         };
 
-        for x in region.min.x..region.max.x {
-            for y in region.min.y..region.max.y {
+        let rect = shape.get_rectangle_with_center(transform.translation.truncate());
 
-                
-                let cell = IVec2::new(x, y);
+        if let Some(region) = map.cells_within_rect(rect){
+            footprints.insert(entity, region);
+            extend_dirty(region);
+        }
+    }
 
-                if let Some(value) = map.get_value_at_cell(cell){
+    let dirty = match dirty {
+        Some(region) => map.clamp_region(region),
+        None => return,
+    };
 
-                    if value == T::get_non_default_value(){
-                        continue;
-                    }
-                }
-                else {
-                    continue;
-                }
+    for cell in map.cells_in_region(dirty) {
+        let value = base.as_ref().and_then(|base| base.0.get_value_at_cell(cell)).unwrap_or_default();
 
-                let cell_center = map.get_coord(cell);
+        map.set_value(cell, value).ok();
+    }
 
-                if(cell_center - center).length() >= *radius {
-                    let _ = map.set_value(cell, T::default());
-                    continue;
-                }
-                
-                let _ = map.set_value(cell, T::get_non_default_value());
-            }
+    for (_, transform, shape) in &targets {
+        for cell in cells_in_shape(&map, transform.translation.truncate(), shape, dirty) {
+            map.set_value(cell, T::get_non_default_value()).ok();
         }
+    }
+}
 
-        
+/// Cells of `clip` whose centre lies inside `shape` placed at `center`.
+fn cells_in_shape<T>(map: &GridMap<T>, center: Vec2, shape: &Shape, clip: IRect) -> Vec<IVec2> where T: Clone + Copy{
+
+    let is_clipped = |cell: &IVec2| cell.cmpge(clip.min).all() && cell.cmplt(clip.max).all();
+
+    if let Shape::Circle(radius) = shape {
+        return map.cells_in_circle(center, *radius).filter(is_clipped).collect();
     }
+
+    let Some(region) = map.cells_within_rect(shape.get_rectangle_with_center(center)) else {
+        return Vec::new();
+    };
+
+    map.cells_in_region(region)
+        .filter(is_clipped)
+        .filter(|&cell| shape.contains(center, map.get_coord(cell)))
+        .collect()
 }
 
-pub fn create_vector_map(mut vector_field: ResMut<GridMap<Vec2>>, proximity_map: ResMut<GridMap<TargetProximity>>){
-    
-    if !proximity_map.is_changed() {
+/// Marks the free cells closer than [`Clearance`] to an obstacle.
+///
+/// The distance of every cell to the closest blocked cell is computed with an
+/// exact Euclidean distance transform and kept in `GridMap<WallDistance>`, up
+/// to [`wall_distance_cap`]. When obstacles move, only the cells within the
+/// cap of the changed region are computed again, from the obstacles within
+/// twice the cap. Only values that actually change are written, so the dirty
+/// region handed to the proximity map stays limited to the band around the
+/// obstacles that moved and the wall distance layer is only flagged as changed
+/// when one of its distances is.
+pub fn inflate_obstacles(
+    mut obstacles_map: ResMut<GridMap<BlockedStatus>>,
+    mut wall_distance: ResMut<GridMap<WallDistance>>,
+    clearance: Res<Clearance>,
+    avoidance: Res<WallAvoidance>,
+    classes: Res<NavigationClasses>,
+    execution: Res<FlowFieldExecution>,
+    mut current_cap: Local<Option<f32>>,
+    mut pending: Local<Option<IRect>>,
+    mut pending_full: Local<bool>,
+    mut task: Local<BackgroundTask<Inflation>>,
+){
+
+    if let Some(inflation) = task.poll() {
+        inflation.apply(&mut obstacles_map, &mut wall_distance);
+    }
+
+    let cap = wall_distance_cap(clearance.0, &classes, *avoidance, obstacles_map.cell_dimentions);
+
+    if obstacles_map.is_changed() || clearance.is_changed() || *current_cap != Some(cap) {
+        match obstacles_map.dirty_region() {
+            _ if clearance.is_changed() || *current_cap != Some(cap) || obstacles_map.is_fully_dirty() => *pending_full = true,
+            Some(region) => *pending = Some(pending.map_or(region, |pending| pending.union(region))),
+            None => {},
+        }
+
+        *current_cap = Some(cap);
+    }
+
+    if task.is_running() || pending.is_none() && !*pending_full {
         return;
     }
-    
-    for x_center in 0..proximity_map.columns{
-        for y_center in 0..proximity_map.rows{
 
-            let center: IVec2 = IVec2::new(x_center as i32, y_center as i32);
-            let mut values = [Vec2::ZERO; 8];
-            let mut i = 0;
+    // A full rebuild covers the pending region too.
+    let region = pending.take().filter(|_| !std::mem::take(&mut *pending_full));
+    let clearance = clearance.0;
+    let obstacles = obstacles_map.clone();
+    let distances = wall_distance.clone();
+
+    if let Some(inflation) = task.start(*execution, move || inflate(&obstacles, &distances, region, cap, clearance)) {
+        inflation.apply(&mut obstacles_map, &mut wall_distance);
+    }
+}
+
+/// Distance from the walls up to which `GridMap<WallDistance>` is exact: one
+/// cell past the widest clearance plus the [`WallAvoidance`] margin, beyond
+/// which no layer tells distances apart.
+fn wall_distance_cap(clearance: f32, classes: &NavigationClasses, avoidance: WallAvoidance, cell_dimentions: Vec2) -> f32{
+    classes.0.iter().map(|class| class.clearance).fold(clearance, f32::max) + avoidance.margin.max(0.) + cell_dimentions.max_element()
+}
+
+/// Wall distances closer than this are left as they are.
+const WALL_DISTANCE_EPSILON: f32 = 1e-3;
+
+/// Changes to the obstacle and wall distance layers.
+#[derive(Default)]
+pub struct Inflation{
+    /// New wall distance layer, replacing the whole current one.
+    rebuilt: Option<GridMap<WallDistance>>,
+    distances: Vec<(IVec2, WallDistance)>,
+    statuses: HashMap<IVec2, BlockedStatus>,
+}
+
+impl Inflation {
+    /// Writes the changes. Cells blocked since the changes were computed are
+    /// left blocked.
+    fn apply(self, obstacles_map: &mut impl DerefMut<Target = GridMap<BlockedStatus>>, wall_distance: &mut impl DerefMut<Target = GridMap<WallDistance>>){
 
-            for dx in -1..=1{
-                for dy in -1..=1{
+        if let Some(rebuilt) = self.rebuilt {
+            **wall_distance = rebuilt;
+        }
+
+        for (cell, distance) in self.distances {
+            wall_distance.set_value(cell, distance).ok();
+        }
+
+        for (cell, status) in self.statuses {
+            if !matches!(obstacles_map.get_value_at_cell(cell), None | Some(BlockedStatus::Blocked)) {
+                obstacles_map.set_value(cell, status).ok();
+            }
+        }
+    }
+}
 
-                    if dx == 0 && dy == 0{
-                        continue;
+/// Wall distances, capped at `cap`, and clearance statuses after the obstacles
+/// inside `region` changed, or from scratch when `region` is `None`.
+///
+/// The distances of the cells within `cap` of a region only depend on the
+/// blocked cells within twice `cap`, so the distance transform runs over that
+/// window alone. Rebuilding from scratch runs it around each allocated chunk
+/// of the obstacle map holding a blocked cell.
+fn inflate(obstacles_map: &GridMap<BlockedStatus>, wall_distance: &GridMap<WallDistance>, region: Option<IRect>, cap: f32, clearance: f32) -> Inflation{
+
+    let reach = (cap / obstacles_map.cell_dimentions.min_element()).ceil() as i32;
+    let is_blocked = |cell: IVec2| obstacles_map.get_value_at_cell(cell) == Some(BlockedStatus::Blocked);
+
+    let mut inflation = Inflation {
+        rebuilt: region.is_none().then(|| wall_distance.new_like(WallDistance(cap))),
+        ..default()
+    };
+
+    let sources = match region {
+        Some(region) => vec![region],
+        None => obstacles_map.chunk_regions().into_iter()
+            .filter(|&chunk| obstacles_map.cells_in_region(chunk).any(is_blocked))
+            .collect(),
+    };
+
+    for source in sources {
+        let window = obstacles_map.clamp_region(source.inflate(2 * reach));
+        let target = obstacles_map.clamp_region(source.inflate(reach));
+        let distances = window_distances(obstacles_map, window, cap);
+        let index = |cell: IVec2| ((cell.x - window.min.x) + (cell.y - window.min.y) * window.width()) as usize;
+
+        for cell in obstacles_map.cells_in_region(target) {
+            let distance = distances[index(cell)];
+
+            match &mut inflation.rebuilt {
+                Some(rebuilt) if distance < cap => {
+                    rebuilt.set_value(cell, WallDistance(distance)).ok();
+                },
+                Some(_) => {},
+                None => {
+                    let WallDistance(current) = wall_distance.get_value_at_cell(cell).unwrap_or_default();
+
+                    if (current - distance).abs() > WALL_DISTANCE_EPSILON {
+                        inflation.distances.push((cell, WallDistance(distance)));
                     }
+                },
+            }
 
-                    let delta = IVec2::new(dx, dy);
-                    let current_pos = center + delta;
-                    values[i] = match proximity_map.get_value_at_cell(current_pos) {
-                        Some(TargetProximity::Computed(value)) => 1./value * delta.as_vec2(),
-                        _ => Vec2::ZERO,
-                    };
-                
-                    i += 1;
-                }
+            if let Some(status) = clearance_status(obstacles_map, cell, distance, clearance) {
+                inflation.statuses.insert(cell, status);
             }
+        }
+    }
 
-            let final_vector = values.iter().fold(Vec2::ZERO, |acc, &v| acc + v);
-            let final_vector = final_vector.normalize() * AGENT_DESIRED_SPEED;
+    // Clearance cells left around obstacles that are gone lie in the
+    // allocated chunks.
+    if let Some(rebuilt) = &inflation.rebuilt {
+        for cell in obstacles_map.chunk_regions().into_iter().flat_map(|region| obstacles_map.cells_in_region(region)) {
+            let WallDistance(distance) = rebuilt.get_value_at_cell(cell).unwrap_or_default();
 
-            vector_field.set_value(center, final_vector).ok();
+            if let Some(status) = clearance_status(obstacles_map, cell, distance, clearance) {
+                inflation.statuses.insert(cell, status);
+            }
         }
     }
-    
+
+    inflation
 }
 
-pub fn compute_proximity_map(mut proximity_map: ResMut<GridMap<TargetProximity>>, obstacles_map: Res<GridMap<BlockedStatus>>, target_map: Res<GridMap<TargetStatus>>){
-    
-    if !obstacles_map.is_changed() && !target_map.is_changed(){
-        return;
-    }
+/// New status of a free cell `distance` away from the closest obstacle, if
+/// it differs from the current one.
+fn clearance_status(obstacles_map: &GridMap<BlockedStatus>, cell: IVec2, distance: f32, clearance: f32) -> Option<BlockedStatus>{
 
-    let mut open_list = VecDeque::new();
+    let status = match obstacles_map.get_value_at_cell(cell)? {
+        BlockedStatus::Blocked => return None,
+        status => status,
+    };
 
-    proximity_map.reset(TargetProximity::NotComputed);
+    let new_status = if distance <= clearance {
+        BlockedStatus::Clearance
+    } else {
+        BlockedStatus::Empty
+    };
 
-    for x in 0..proximity_map.columns {
-        for y in 0..proximity_map.rows {
-            let pos: IVec2 = IVec2::new(x as i32, y as i32);
+    (new_status != status).then_some(new_status)
+}
 
-            let proximity = match (obstacles_map.get_value_at_cell(pos), target_map.get_value_at_cell(pos)) {
-                (Some(BlockedStatus::Blocked), _) => TargetProximity::Unreachable,
-                (_, Some(TargetStatus::IsTarget)) => TargetProximity::Computed(0.),
-                (_, _) => TargetProximity::NotComputed
-            };
+/// Distances, capped at `cap`, from the cells of `window` to the closest
+/// blocked cell of the window, row by row.
+fn window_distances(obstacles_map: &GridMap<BlockedStatus>, window: IRect, cap: f32) -> Vec<f32>{
 
-            if let TargetProximity::Computed(_) = proximity{
-                open_list.push_back(pos);
-            }
+    let columns = window.width().max(0) as usize;
+    let rows = window.height().max(0) as usize;
+    let spacing = obstacles_map.cell_dimentions;
 
-            proximity_map.set_value(pos, proximity).ok();
+    let mut squared: Vec<f32> = (0..columns * rows)
+        .map(|i| match obstacles_map.get_value_at_cell(window.min + IVec2::new((i % columns) as i32, (i / columns) as i32)) {
+            Some(BlockedStatus::Blocked) => 0.,
+            _ => UNREACHED_SQUARED_DISTANCE,
+        })
+        .collect();
+
+    for x in 0..columns {
+        let column: Vec<f32> = (0..rows).map(|y| squared[x + y * columns]).collect();
+
+        for (y, value) in squared_distance_transform(&column, spacing.y).into_iter().enumerate() {
+            squared[x + y * columns] = value;
         }
     }
 
-    while let Some(pivot_pos) = open_list.pop_front(){
-        let value_pivot_pos =  proximity_map.get_value_at_cell(pivot_pos);
+    for y in 0..rows {
+        let row = &squared[y * columns..(y + 1) * columns];
+        let row = squared_distance_transform(row, spacing.x);
+        squared[y * columns..(y + 1) * columns].copy_from_slice(&row);
+    }
 
-        let value_pivot_pos = match value_pivot_pos {
-            None | Some(TargetProximity::Unreachable | TargetProximity::NotComputed)=> continue,
-            Some(TargetProximity::Computed(value)) => value,
-        };
+    squared.into_iter().map(|value| value.sqrt().min(cap)).collect()
+}
 
-        for x in -1..=1{
-            for y in -1..=1{
+/// Stand-in for an infinite squared distance that keeps the arithmetic of the
+/// distance transform finite.
+const UNREACHED_SQUARED_DISTANCE: f32 = 1e20;
 
-                if x == 0 && y == 0{
-                    continue;
-                }
+/// One dimensional squared Euclidean distance transform of `values` for
+/// samples `spacing` apart (Felzenszwalb and Huttenlocher).
+fn squared_distance_transform(values: &[f32], spacing: f32) -> Vec<f32>{
+
+    let n = values.len();
+    let position = |q: usize| q as f32 * spacing;
 
-                let current_cell = pivot_pos + IVec2::new(x, y);
+    let mut distances = vec![0.; n];
+    let mut parabolas = vec![0_usize; n];
+    let mut boundaries = vec![0.; n + 1];
+    let mut k = 0;
 
-                let delta = match (x, y) {
-                    (0, _) | (_, 0) => 1.,
-                    (_, _) => 2_f32.sqrt(),
-                };
+    if n == 0 {
+        return distances;
+    }
 
-                let value_at_cell = proximity_map.get_value_at_cell(pivot_pos + IVec2::new(x, y));
+    boundaries[0] = f32::NEG_INFINITY;
+    boundaries[1] = f32::INFINITY;
 
-                match value_at_cell {
-                    None | Some(TargetProximity::Unreachable)=> {},
-                    Some(TargetProximity::NotComputed) => {
-                        proximity_map.set_value(current_cell, TargetProximity::Computed(value_pivot_pos + delta)).unwrap();
-                        open_list.push_back(current_cell);
-                    },
-                    Some(TargetProximity::Computed(value)) => {
-                        let new_distance = value_pivot_pos + delta;
-                        let distance = f32::min(value, new_distance);
-                        proximity_map.set_value(current_cell, TargetProximity::Computed(distance)).unwrap();
-                    }  
-                };
-            }
+    let intersection = |q: usize, p: usize| {
+        ((values[q] + position(q).powi(2)) - (values[p] + position(p).powi(2))) / (2. * (position(q) - position(p)))
+    };
+
+    for q in 1..n {
+        let mut s = intersection(q, parabolas[k]);
+
+        while s <= boundaries[k] {
+            k -= 1;
+            s = intersection(q, parabolas[k]);
+        }
+
+        k += 1;
+        parabolas[k] = q;
+        boundaries[k] = s;
+        boundaries[k + 1] = f32::INFINITY;
+    }
+
+    k = 0;
+
+    for (q, distance) in distances.iter_mut().enumerate() {
+        while boundaries[k + 1] < position(q) {
+            k += 1;
         }
+
+        let p = parabolas[k];
+        *distance = (position(q) - position(p)).powi(2) + values[p];
     }
+
+    distances
 }
 
-pub fn apply_vector_map(vector_field: ResMut<GridMap<Vec2>>, mut agents: Query<(&mut MotivationForce, &Transform, &Speed), With<Agent>>){
+pub fn create_vector_map(mut vector_field: ResMut<GridMap<Vec2>>, mut proximity_map: ResMut<GridMap<TargetProximity>>, mode: Res<VectorFieldMode>){
     
-    for (mut motivation_force, transform, agent_speed) in &mut agents {
+    let region = proximity_map.bypass_change_detection().take_dirty_region();
 
-        let pos = transform.translation.truncate();
+    let region = match region {
+        _ if mode.is_changed() => proximity_map.full_region(),
+        Some(region) => proximity_map.clamp_region(region.inflate(1)),
+        None => return,
+    };
 
-        let base_vector = match vector_field.get_value_at(pos){
-            Some(value) => value,
-            None => continue,
+    fill_vector_map(&mut vector_field, &proximity_map, region, *mode);
+}
+
+/// Recomputes the vectors of `region`. Whole maps are filled in parallel.
+fn fill_vector_map(vector_field: &mut GridMap<Vec2>, proximity_map: &GridMap<TargetProximity>, region: IRect, mode: VectorFieldMode){
+
+    let vector_at = |center: IVec2| match mode {
+        VectorFieldMode::Weighted => weighted_vector(proximity_map, center),
+        VectorFieldMode::Gradient => gradient_vector(proximity_map, center),
+    };
+
+    if vector_field.clamp_region(region) == vector_field.full_region() {
+        vector_field.par_update(|center, _| vector_at(center));
+        return;
+    }
+
+    for center in vector_field.cells_in_region(region) {
+        vector_field.set_value(center, vector_at(center)).ok();
+    }
+}
+
+/// Sum of the directions to the reached neighbours of `center`, weighted by
+/// their closeness to a target. Targets and unreached cells get
+/// [`FALLBACK_VECTOR`], as do cells whose neighbours cancel out.
+fn weighted_vector(proximity_map: &GridMap<TargetProximity>, center: IVec2) -> Vec2{
+
+    match proximity_map.get_value_at_cell(center) {
+        Some(TargetProximity::Computed(value)) if value <= 0. => return FALLBACK_VECTOR,
+        None | Some(TargetProximity::NotComputed) => return FALLBACK_VECTOR,
+        _ => {},
+    }
+
+    let mut values = [Vec2::ZERO; 8];
+
+    for (i, delta) in NEIGHBOURS_8.iter().enumerate(){
+        let current_pos = center + *delta;
+        values[i] = match proximity_map.get_value_at_cell(current_pos) {
+            _ if cuts_corner(center, *delta, |cell| is_unreachable(proximity_map, cell)) => Vec2::ZERO,
+            Some(TargetProximity::Computed(value)) => 1./value.max(PROXIMITY_EPSILON) * delta.as_vec2(),
+            _ => Vec2::ZERO,
         };
+    }
 
-        if base_vector.is_nan(){
+    let final_vector = values.iter().fold(Vec2::ZERO, |acc, &v| acc + v);
+    final_vector.try_normalize().map_or(FALLBACK_VECTOR, |direction| direction * AGENT_DESIRED_SPEED)
+}
+
+/// Direction of steepest descent of the proximity map at `center`.
+///
+/// The slope towards each neighbour is its drop in distance divided by the
+/// step length. Neighbours sharing the steepest slope are averaged, so a cell
+/// between two equally good orthogonal moves points diagonally between them.
+/// Targets, unreached cells and local minima get [`FALLBACK_VECTOR`]. Blocked
+/// cells point to their closest reachable neighbour so agents pushed into them
+/// can walk back out, and fall back too when no neighbour is reached.
+/// Diagonal moves around a blocked corner are not considered.
+fn gradient_vector(proximity_map: &GridMap<TargetProximity>, center: IVec2) -> Vec2{
+
+    let center_value = match proximity_map.get_value_at_cell(center) {
+        Some(TargetProximity::Computed(value)) if value <= 0. => return FALLBACK_VECTOR,
+        Some(TargetProximity::Computed(value)) => value,
+        Some(TargetProximity::Unreachable) => f32::INFINITY,
+        None | Some(TargetProximity::NotComputed) => return FALLBACK_VECTOR,
+    };
+
+    let mut best_slope = 0.;
+    let mut best_direction = Vec2::ZERO;
+
+    for delta in NEIGHBOURS_8{
+        let Some(TargetProximity::Computed(value)) = proximity_map.get_value_at_cell(center + delta) else {
+            continue;
+        };
+
+        if center_value.is_finite() && cuts_corner(center, delta, |cell| is_unreachable(proximity_map, cell)) {
             continue;
         }
-        
-        let final_force = base_vector - agent_speed.0;
 
-        motivation_force.0 = final_force;
+        let slope = if center_value.is_finite() {
+            (center_value - value) / step_cost(delta)
+        } else {
+            1. / (1. + value)
+        };
+
+        if slope <= PROXIMITY_EPSILON {
+            continue;
+        }
+
+        if slope > best_slope + PROXIMITY_EPSILON {
+            best_slope = slope;
+            best_direction = delta.as_vec2().normalize();
+        } else if slope >= best_slope - PROXIMITY_EPSILON {
+            best_direction += delta.as_vec2().normalize();
+        }
     }
+
+    best_direction.try_normalize().map_or(FALLBACK_VECTOR, |direction| direction * AGENT_DESIRED_SPEED)
 }
 
+/// Vector of the cells with no way down the proximity map: targets, cells the
+/// propagation never reached and enclosed blocked cells. Agents standing on
+/// them are no longer pushed by the field; arrival and trapped detection take
+/// over from there.
+const FALLBACK_VECTOR: Vec2 = Vec2::ZERO;
 
-pub fn handle_grid_state_inputs(grid_state: Res<State<ShowGridState>>, mut nex_grid_state: ResMut<NextState<ShowGridState>>, keys: Res<ButtonInput<KeyCode>>) {
+/// Tolerance used when comparing distances of the proximity map.
+const PROXIMITY_EPSILON: f32 = 1e-4;
+
+/// Whether a step of `delta` from `cell` squeezes diagonally past a cell for
+/// which `is_blocked` holds. Diagonal steps need both orthogonal neighbours
+/// to be free.
+fn cuts_corner(cell: IVec2, delta: IVec2, is_blocked: impl Fn(IVec2) -> bool) -> bool{
+    delta.x != 0 && delta.y != 0 && (is_blocked(cell + delta.with_y(0)) || is_blocked(cell + delta.with_x(0)))
+}
+
+fn is_unreachable(proximity_map: &GridMap<TargetProximity>, cell: IVec2) -> bool{
+    matches!(proximity_map.get_value_at_cell(cell), Some(TargetProximity::Unreachable))
+}
+
+/// Cost multiplier of entering a cell, growing as the cell gets closer to an
+/// obstacle than `clearance` plus the [`WallAvoidance`] margin.
+fn wall_cost(wall_distance: &GridMap<WallDistance>, avoidance: WallAvoidance, clearance: f32) -> impl Fn(IVec2) -> f32 + '_{
+    move |cell| match wall_distance.get_value_at_cell(cell) {
+        Some(WallDistance(distance)) => avoidance.cost(distance, clearance),
+        None => 1.,
+    }
+}
+
+pub fn compute_proximity_map(
+    mut proximity_map: ResMut<GridMap<TargetProximity>>,
+    mut obstacles_map: ResMut<GridMap<BlockedStatus>>,
+    mut target_map: ResMut<GridMap<TargetStatus>>,
+    wall_distance: Res<GridMap<WallDistance>>,
+    clearance: Res<Clearance>,
+    avoidance: Res<WallAvoidance>,
+){
     
-    if keys.just_pressed(KeyCode::KeyG) {
-        match grid_state.get() {
-            ShowGridState::HideGrid => nex_grid_state.set(ShowGridState::ShowGrid),
-            ShowGridState::ShowGrid => nex_grid_state.set(ShowGridState::HideGrid),
+    let mut changes = MapChanges::take(&mut obstacles_map, &mut target_map).with_reach(avoidance.reach(obstacles_map.cell_dimentions));
+    changes.obstacles_full |= avoidance.is_changed();
+
+    update_proximity_map(&mut proximity_map, &obstacles_map, &target_map, changes, wall_cost(&wall_distance, *avoidance, clearance.0));
+}
+
+/// Changes to the obstacle and target maps since the last flow field update.
+#[derive(Clone, Copy)]
+struct MapChanges{
+    obstacles: Option<IRect>,
+    obstacles_full: bool,
+    targets: Option<IRect>,
+}
+
+impl MapChanges {
+    fn take(obstacles_map: &mut ResMut<GridMap<BlockedStatus>>, target_map: &mut ResMut<GridMap<TargetStatus>>) -> Self{
+        Self {
+            obstacles_full: obstacles_map.is_fully_dirty(),
+            obstacles: obstacles_map.bypass_change_detection().take_dirty_region(),
+            targets: target_map.bypass_change_detection().take_dirty_region(),
         }
     }
+
+    /// Grows the obstacle region by `cells` so it also covers the cells
+    /// whose wall avoidance cost depends on the obstacles that changed.
+    fn with_reach(mut self, cells: i32) -> Self{
+        self.obstacles = self.obstacles.map(|region| region.inflate(cells));
+        self
+    }
+
+    fn is_empty(&self) -> bool{
+        self.obstacles.is_none() && self.targets.is_none() && !self.obstacles_full
+    }
 }
 
-pub fn handle_overlay_inputs(state: Res<State<PathFindingOverlayState>>, mut next_state: ResMut<NextState<PathFindingOverlayState>>, keys: Res<ButtonInput<KeyCode>>) {
-    
-    let mut next = Option::None;
+fn update_proximity_map(proximity_map: &mut GridMap<TargetProximity>, obstacles_map: &GridMap<BlockedStatus>, target_map: &GridMap<TargetStatus>, changes: MapChanges, cell_cost: impl Fn(IVec2) -> f32){
 
-    if keys.just_pressed(KeyCode::KeyO) {
-        next = Some(PathFindingOverlayState::ShowObstacles);
+    match (changes.obstacles, changes.targets) {
+        _ if changes.obstacles_full => rebuild_proximity_map(proximity_map, obstacles_map, target_map, cell_cost),
+        (None, None) => {},
+        (Some(region), None) => repair_proximity_map(proximity_map, obstacles_map, target_map, region, cell_cost),
+        (_, _) => rebuild_proximity_map(proximity_map, obstacles_map, target_map, cell_cost),
     }
+}
 
-    if keys.just_pressed(KeyCode::KeyP) {
-        next = Some(PathFindingOverlayState::ShowProimity);
+/// Updates the shared proximity and vector maps on the async compute task
+/// pool. Changes made while a task runs are picked up by the next one.
+pub fn update_flow_field_task(
+    mut proximity_map: ResMut<GridMap<TargetProximity>>,
+    mut vector_field: ResMut<GridMap<Vec2>>,
+    mut obstacles_map: ResMut<GridMap<BlockedStatus>>,
+    mut target_map: ResMut<GridMap<TargetStatus>>,
+    wall_distance: Res<GridMap<WallDistance>>,
+    settings: FieldSettings,
+    mut mode_changed: Local<bool>,
+    mut avoidance_changed: Local<bool>,
+    mut task: Local<BackgroundTask<(GridMap<TargetProximity>, GridMap<Vec2>)>>,
+){
+
+    if let Some(layers) = task.poll() {
+        (*proximity_map, *vector_field) = layers;
     }
 
-    if keys.just_pressed(KeyCode::KeyT) {
-        next = Some(PathFindingOverlayState::ShowTargets);
+    *mode_changed |= settings.mode.is_changed();
+    *avoidance_changed |= settings.avoidance.is_changed();
+
+    if task.is_running() {
+        return;
     }
 
-    if keys.just_pressed(KeyCode::KeyV) {
-        next = Some(PathFindingOverlayState::ShowVectorField);
+    let mut changes = MapChanges::take(&mut obstacles_map, &mut target_map).with_reach(settings.avoidance.reach(obstacles_map.cell_dimentions));
+    changes.obstacles_full |= std::mem::take(&mut *avoidance_changed);
+
+    if changes.is_empty() && !*mode_changed {
+        return;
     }
 
-    if let Some(new_value) = next{
-        if new_value == *state.get(){
-            next_state.set(PathFindingOverlayState::ShowNone);
-        } else {
-            next_state.set(new_value);
+    let refill_all = std::mem::take(&mut *mode_changed);
+    let (mode, avoidance, clearance) = (*settings.mode, *settings.avoidance, settings.clearance.0);
+
+    let mut proximity = proximity_map.clone();
+    let mut vectors = vector_field.clone();
+    let obstacles_map = obstacles_map.clone();
+    let target_map = target_map.clone();
+    let wall_distance = wall_distance.clone();
+
+    let work = move || {
+        update_proximity_map(&mut proximity, &obstacles_map, &target_map, changes, wall_cost(&wall_distance, avoidance, clearance));
+
+        let region = match proximity.take_dirty_region() {
+            _ if refill_all => Some(proximity.full_region()),
+            Some(region) => Some(proximity.clamp_region(region.inflate(1))),
+            None => None,
+        };
+
+        if let Some(region) = region {
+            fill_vector_map(&mut vectors, &proximity, region, mode);
         }
+
+        (proximity, vectors)
+    };
+
+    if let Some(layers) = task.start(*settings.execution, work) {
+        (*proximity_map, *vector_field) = layers;
     }
 }
 
+fn base_proximity(obstacles_map: &GridMap<BlockedStatus>, target_map: &GridMap<TargetStatus>, pos: IVec2) -> TargetProximity{
+    match (obstacles_map.get_value_at_cell(pos), target_map.get_value_at_cell(pos)) {
+        (Some(BlockedStatus::Blocked), _) => TargetProximity::Unreachable,
+        (_, Some(TargetStatus::IsTarget)) => TargetProximity::Computed(0.),
+        (Some(BlockedStatus::Clearance), _) => TargetProximity::Unreachable,
+        (_, _) => TargetProximity::NotComputed
+    }
+}
 
-pub fn draw_grid(mut gizmos: Gizmos, map: Res<GridMap<BlockedStatus>>){
-    
-    gizmos
-        .grid_2d(
-            map.area.center(),
-            0.,
-            UVec2::new(map.columns as u32, map.rows as u32),
-            map.cell_dimentions,
-            LinearRgba::gray(0.05),
-        )
-        .outer_edges();
+fn step_cost(delta: IVec2) -> f32{
+    match (delta.x, delta.y) {
+        (0, _) | (_, 0) => 1.,
+        (_, _) => 2_f32.sqrt(),
+    }
 }
 
-pub fn draw_obstacles(mut gizmos: Gizmos, map: Res<GridMap<BlockedStatus>>){
+/// Recomputes the whole proximity map. Only the chunks of the obstacle and
+/// target maps holding something are visited to seed it, since every other
+/// cell starts as [`TargetProximity::NotComputed`] anyway.
+fn rebuild_proximity_map(proximity_map: &mut GridMap<TargetProximity>, obstacles_map: &GridMap<BlockedStatus>, target_map: &GridMap<TargetStatus>, cell_cost: impl Fn(IVec2) -> f32){
 
-    let global_offset = Vec2::new(map.columns as f32, map.rows as f32) / 2.;
-    let color = Color::from(RED_500);
+    let mut open_list = VecDeque::new();
 
-    for x in 0..map.columns {
-        for y in 0..map.rows {
-            
-            if let Some(BlockedStatus::Empty) = map.get_value_at_cell(IVec2::new(x as i32, y as i32)){
-                continue;
-            }
+    proximity_map.reset(TargetProximity::NotComputed);
 
-        
-            let cell_top_left = map.area.center() + (Vec2::new(x as f32,  y as f32) - global_offset) * map.cell_dimentions;
+    let mut regions = obstacles_map.chunk_regions();
+    regions.extend(target_map.chunk_regions());
+    regions.sort_by_key(|region| (region.min.x, region.min.y, region.max.x, region.max.y));
+    regions.dedup();
 
-            gizmos.line_2d(cell_top_left, cell_top_left + map.cell_dimentions, color);
-            gizmos.line_2d(cell_top_left + map.cell_dimentions.with_x(0.), cell_top_left + map.cell_dimentions.with_y(0.), color);
+    for pos in regions.into_iter().flat_map(|region| obstacles_map.cells_in_region(region)) {
+        let proximity = base_proximity(obstacles_map, target_map, pos);
+
+        if let TargetProximity::Computed(_) = proximity{
+            open_list.push_back(pos);
         }
+
+        proximity_map.set_value(pos, proximity).ok();
     }
 
+    propagate_proximity(proximity_map, open_list, cell_cost);
 }
 
-pub fn draw_targets(mut gizmos: Gizmos, map: Res<GridMap<TargetStatus>>){
+/// Repairs the proximity map after the obstacles inside `region` changed.
+///
+/// Every distance that was derived from a cell of `region` is invalidated,
+/// then the invalidated cells are filled again from the still valid cells
+/// around them. Cells that can now be reached through a shorter path are
+/// lowered by the same propagation. The region is grown by one cell so
+/// diagonal steps around corners of the changed cells are checked again.
+fn repair_proximity_map(proximity_map: &mut GridMap<TargetProximity>, obstacles_map: &GridMap<BlockedStatus>, target_map: &GridMap<TargetStatus>, region: IRect, cell_cost: impl Fn(IVec2) -> f32){
+
+    let mut invalidated = VecDeque::new();
+    let mut reseed = Vec::new();
+
+    for pos in proximity_map.cells_in_region(region.inflate(1)) {
+        if let Some(TargetProximity::Computed(value)) = proximity_map.get_value_at_cell(pos){
+            invalidated.push_back((pos, value));
+        }
 
-    let global_offset = Vec2::new(map.columns as f32, map.rows as f32) / 2.;
-    let color = Color::from(GREEN_500);
+        proximity_map.set_value(pos, base_proximity(obstacles_map, target_map, pos)).ok();
+        reseed.push(pos);
+    }
 
-    for x in 0..map.columns {
-        for y in 0..map.rows {
-            
-            if let Some(TargetStatus::NotTarget) = map.get_value_at_cell(IVec2::new(x as i32, y as i32)){
+    while let Some((pivot_pos, previous_value)) = invalidated.pop_front(){
+        for delta in NEIGHBOURS_8{
+            let current_cell = pivot_pos + delta;
+
+            if let Some(TargetStatus::IsTarget) = target_map.get_value_at_cell(current_cell){
                 continue;
             }
 
-        
-            let cell_top_left = map.area.center() + (Vec2::new(x as f32,  y as f32) - global_offset) * map.cell_dimentions;
+            let Some(TargetProximity::Computed(value)) = proximity_map.get_value_at_cell(current_cell) else {
+                continue;
+            };
 
-            gizmos.line_2d(cell_top_left, cell_top_left + map.cell_dimentions, color);
-            gizmos.line_2d(cell_top_left + map.cell_dimentions.with_x(0.), cell_top_left + map.cell_dimentions.with_y(0.), color);
+            if (value - (previous_value + step_cost(delta) * cell_cost(current_cell))).abs() > PROXIMITY_EPSILON {
+                continue;
+            }
+
+            proximity_map.set_value(current_cell, TargetProximity::NotComputed).ok();
+            invalidated.push_back((current_cell, value));
+            reseed.push(current_cell);
+        }
+    }
+
+    let mut open_list = VecDeque::new();
+
+    for pos in reseed {
+        for current_cell in std::iter::once(pos).chain(proximity_map.neighbours_8(pos)) {
+
+            if let Some(TargetProximity::Computed(_)) = proximity_map.get_value_at_cell(current_cell){
+                open_list.push_back(current_cell);
+            }
         }
     }
 
+    propagate_proximity(proximity_map, open_list, cell_cost);
 }
 
-pub fn draw_proximity(mut gizmos: Gizmos, map: Res<GridMap<TargetProximity>>){
+/// Spreads the distances of the cells in `open_list` to the rest of the map.
+/// Entering a cell costs the step length scaled by `cell_cost` of that cell.
+/// Diagonal steps past an unreachable cell are not taken.
+fn propagate_proximity(proximity_map: &mut GridMap<TargetProximity>, mut open_list: VecDeque<IVec2>, cell_cost: impl Fn(IVec2) -> f32){
 
-    let global_offset = Vec2::new(map.columns as f32, map.rows as f32) / 2.;
-    
+    while let Some(pivot_pos) = open_list.pop_front(){
+        let value_pivot_pos =  proximity_map.get_value_at_cell(pivot_pos);
 
-    for x in 0..map.columns {
-        for y in 0..map.rows {
-            
-            if let Some(TargetProximity::Unreachable) = map.get_value_at_cell(IVec2::new(x as i32, y as i32)){
+        let value_pivot_pos = match value_pivot_pos {
+            None | Some(TargetProximity::Unreachable | TargetProximity::NotComputed)=> continue,
+            Some(TargetProximity::Computed(value)) => value,
+        };
+
+        for delta in NEIGHBOURS_8{
+            let current_cell = pivot_pos + delta;
+
+            if cuts_corner(pivot_pos, delta, |cell| is_unreachable(proximity_map, cell)) {
                 continue;
             }
 
-            let color = match map.get_value_at_cell(IVec2::new(x as i32, y as i32)) {
-                None | Some(TargetProximity::Unreachable) => continue,
-                Some(TargetProximity::NotComputed) => Color::from(PURPLE_500),
-                Some(TargetProximity::Computed(value)) => Color::from(GREEN_500).with_alpha(1./(value + 1.)),
+            let new_distance = value_pivot_pos + step_cost(delta) * cell_cost(current_cell);
+
+            match proximity_map.get_value_at_cell(current_cell) {
+                None | Some(TargetProximity::Unreachable)=> {},
+                Some(TargetProximity::NotComputed) => {
+                    proximity_map.set_value(current_cell, TargetProximity::Computed(new_distance)).unwrap();
+                    open_list.push_back(current_cell);
+                },
+                Some(TargetProximity::Computed(value)) => {
+                    if new_distance < value - PROXIMITY_EPSILON {
+                        proximity_map.set_value(current_cell, TargetProximity::Computed(new_distance)).unwrap();
+                        open_list.push_back(current_cell);
+                    }
+                }  
             };
-        
-            let cell_top_left = map.area.center() + (Vec2::new(x as f32,  y as f32) - global_offset) * map.cell_dimentions;
-
-            gizmos.line_2d(cell_top_left, cell_top_left + map.cell_dimentions, color);
-            gizmos.line_2d(cell_top_left + map.cell_dimentions.with_x(0.), cell_top_left + map.cell_dimentions.with_y(0.), color);
         }
     }
-
 }
 
-pub fn draw_vectors(mut gizmos: Gizmos, map: Res<GridMap<Vec2>>){
+pub fn update_sector_graph(
+    mut graph: ResMut<SectorGraph>,
+    mut proximity_map: ResMut<GridMap<TargetProximity>>,
+    mut vector_field: ResMut<GridMap<Vec2>>,
+    mut obstacles_map: ResMut<GridMap<BlockedStatus>>,
+    mut target_map: ResMut<GridMap<TargetStatus>>,
+    wall_distance: Res<GridMap<WallDistance>>,
+    clearance: Res<Clearance>,
+    avoidance: Res<WallAvoidance>,
+){
+
+    let mut changes = MapChanges::take(&mut obstacles_map, &mut target_map).with_reach(avoidance.reach(obstacles_map.cell_dimentions));
+    changes.obstacles_full |= avoidance.is_changed();
+
+    let region = match (changes.obstacles, changes.targets) {
+        _ if changes.obstacles_full => obstacles_map.full_region(),
+        (Some(obstacles), Some(targets)) => obstacles.union(targets),
+        (Some(region), None) | (None, Some(region)) => region,
+        (None, None) => return,
+    };
+
+    let region = obstacles_map.clamp_region(region);
+    let stale = update_sectors(&mut graph, &obstacles_map, &target_map, region, wall_cost(&wall_distance, *avoidance, clearance.0));
+
+    if region == obstacles_map.full_region() {
+        proximity_map.reset(TargetProximity::NotComputed);
+        vector_field.reset(Vec2::ZERO);
+        return;
+    }
 
-    let global_offset = Vec2::new(map.columns as f32, map.rows as f32) / 2.;
+    clear_sectors(&mut proximity_map, &mut vector_field, &graph, stale);
+}
+
+/// Forgets the cell level fields of `sectors`, to be built again when agents
+/// stand in them.
+fn clear_sectors(proximity_map: &mut GridMap<TargetProximity>, vector_field: &mut GridMap<Vec2>, graph: &SectorGraph, sectors: Vec<IVec2>){
+    for sector in sectors {
+        for cell in proximity_map.cells_in_region(graph.sector_region(sector)) {
+            if !matches!(proximity_map.get_value_at_cell(cell), Some(TargetProximity::NotComputed)) {
+                proximity_map.set_value(cell, TargetProximity::NotComputed).ok();
+            }
+
+            if vector_field.get_value_at_cell(cell) != Some(Vec2::ZERO) {
+                vector_field.set_value(cell, Vec2::ZERO).ok();
+            }
+        }
+    }
+}
+
+/// Builds the cell level fields of the sectors agents stand in, for the shared
+/// field and for the class and destination fields they follow.
+pub fn build_occupied_sectors(
+    mut graph: ResMut<SectorGraph>,
+    mut proximity_map: ResMut<GridMap<TargetProximity>>,
+    mut vector_field: ResMut<GridMap<Vec2>>,
+    mut class_fields: ResMut<ClassFields>,
+    mut destination_fields: ResMut<DestinationFields>,
+    classes: Res<NavigationClasses>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    target_map: Res<GridMap<TargetStatus>>,
+    wall_distance: Res<GridMap<WallDistance>>,
+    clearance: Res<Clearance>,
+    avoidance: Res<WallAvoidance>,
+    mode: Res<VectorFieldMode>,
+    backend: Res<NavigationBackend>,
+    agents: Query<(&Transform, Option<&AgentClass>, Option<&Destination>, Has<RouteChoice>), With<Agent>>,
+){
+
+    if mode.is_changed() {
+        graph.built_sectors.clear();
+    }
+
+    // With the navigation mesh steering, only route choice reads the fields.
+    let steers = *backend == NavigationBackend::FlowField;
+
+    // Building sectors doesn't change what the fields lead to, so it must not
+    // make the class and destination fields look rebuilt.
+    let class_fields = class_fields.bypass_change_detection();
+    let destination_fields = destination_fields.bypass_change_detection();
+
+    for (transform, class, destination, chooses_route) in &agents {
+
+        let Some(cell) = obstacles_map.get_cell(transform.translation.truncate()) else {
+            continue;
+        };
+
+        let class = class.map(|class| class.0);
+        let layers = class.and_then(|class| classes.0.get(class).zip(class_fields.0.get(class)));
+
+        // Route choice compares the fields of every objective at the agent.
+        let mut followed = false;
+
+        for ((objective, field_class), field) in destination_fields.0.iter_mut() {
+
+            if *field_class != class || !chooses_route && (!steers || destination.map(|destination| destination.0) != Some(*objective)) {
+                continue;
+            }
+
+            followed |= destination.map(|destination| destination.0) == Some(*objective);
+
+            let Some(graph) = field.sectors.as_mut() else {
+                continue;
+            };
+
+            match layers {
+                Some((class, layers)) => build_sector_at(graph, cell, &mut field.proximity, &mut field.vectors, &layers.obstacles, &field.targets, class_cost(&layers.zone_cost, &wall_distance, *avoidance, class.clearance), *mode),
+                None => build_sector_at(graph, cell, &mut field.proximity, &mut field.vectors, &obstacles_map, &field.targets, wall_cost(&wall_distance, *avoidance, clearance.0), *mode),
+            }
+        }
+
+        if followed || !steers {
+            continue;
+        }
+
+        match class.and_then(|class| classes.0.get(class).zip(class_fields.0.get_mut(class))) {
+            Some((class, field)) => {
+                let ClassField { obstacles, zone_cost, proximity, vectors, sectors } = field;
+
+                if let Some(graph) = sectors.as_mut() {
+                    build_sector_at(graph, cell, proximity, vectors, obstacles, &target_map, class_cost(zone_cost, &wall_distance, *avoidance, class.clearance), *mode);
+                }
+            },
+            None => build_sector_at(&mut graph, cell, &mut proximity_map, &mut vector_field, &obstacles_map, &target_map, wall_cost(&wall_distance, *avoidance, clearance.0), *mode),
+        }
+    }
+}
+
+/// Builds the cell level field of the sector of `cell`, unless it is built.
+fn build_sector_at(
+    graph: &mut SectorGraph,
+    cell: IVec2,
+    proximity_map: &mut GridMap<TargetProximity>,
+    vector_field: &mut GridMap<Vec2>,
+    obstacles_map: &GridMap<BlockedStatus>,
+    target_map: &GridMap<TargetStatus>,
+    cell_cost: impl Fn(IVec2) -> f32,
+    mode: VectorFieldMode,
+){
+
+    let sector = graph.get_sector(cell);
+
+    if !graph.built_sectors.contains(&sector) {
+        build_sector_field(graph, sector, proximity_map, vector_field, obstacles_map, target_map, cell_cost, mode);
+    }
+}
+
+/// Distances inside `region` from the given seed cells, walking around blocked
+/// cells with steps scaled by `cell_cost`. Cells that cannot be reached are
+/// left at `f32::INFINITY`.
+fn local_distances(obstacles_map: &GridMap<BlockedStatus>, region: IRect, seeds: &[(IVec2, f32)], cell_cost: &impl Fn(IVec2) -> f32) -> Vec<f32>{
+
+    let is_blocked = |cell: IVec2| matches!(obstacles_map.get_value_at_cell(cell), Some(BlockedStatus::Blocked | BlockedStatus::Clearance));
+
+    let size = region.size();
+    let index = |cell: IVec2| ((cell.x - region.min.x) + (cell.y - region.min.y) * size.x) as usize;
+    let inside = |cell: IVec2| cell.cmpge(region.min).all() && cell.cmplt(region.max).all();
+
+    let mut distances = vec![f32::INFINITY; (size.x * size.y).max(0) as usize];
+    let mut open_list = VecDeque::new();
+
+    for &(cell, distance) in seeds {
+        if !inside(cell) || distance >= distances[index(cell)] {
+            continue;
+        }
+
+        distances[index(cell)] = distance;
+        open_list.push_back(cell);
+    }
+
+    while let Some(pivot_pos) = open_list.pop_front(){
+        let value_pivot_pos = distances[index(pivot_pos)];
+
+        for delta in NEIGHBOURS_8{
+            let current_cell = pivot_pos + delta;
+
+            if !inside(current_cell) {
+                continue;
+            }
+
+            if is_blocked(current_cell) || cuts_corner(pivot_pos, delta, is_blocked) {
+                continue;
+            }
+
+            let new_distance = value_pivot_pos + step_cost(delta) * cell_cost(current_cell);
+
+            if new_distance < distances[index(current_cell)] - PROXIMITY_EPSILON {
+                distances[index(current_cell)] = new_distance;
+                open_list.push_back(current_cell);
+            }
+        }
+    }
+
+    distances
+}
+
+fn add_portals(graph: &mut SectorGraph, obstacles_map: &GridMap<BlockedStatus>, border: impl Iterator<Item = (IVec2, IVec2)>){
+
+    let is_empty = |cell: IVec2| matches!(obstacles_map.get_value_at_cell(cell), Some(BlockedStatus::Empty));
+
+    let mut runs: Vec<(Vec<IVec2>, Vec<IVec2>)> = Vec::new();
+    let mut open_run = false;
+
+    for (inner, outer) in border {
+        if !is_empty(inner) || !is_empty(outer) {
+            open_run = false;
+            continue;
+        }
+
+        if !open_run {
+            runs.push((Vec::new(), Vec::new()));
+            open_run = true;
+        }
+
+        let run = runs.last_mut().unwrap();
+        run.0.push(inner);
+        run.1.push(outer);
+    }
+
+    for (inner_cells, outer_cells) in runs {
+        let inner = graph.nodes.len();
+        let outer = inner + 1;
+
+        for (node, cells, pair) in [(inner, inner_cells, outer), (outer, outer_cells, inner)] {
+            let sector = graph.get_sector(cells[0]);
+            graph.nodes.push(PortalNode { sector, cells, pair });
+            graph.edges.push(vec![(pair, 1.)]);
+            graph.target_distance.push(f32::INFINITY);
+            graph.node_distance.push(f32::INFINITY);
+            graph.sector_nodes.entry(sector).or_default().push(node);
+        }
+    }
+}
+
+/// Rebuilds the portals and edges of the sectors overlapping `region` and of
+/// their neighbours, then the distance of every node to the closest target.
+///
+/// Returns the built sectors whose cell level fields no longer hold: those
+/// overlapping `region` and those whose portals now lead elsewhere. They are
+/// removed from `built_sectors`.
+fn update_sectors(graph: &mut SectorGraph, obstacles_map: &GridMap<BlockedStatus>, target_map: &GridMap<TargetStatus>, region: IRect, cell_cost: impl Fn(IVec2) -> f32) -> Vec<IVec2>{
+
+    let sector_count = IVec2::new(
+        obstacles_map.columns.div_ceil(graph.sector_size) as i32,
+        obstacles_map.rows.div_ceil(graph.sector_size) as i32,
+    );
+    let in_grid = |sector: IVec2| sector.cmpge(IVec2::ZERO).all() && sector.cmplt(sector_count).all();
+
+    let (first, last) = (graph.get_sector(region.min).max(IVec2::ZERO), graph.get_sector(region.max - IVec2::ONE).min(sector_count - IVec2::ONE));
+    let changed: HashSet<IVec2> = (first.x..=last.x)
+        .flat_map(|sx| (first.y..=last.y).map(move |sy| IVec2::new(sx, sy)))
+        .collect();
+
+    let seeds: HashMap<IVec2, Vec<(Vec<IVec2>, f32)>> = graph.built_sectors.iter()
+        .filter(|sector| !changed.contains(sector))
+        .map(|&sector| (sector, portal_seeds(graph, sector)))
+        .collect();
+
+    graph.remove_portals(&changed);
+
+    for &sector in &changed {
+        for axis in [IVec2::X, IVec2::Y] {
+            if in_grid(sector + axis) {
+                add_sector_border(graph, obstacles_map, sector, axis);
+            }
+
+            if in_grid(sector - axis) && !changed.contains(&(sector - axis)) {
+                add_sector_border(graph, obstacles_map, sector - axis, axis);
+            }
+        }
+    }
+
+    let connected: HashSet<IVec2> = changed.iter()
+        .flat_map(|&sector| [IVec2::ZERO, IVec2::X, IVec2::NEG_X, IVec2::Y, IVec2::NEG_Y].map(|delta| sector + delta))
+        .filter(|&sector| in_grid(sector))
+        .collect();
+
+    for sector in connected {
+        connect_sector(graph, obstacles_map, target_map, sector, &cell_cost);
+    }
+
+    propagate_node_distances(graph);
+
+    let stale: Vec<IVec2> = graph.built_sectors.iter()
+        .filter(|sector| seeds.get(sector).is_none_or(|seeds| !same_seeds(seeds, &portal_seeds(graph, **sector))))
+        .copied()
+        .collect();
+
+    for sector in &stale {
+        graph.built_sectors.remove(sector);
+    }
+
+    stale
+}
+
+/// Adds the portals across the border between `sector` and its neighbour
+/// along `axis`.
+fn add_sector_border(graph: &mut SectorGraph, obstacles_map: &GridMap<BlockedStatus>, sector: IVec2, axis: IVec2){
+
+    let region = obstacles_map.clamp_region(graph.sector_region(sector));
+
+    match axis == IVec2::X {
+        true => add_portals(graph, obstacles_map, (region.min.y..region.max.y).map(|y| (IVec2::new(region.max.x - 1, y), IVec2::new(region.max.x, y)))),
+        false => add_portals(graph, obstacles_map, (region.min.x..region.max.x).map(|x| (IVec2::new(x, region.max.y - 1), IVec2::new(x, region.max.y)))),
+    }
+}
+
+/// Recomputes the edges between the portals of a sector and their distance to
+/// the targets of the sector. The edge to the other side of each portal is
+/// kept, with the cost of stepping onto the other side.
+fn connect_sector(graph: &mut SectorGraph, obstacles_map: &GridMap<BlockedStatus>, target_map: &GridMap<TargetStatus>, sector: IVec2, cell_cost: &impl Fn(IVec2) -> f32){
+
+    let region = obstacles_map.clamp_region(graph.sector_region(sector));
+    let nodes = graph.sector_nodes.get(&sector).cloned().unwrap_or_default();
+
+    let closest = |graph: &SectorGraph, distances: &[f32], node: usize| graph.nodes[node].cells.iter()
+        .map(|cell| distances[((cell.x - region.min.x) + (cell.y - region.min.y) * region.width()) as usize])
+        .fold(f32::INFINITY, f32::min);
+
+    for &from in &nodes {
+        let pair = graph.nodes[from].pair;
+        let crossing = graph.nodes[pair].cells.iter().map(|&cell| cell_cost(cell)).fold(f32::INFINITY, f32::min);
+
+        graph.edges[from].retain(|(to, _)| *to == pair);
+
+        for (_, cost) in &mut graph.edges[from] {
+            *cost = crossing;
+        }
+
+        let seeds: Vec<_> = graph.nodes[from].cells.iter().map(|&cell| (cell, 0.)).collect();
+        let distances = local_distances(obstacles_map, region, &seeds, cell_cost);
+
+        for &to in &nodes {
+            let distance = closest(graph, &distances, to);
+
+            if to != from && distance.is_finite() {
+                graph.edges[from].push((to, distance));
+            }
+        }
+    }
+
+    let targets: Vec<_> = obstacles_map.cells_in_region(region)
+        .filter(|&cell| target_map.get_value_at_cell(cell) == Some(TargetStatus::IsTarget))
+        .map(|cell| (cell, 0.))
+        .collect();
+
+    let distances = match targets.is_empty() {
+        true => Vec::new(),
+        false => local_distances(obstacles_map, region, &targets, cell_cost),
+    };
+
+    for &node in &nodes {
+        graph.target_distance[node] = match targets.is_empty() {
+            true => f32::INFINITY,
+            false => closest(graph, &distances, node),
+        };
+    }
+}
+
+/// Distance from every node to the closest target, through the graph.
+fn propagate_node_distances(graph: &mut SectorGraph){
+
+    let mut node_distance = graph.target_distance.clone();
+    let mut open_list: VecDeque<usize> = (0..node_distance.len()).filter(|&node| node_distance[node].is_finite()).collect();
+
+    while let Some(node) = open_list.pop_front() {
+        for &(neighbour, cost) in &graph.edges[node] {
+            let new_distance = node_distance[node] + cost;
+
+            if new_distance < node_distance[neighbour] - PROXIMITY_EPSILON {
+                node_distance[neighbour] = new_distance;
+                open_list.push_back(neighbour);
+            }
+        }
+    }
+
+    graph.node_distance = node_distance;
+}
+
+/// Portals of a sector with the distance of their far side, which seed the
+/// cell level field of the sector.
+fn portal_seeds(graph: &SectorGraph, sector: IVec2) -> Vec<(Vec<IVec2>, f32)>{
+
+    let mut seeds: Vec<_> = graph.sector_nodes.get(&sector).into_iter().flatten()
+        .map(|&node| (graph.nodes[node].cells.clone(), graph.node_distance[graph.nodes[node].pair]))
+        .collect();
+
+    seeds.sort_by_key(|(cells, _)| cells.iter().map(|cell| (cell.x, cell.y)).collect::<Vec<_>>());
+    seeds
+}
+
+fn same_seeds(before: &[(Vec<IVec2>, f32)], after: &[(Vec<IVec2>, f32)]) -> bool{
+    before.len() == after.len() && before.iter().zip(after).all(|((cells, distance), (other_cells, other_distance))| {
+        cells == other_cells && (distance == other_distance || (distance - other_distance).abs() <= PROXIMITY_EPSILON)
+    })
+}
+
+/// Builds the cell level proximity and vector fields of a single sector.
+///
+/// Targets inside the sector are seeded at distance zero and every portal
+/// leaving the sector is seeded with the distance of the node on its far side,
+/// so the local field leads either to a target or to the best portal.
+fn build_sector_field(
+    graph: &mut SectorGraph,
+    sector: IVec2,
+    proximity_map: &mut GridMap<TargetProximity>,
+    vector_field: &mut GridMap<Vec2>,
+    obstacles_map: &GridMap<BlockedStatus>,
+    target_map: &GridMap<TargetStatus>,
+    cell_cost: impl Fn(IVec2) -> f32,
+    mode: VectorFieldMode,
+){
+
+    let region = obstacles_map.clamp_region(graph.sector_region(sector));
+
+    let mut seeds: Vec<_> = obstacles_map.cells_in_region(region)
+        .filter(|&cell| target_map.get_value_at_cell(cell) == Some(TargetStatus::IsTarget))
+        .map(|cell| (cell, 0.))
+        .collect();
+
+    for &node in graph.sector_nodes.get(&sector).into_iter().flatten() {
+        let far_side = &graph.nodes[graph.nodes[node].pair];
+        let distance = graph.node_distance[graph.nodes[node].pair];
+
+        if !distance.is_finite() {
+            continue;
+        }
+
+        seeds.extend(graph.nodes[node].cells.iter().map(|&cell| (cell, distance + cell_cost(cell))));
+
+        if graph.built_sectors.contains(&far_side.sector) {
+            continue;
+        }
+
+        for &cell in &far_side.cells {
+            proximity_map.set_value(cell, TargetProximity::Computed(distance)).ok();
+        }
+    }
+
+    let distances = local_distances(obstacles_map, region, &seeds, &cell_cost);
+
+    for cell in obstacles_map.cells_in_region(region) {
+        let distance = distances[((cell.x - region.min.x) + (cell.y - region.min.y) * region.width()) as usize];
+
+        let proximity = match obstacles_map.get_value_at_cell(cell) {
+            Some(BlockedStatus::Blocked) => TargetProximity::Unreachable,
+            _ if distance.is_finite() => TargetProximity::Computed(distance),
+            Some(BlockedStatus::Clearance) => TargetProximity::Unreachable,
+            _ => TargetProximity::NotComputed,
+        };
+
+        proximity_map.set_value(cell, proximity).ok();
+    }
+
+    fill_vector_map(vector_field, proximity_map, region, mode);
+
+    graph.built_sectors.insert(sector);
+}
+
+pub fn update_class_fields(
+    classes: Res<NavigationClasses>,
+    mut class_fields: ResMut<ClassFields>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    wall_distance: Res<GridMap<WallDistance>>,
+    target_map: Res<GridMap<TargetStatus>>,
+    avoidance: Res<WallAvoidance>,
+    mode: Res<VectorFieldMode>,
+    sectors: Option<Res<SectorGraph>>,
+    execution: Res<FlowFieldExecution>,
+    zones: Query<(&Transform, &Shape, &Zone)>,
+    changed_zones: Query<(), (With<Zone>, Or<(Changed<Transform>, Changed<Zone>)>)>,
+    mut removed_zones: RemovedComponents<Zone>,
+    mut pending: Local<bool>,
+    mut task: Local<BackgroundTask<Vec<ClassField>>>,
+){
+
+    if let Some(fields) = task.poll() {
+        class_fields.0 = fields;
+    }
+
+    let zones_removed = removed_zones.read().count() > 0;
+
+    *pending |= classes.is_changed()
+        || obstacles_map.is_changed()
+        || target_map.is_changed()
+        || mode.is_changed()
+        || avoidance.is_changed()
+        || !changed_zones.is_empty()
+        || zones_removed;
+
+    if task.is_running() || !std::mem::take(&mut *pending) {
+        return;
+    }
+
+    let classes = classes.0.clone();
+    let obstacles_map = obstacles_map.clone();
+    let wall_distance = wall_distance.clone();
+    let target_map = target_map.clone();
+    let zones: Vec<_> = zones.iter()
+        .map(|(transform, shape, zone)| (transform.translation.truncate(), shape.clone(), zone.label.clone()))
+        .collect();
+    let (avoidance, mode, sector_size) = (*avoidance, *mode, sectors.map(|graph| graph.sector_size));
+
+    let work = move || classes.iter()
+        .map(|class| build_class_field(class, &obstacles_map, &wall_distance, &target_map, &zones, avoidance, mode, sector_size))
+        .collect();
+
+    if let Some(fields) = task.start(*execution, work) {
+        class_fields.0 = fields;
+    }
+}
+
+/// Builds the full set of navigation layers for one agent class.
+///
+/// Obstacles are inflated with the class clearance, forbidden zones are
+/// blocked and the cost multipliers of the zones covering a cell are combined
+/// with the wall avoidance cost for the class clearance.
+fn build_class_field(
+    class: &NavigationClass,
+    obstacles_map: &GridMap<BlockedStatus>,
+    wall_distance: &GridMap<WallDistance>,
+    target_map: &GridMap<TargetStatus>,
+    zones: &[(Vec2, Shape, String)],
+    avoidance: WallAvoidance,
+    mode: VectorFieldMode,
+    sector_size: Option<usize>,
+) -> ClassField{
+
+    let mut obstacles = obstacles_map.zip_with(wall_distance, |status, WallDistance(distance)| match status {
+        BlockedStatus::Blocked => BlockedStatus::Blocked,
+        _ if distance <= class.clearance => BlockedStatus::Clearance,
+        _ => BlockedStatus::Empty,
+    });
+    let mut zone_cost = obstacles_map.new_like(1_f32);
+
+    for (position, shape, label) in zones {
+        let forbidden = class.forbidden_zones.contains(label);
+        let multiplier = class.cost_multipliers.get(label).copied();
+
+        for cell in cells_in_shape(&obstacles, *position, shape, obstacles.full_region()) {
+            if forbidden {
+                obstacles.set_value(cell, BlockedStatus::Blocked).ok();
+            } else if let (Some(multiplier), Some(current)) = (multiplier, zone_cost.get_value_at_cell(cell)) {
+                zone_cost.set_value(cell, current * multiplier).ok();
+            }
+        }
+    }
+
+    let (proximity, vectors, sectors) = build_field_layers(&obstacles, target_map, class_cost(&zone_cost, wall_distance, avoidance, class.clearance), mode, sector_size);
+
+    ClassField { obstacles, zone_cost, proximity, vectors, sectors }
+}
+
+/// Proximity and vector layers leading to `target_map`. With a sector size,
+/// only the sector graph is built and the cell level layers are left for
+/// [`build_occupied_sectors`].
+fn build_field_layers(
+    obstacles_map: &GridMap<BlockedStatus>,
+    target_map: &GridMap<TargetStatus>,
+    cell_cost: impl Fn(IVec2) -> f32,
+    mode: VectorFieldMode,
+    sector_size: Option<usize>,
+) -> (GridMap<TargetProximity>, GridMap<Vec2>, Option<SectorGraph>){
+
+    let mut proximity = obstacles_map.new_like(TargetProximity::NotComputed);
+    let mut vectors = obstacles_map.new_like(Vec2::ZERO);
+
+    if let Some(sector_size) = sector_size {
+        let mut graph = SectorGraph::new(sector_size);
+        update_sectors(&mut graph, obstacles_map, target_map, obstacles_map.full_region(), cell_cost);
+
+        return (proximity, vectors, Some(graph));
+    }
+
+    rebuild_proximity_map(&mut proximity, obstacles_map, target_map, cell_cost);
+
+    let region = vectors.full_region();
+    fill_vector_map(&mut vectors, &proximity, region, mode);
+
+    (proximity, vectors, None)
+}
+
+/// Cost of entering a cell for the agents of a class: the wall avoidance cost
+/// for the class clearance times the cost multipliers of the zones.
+fn class_cost<'a>(zone_cost: &'a GridMap<f32>, wall_distance: &'a GridMap<WallDistance>, avoidance: WallAvoidance, clearance: f32) -> impl Fn(IVec2) -> f32 + 'a{
+    let near_wall = wall_cost(wall_distance, avoidance, clearance);
+    move |cell| zone_cost.get_value_at_cell(cell).unwrap_or(1.) * near_wall(cell)
+}
+
+/// Keeps a flow field for every objective and class pair agents use: the
+/// destination of each agent for its class, and every objective for the
+/// classes of the `RouteChoice` agents.
+///
+/// Fields are built from scratch when they are first used or their objective
+/// moves. When obstacles change, they are repaired around the change, as the
+/// shared field is: the dirty region of the obstacle map for the fields on the
+/// shared layers, and the cells that differ from the previous class layers for
+/// the fields of a class.
+pub fn update_destination_fields(
+    mut destination_fields: ResMut<DestinationFields>,
+    classes: Res<NavigationClasses>,
+    class_fields: Res<ClassFields>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    wall_distance: Res<GridMap<WallDistance>>,
+    settings: FieldSettings,
+    objectives: Query<(Entity, &Transform, &Shape), With<Objective>>,
+    changed: Query<(), (With<Objective>, Changed<Transform>)>,
+    agents: Query<(Option<&Destination>, Option<&AgentClass>, Has<RouteChoice>), With<Agent>>,
+    mut pending: Local<HashMap<(Entity, Option<usize>), Option<IRect>>>,
+    mut in_flight: Local<HashSet<(Entity, Option<usize>)>>,
+    mut previous_class_layers: Local<Vec<(GridMap<BlockedStatus>, GridMap<f32>)>>,
+    mut task: Local<BackgroundTask<Vec<((Entity, Option<usize>), DestinationField)>>>,
+){
+
+    let FieldSettings { clearance, avoidance, mode, sectors, execution } = settings;
+
+    if let Some(fields) = task.poll() {
+        destination_fields.0.extend(fields);
+        in_flight.clear();
+    }
+
+    let mut used: HashSet<(Entity, Option<usize>)> = HashSet::new();
+
+    for (destination, class, chooses_route) in &agents {
+        let class = class.map(|class| class.0);
+
+        used.extend(destination.filter(|destination| objectives.contains(destination.0)).map(|destination| (destination.0, class)));
+
+        if chooses_route {
+            used.extend(objectives.iter().map(|(objective, _, _)| (objective, class)));
+        }
+    }
+
+    if destination_fields.0.keys().any(|key| !used.contains(key)) {
+        destination_fields.0.retain(|key, _| used.contains(key));
+    }
+
+    // Fields of a class whose layers are not built yet use the shared layers,
+    // as agents without a destination do. `None` asks for a rebuild.
+    let has_class_layers = |class: Option<usize>| class.is_some_and(|class| class < class_fields.0.len());
+    let mut changes: Vec<(Option<usize>, Option<IRect>)> = Vec::new();
+
+    if mode.is_changed() || avoidance.is_changed() {
+        changes.push((None, None));
+        changes.extend((0..class_fields.0.len()).map(|class| (Some(class), None)));
+    }
+
+    if obstacles_map.is_changed() || clearance.is_changed() {
+        let region = match obstacles_map.dirty_region() {
+            _ if clearance.is_changed() || obstacles_map.is_fully_dirty() => None,
+            region => region.map(|region| obstacles_map.clamp_region(region.inflate(avoidance.reach(obstacles_map.cell_dimentions)))),
+        };
+
+        if region.is_some() || obstacles_map.is_fully_dirty() || clearance.is_changed() {
+            changes.push((None, region));
+        }
+    }
+
+    if class_fields.is_changed() {
+        previous_class_layers.truncate(class_fields.0.len());
+
+        for (class, field) in class_fields.0.iter().enumerate() {
+            let region = match previous_class_layers.get(class) {
+                Some((obstacles, zone_cost)) if !classes.is_changed() => changed_region(obstacles, &field.obstacles)
+                    .into_iter()
+                    .chain(changed_region(zone_cost, &field.zone_cost))
+                    .reduce(|a, b| a.union(b))
+                    .map(|region| Some(field.obstacles.clamp_region(region.inflate(avoidance.reach(field.obstacles.cell_dimentions))))),
+                _ => Some(None),
+            };
+
+            if let Some(region) = region {
+                changes.push((Some(class), region));
+            }
+        }
+
+        *previous_class_layers = class_fields.0.iter().map(|field| (field.obstacles.clone(), field.zone_cost.clone())).collect();
+    }
+
+    for &key in &used {
+        let is_new = !destination_fields.0.contains_key(&key) && !in_flight.contains(&key);
+
+        let layers = key.1.filter(|_| has_class_layers(key.1));
+        let region = changes.iter()
+            .filter(|(class, _)| *class == layers)
+            .map(|(_, region)| *region)
+            .chain((is_new || changed.contains(key.0)).then_some(None))
+            .reduce(|a, b| a.zip(b).map(|(a, b)| a.union(b)));
+
+        if let Some(region) = region {
+            pending.entry(key)
+                .and_modify(|pending| *pending = pending.zip(region).map(|(a, b)| a.union(b)))
+                .or_insert(region);
+        }
+    }
+
+    pending.retain(|key, _| used.contains(key));
+
+    if task.is_running() || pending.is_empty() {
+        return;
+    }
+
+    let jobs: Vec<_> = pending.drain()
+        .filter_map(|(key, region)| objectives.get(key.0).ok().map(|(_, transform, shape)| {
+            let repair = region.zip(destination_fields.0.get(&key).cloned());
+            (key, transform.translation.truncate(), shape.clone(), repair)
+        }))
+        .collect();
+
+    let class_layers: HashMap<usize, (f32, GridMap<BlockedStatus>, GridMap<f32>)> = jobs.iter()
+        .filter_map(|((_, class), _, _, _)| *class)
+        .filter_map(|class| classes.0.get(class).zip(class_fields.0.get(class)).map(|(navigation, field)| (class, (navigation.clearance, field.obstacles.clone(), field.zone_cost.clone()))))
+        .collect();
+
+    *in_flight = jobs.iter().map(|(key, _, _, _)| *key).collect();
+
+    let obstacles_map = obstacles_map.clone();
+    let wall_distance = wall_distance.clone();
+    let (clearance, avoidance, mode, sector_size) = (clearance.0, *avoidance, *mode, sectors.map(|graph| graph.sector_size));
+
+    let work = move || jobs.into_iter()
+        .map(|(key, center, shape, repair)| {
+            let field = match (key.1.and_then(|class| class_layers.get(&class)), repair) {
+                (Some((clearance, obstacles, zone_cost)), Some((region, field))) => repair_destination_field(field, obstacles, region, class_cost(zone_cost, &wall_distance, avoidance, *clearance), mode),
+                (Some((clearance, obstacles, zone_cost)), None) => build_destination_field(obstacles, center, &shape, class_cost(zone_cost, &wall_distance, avoidance, *clearance), mode, sector_size),
+                (None, Some((region, field))) => repair_destination_field(field, &obstacles_map, region, wall_cost(&wall_distance, avoidance, clearance), mode),
+                (None, None) => build_destination_field(&obstacles_map, center, &shape, wall_cost(&wall_distance, avoidance, clearance), mode, sector_size),
+            };
+
+            (key, field)
+        })
+        .collect();
+
+    if let Some(fields) = task.start(*execution, work) {
+        destination_fields.0.extend(fields);
+        in_flight.clear();
+    }
+}
+
+/// Bounds of the cells whose value differs between two layers of the same
+/// grid, or the whole grid when their sizes differ.
+fn changed_region<T: PartialEq + Copy>(old: &GridMap<T>, new: &GridMap<T>) -> Option<IRect>{
+
+    if (old.columns, old.rows) != (new.columns, new.rows) {
+        return Some(new.full_region());
+    }
+
+    new.cells()
+        .filter(|&cell| old.get_value_at_cell(cell) != new.get_value_at_cell(cell))
+        .map(|cell| IRect::from_corners(cell, cell + IVec2::ONE))
+        .reduce(|a, b| a.union(b))
+}
+
+fn build_destination_field(obstacles_map: &GridMap<BlockedStatus>, center: Vec2, shape: &Shape, cell_cost: impl Fn(IVec2) -> f32, mode: VectorFieldMode, sector_size: Option<usize>) -> DestinationField{
+
+    let mut targets = obstacles_map.new_like(TargetStatus::NotTarget);
+
+    for cell in cells_in_shape(&targets, center, shape, targets.full_region()) {
+        targets.set_value(cell, TargetStatus::IsTarget).ok();
+    }
+
+    let (proximity, vectors, sectors) = build_field_layers(obstacles_map, &targets, cell_cost, mode, sector_size);
+
+    DestinationField { targets, proximity, vectors, sectors }
+}
+
+/// Brings a destination field up to date after the obstacles inside `region`
+/// changed, the way the shared field is: the proximity map is repaired around
+/// the region, or the sectors touching it are updated and their cell level
+/// fields forgotten.
+fn repair_destination_field(mut field: DestinationField, obstacles_map: &GridMap<BlockedStatus>, region: IRect, cell_cost: impl Fn(IVec2) -> f32, mode: VectorFieldMode) -> DestinationField{
+
+    if let Some(graph) = field.sectors.as_mut() {
+        let stale = update_sectors(graph, obstacles_map, &field.targets, region, cell_cost);
+        clear_sectors(&mut field.proximity, &mut field.vectors, graph, stale);
+
+        return field;
+    }
+
+    field.proximity.take_dirty_region();
+    repair_proximity_map(&mut field.proximity, obstacles_map, &field.targets, region, cell_cost);
+
+    if let Some(region) = field.proximity.take_dirty_region() {
+        let region = field.proximity.clamp_region(region.inflate(1));
+        fill_vector_map(&mut field.vectors, &field.proximity, region, mode);
+    }
+
+    field
+}
+
+pub fn route_choice_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    fixed_time: Res<Time<Fixed>>,
+    settings: Res<RouteChoiceSettings>,
+    destination_fields: Res<DestinationFields>,
+    mut elapsed: Local<f32>,
+    deciding_agents: Query<(Entity, &Transform, &RouteChoice, Option<&Destination>, Option<&AgentClass>, Option<&DesiredSpeed>, Ref<RouteChoice>), (With<Agent>, Without<Itinerary>, Without<Queued>)>,
+    all_agents: Query<&Transform, With<Agent>>,
+    objectives: Query<(Entity, &Transform, &Shape, Option<&Sink>), With<Objective>>,
+){
+
+    *elapsed += time.delta_seconds();
+
+    let evaluate_all = *elapsed >= settings.interval_seconds;
+
+    if evaluate_all {
+        *elapsed = 0.;
+    }
+
+    let service_rates: HashMap<Entity, f32> = objectives.iter()
+        .map(|(entity, _, _, sink)| (entity, sink.map_or(settings.service_rate, Sink::throughput)))
+        .collect();
+
+    let queues: HashMap<Entity, usize> = objectives.iter()
+        .map(|(entity, transform, shape, _)| {
+            let center = transform.translation.truncate();
+
+            let queue = all_agents.iter()
+                .filter(|agent| shape.distance(center, agent.translation.truncate()) <= settings.queue_radius)
+                .count();
+
+            (entity, queue)
+        })
+        .collect();
+
+    for (agent, transform, route_choice, destination, class, desired_speed, route_choice_ref) in &deciding_agents {
+
+        if !evaluate_all && !route_choice_ref.is_added() {
+            continue;
+        }
+
+        let speed_per_second = DesiredSpeed::of(desired_speed).max(f32::EPSILON) / fixed_time.timestep().as_secs_f32();
+
+        let pos = transform.translation.truncate();
+
+        let class = class.map(|class| class.0);
+
+        let routes: Vec<(Entity, f32)> = destination_fields.0.iter()
+            .filter(|((_, field_class), _)| *field_class == class)
+            .filter_map(|(&(entity, _), field)| match field.proximity.get_value_at(pos) {
+                Some(TargetProximity::Computed(distance)) => Some((entity, distance * field.proximity.cell_dimentions.x)),
+                _ => None,
+            })
+            .collect();
+
+        let shortest = routes.iter().copied().min_by(|a, b| a.1.total_cmp(&b.1));
+
+        let choice = match route_choice.0 {
+            RouteChoiceModel::Nearest => shortest,
+            RouteChoiceModel::Familiar { preferred, tolerance } => {
+                let familiar = routes.iter().copied().find(|(entity, _)| *entity == preferred);
+
+                match (familiar, shortest) {
+                    (Some(familiar), Some(shortest)) if familiar.1 <= shortest.1 * tolerance => Some(familiar),
+                    (_, shortest) => shortest,
+                }
+            },
+            RouteChoiceModel::TravelTime => routes.iter().copied()
+                .map(|(entity, distance)| {
+                    let queue = queues.get(&entity).copied().unwrap_or(0) as f32;
+                    (entity, distance / speed_per_second + queue / service_rates.get(&entity).copied().unwrap_or(settings.service_rate))
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1)),
+        };
+
+        let Some((chosen, _)) = choice else {
+            continue;
+        };
+
+        if destination != Some(&Destination(chosen)) {
+            commands.entity(agent).insert(Destination(chosen));
+        }
+    }
+}
+
+/// Labels the connected walkable regions of the map and records which of them
+/// lead to a target.
+///
+/// Regions are made of free cells and target cells sharing an edge. Diagonal
+/// moves of the proximity map need both cells beside them to be free, so they
+/// never join regions that are not already joined through an edge.
+pub fn label_regions(
+    mut labels: ResMut<GridMap<RegionLabel>>,
+    mut reachable: ResMut<ReachableRegions>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    target_map: Res<GridMap<TargetStatus>>,
+    objectives: Query<(Entity, &Transform, &Shape), With<Objective>>,
+    changed_objectives: Query<(), (With<Objective>, Changed<Transform>)>,
+){
+
+    if !obstacles_map.is_changed() && !target_map.is_changed() && changed_objectives.is_empty() {
+        return;
+    }
+
+    *labels = find_regions(&obstacles_map, &target_map);
+
+    let labels_in = |cells: &mut dyn Iterator<Item = IVec2>| -> HashSet<u32> {
+        cells.filter_map(|cell| labels.get_value_at_cell(cell)?.0).collect()
+    };
+
+    reachable.any_target = labels_in(&mut target_map.chunk_regions().into_iter()
+        .flat_map(|region| target_map.cells_in_region(region))
+        .filter(|&cell| target_map.get_value_at_cell(cell) == Some(TargetStatus::IsTarget)));
+
+    reachable.per_objective = objectives.iter()
+        .map(|(entity, transform, shape)| {
+            let cells = cells_in_shape(&labels, transform.translation.truncate(), shape, labels.full_region());
+            (entity, labels_in(&mut cells.into_iter()))
+        })
+        .collect();
+}
+
+/// Part of the map labelled as a whole by [`find_regions`].
+#[derive(Clone, Copy)]
+enum RegionNode{
+    /// Chunk that neither the obstacle nor the target map stores: all of its
+    /// cells are free and joined.
+    Chunk(IVec2),
+    Cell(IVec2),
+}
+
+/// Region of every passable cell of the maps.
+///
+/// The chunks of a chunked map that hold no obstacle or target are flooded as
+/// single nodes. The cells of the largest region among them are left to the
+/// default value of the labels, so labelling a mostly empty map only
+/// allocates the chunks around its obstacles and targets.
+fn find_regions(obstacles_map: &GridMap<BlockedStatus>, target_map: &GridMap<TargetStatus>) -> GridMap<RegionLabel>{
+
+    let is_passable = |cell: IVec2| obstacles_map.get_value_at_cell(cell) == Some(BlockedStatus::Empty)
+        || target_map.get_value_at_cell(cell) == Some(TargetStatus::IsTarget)
+            && obstacles_map.get_value_at_cell(cell) != Some(BlockedStatus::Blocked);
+
+    let chunk_size = obstacles_map.chunk_size().filter(|&size| target_map.chunk_size() == Some(size)).map(|size| size as i32);
+
+    let node_of = |cell: IVec2| match chunk_size {
+        Some(size) if !obstacles_map.is_allocated(cell) && !target_map.is_allocated(cell) => RegionNode::Chunk(cell.div_euclid(IVec2::splat(size))),
+        _ => RegionNode::Cell(cell),
+    };
+
+    let chunk_region = |chunk: IVec2| {
+        let size = chunk_size.unwrap_or(1);
+        obstacles_map.clamp_region(IRect::from_corners(chunk * size, (chunk + 1) * size))
+    };
+
+    let neighbours = |node: RegionNode| -> Vec<RegionNode> {
+        let cells: Vec<IVec2> = match node {
+            RegionNode::Cell(cell) => obstacles_map.neighbours_4(cell).collect(),
+            RegionNode::Chunk(chunk) => {
+                let region = chunk_region(chunk);
+
+                (region.min.x..region.max.x).flat_map(|x| [IVec2::new(x, region.min.y - 1), IVec2::new(x, region.max.y)])
+                    .chain((region.min.y..region.max.y).flat_map(|y| [IVec2::new(region.min.x - 1, y), IVec2::new(region.max.x, y)]))
+                    .filter(|&cell| obstacles_map.get_value_at_cell(cell).is_some())
+                    .collect()
+            },
+        };
+
+        cells.into_iter().filter(|&cell| is_passable(cell)).map(node_of).collect()
+    };
+
+    let mut touched = obstacles_map.chunk_regions();
+    touched.extend(target_map.chunk_regions());
+    touched.sort_by_key(|region| (region.min.x, region.min.y, region.max.x, region.max.y));
+    touched.dedup();
+
+    let free_chunks: Vec<IVec2> = match chunk_size {
+        Some(size) => (0..obstacles_map.columns.div_ceil(size as usize) as i32)
+            .flat_map(|x| (0..obstacles_map.rows.div_ceil(size as usize) as i32).map(move |y| IVec2::new(x, y)))
+            .filter(|&chunk| matches!(node_of(chunk * size), RegionNode::Chunk(_)))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let mut cell_labels = obstacles_map.new_like(RegionLabel(None));
+    let mut chunk_labels: HashMap<IVec2, u32> = HashMap::new();
+    let mut next_label = 0;
+
+    let starts = free_chunks.iter().map(|&chunk| RegionNode::Chunk(chunk))
+        .chain(touched.iter().flat_map(|&region| obstacles_map.cells_in_region(region)).filter(|&cell| is_passable(cell)).map(RegionNode::Cell));
+
+    for start in starts {
+
+        // Gives `node` the current label, unless it already has one.
+        let mut label = |node: RegionNode| -> bool {
+            match node {
+                RegionNode::Chunk(chunk) if chunk_labels.contains_key(&chunk) => false,
+                RegionNode::Chunk(chunk) => chunk_labels.insert(chunk, next_label).is_none(),
+                RegionNode::Cell(cell) => cell_labels.get_value_at_cell(cell) == Some(RegionLabel(None))
+                    && cell_labels.set_value(cell, RegionLabel(Some(next_label))).is_ok(),
+            }
+        };
+
+        if !label(start) {
+            continue;
+        }
+
+        let mut open_list = VecDeque::from([start]);
+
+        while let Some(pivot) = open_list.pop_front() {
+            for neighbour in neighbours(pivot) {
+                if label(neighbour) {
+                    open_list.push_back(neighbour);
+                }
+            }
+        }
+
+        next_label += 1;
+    }
+
+    let mut chunk_cells: HashMap<u32, i32> = HashMap::new();
+
+    for (&chunk, &label) in &chunk_labels {
+        let region = chunk_region(chunk);
+        *chunk_cells.entry(label).or_default() += region.width() * region.height();
+    }
+
+    let largest = chunk_cells.into_iter().max_by_key(|&(label, cells)| (cells, std::cmp::Reverse(label))).map(|(label, _)| label);
+    let mut labels = obstacles_map.new_like(RegionLabel(largest));
+
+    for cell in touched.iter().flat_map(|&region| obstacles_map.cells_in_region(region)) {
+        let label = cell_labels.get_value_at_cell(cell).unwrap_or_default();
+
+        if label != RegionLabel(largest) {
+            labels.set_value(cell, label).ok();
+        }
+    }
+
+    for (chunk, label) in chunk_labels {
+        if Some(label) != largest {
+            for cell in obstacles_map.cells_in_region(chunk_region(chunk)) {
+                labels.set_value(cell, RegionLabel(Some(label))).ok();
+            }
+        }
+    }
+
+    labels
+}
+
+/// Cells around an agent searched for a region when the agent stands on a
+/// cell without one, e.g. brushing past a wall.
+const REGION_SEARCH_RADIUS: i32 = 3;
+
+fn region_at(labels: &GridMap<RegionLabel>, pos: Vec2) -> Option<u32>{
+
+    let cell = labels.get_cell(pos)?;
+
+    (0..=REGION_SEARCH_RADIUS).find_map(|radius| {
+        labels.cells_in_region(IRect::from_corners(cell - radius, cell + radius + 1))
+            .find_map(|cell| labels.get_value_at_cell(cell)?.0)
+    })
+}
+
+/// Whether an agent at `pos` can walk to the `destination` objective, or to
+/// any objective when it has none.
+pub fn can_reach_destination(labels: &GridMap<RegionLabel>, reachable: &ReachableRegions, pos: Vec2, destination: Option<Entity>) -> bool{
+
+    let Some(region) = region_at(labels, pos) else {
+        return false;
+    };
+
+    match destination {
+        Some(destination) => reachable.per_objective.get(&destination).is_some_and(|regions| regions.contains(&region)),
+        None => reachable.any_target.contains(&region),
+    }
+}
+
+pub fn report_unreachable_agents(
+    labels: Res<GridMap<RegionLabel>>,
+    reachable: Res<ReachableRegions>,
+    agents: Query<(Entity, &Transform, Option<&Destination>), With<Agent>>,
+){
+
+    let unreachable: Vec<_> = agents.iter()
+        .filter(|(_, transform, destination)| !can_reach_destination(&labels, &reachable, transform.translation.truncate(), destination.map(|destination| destination.0)))
+        .collect();
+
+    if unreachable.is_empty() {
+        info!("All {} agents can reach their destination", agents.iter().len());
+        return;
+    }
+
+    warn!("{} of {} agents cannot reach their destination:", unreachable.len(), agents.iter().len());
+
+    for (agent, transform, destination) in unreachable {
+        match destination {
+            Some(destination) => warn!("  {:?} at {} -> objective {:?}", agent, transform.translation.truncate(), destination.0),
+            None => warn!("  {:?} at {} -> any objective", agent, transform.translation.truncate()),
+        }
+    }
+}
+
+pub fn detect_trapped_agents(
+    mut commands: Commands,
+    labels: Res<GridMap<RegionLabel>>,
+    reachable: Res<ReachableRegions>,
+    agents: Query<(Entity, &Transform, Option<&Destination>, Has<Trapped>), With<Agent>>,
+    mut trapped_events: EventWriter<AgentTrapped>,
+){
+
+    for (agent, transform, destination, was_trapped) in &agents {
+        let position = transform.translation.truncate();
+        let trapped = !can_reach_destination(&labels, &reachable, position, destination.map(|destination| destination.0));
+
+        match (trapped, was_trapped) {
+            (true, false) => {
+                commands.entity(agent).insert(Trapped);
+                trapped_events.send(AgentTrapped { agent, position });
+            },
+            (false, true) => {
+                commands.entity(agent).remove::<Trapped>();
+            },
+            _ => {},
+        }
+    }
+}
+
+pub fn log_trapped_agents(mut trapped_events: EventReader<AgentTrapped>){
+    for event in trapped_events.read() {
+        warn!("Agent {:?} is trapped at {} with no path to its destination", event.agent, event.position);
+    }
+}
+
+/// Two basins meeting at a saddle narrower than this fraction of the
+/// shallower basin's depth are kept as separate rooms joined by a door.
+const DOOR_DEPTH_RATIO: f32 = 0.7;
+
+/// Splits the walkable space in rooms and doors.
+///
+/// Each cell's distance to the closest wall (or edge of the area) is treated
+/// as a height map and flooded from the highest cells down (watershed). Every
+/// local maximum starts a basin. When two basins meet, the height of the
+/// meeting cell is the widest point between them: if it is close to the depth
+/// of the shallower basin they are one room, otherwise the meeting point is a
+/// door between two rooms.
+///
+/// Rooms only change with the walls, so they are segmented again when the
+/// wall distances change.
+pub fn segment_rooms(
+    mut room_graph: ResMut<RoomGraph>,
+    mut room_labels: ResMut<GridMap<RoomLabel>>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    wall_distance: Res<GridMap<WallDistance>>,
+    execution: Res<FlowFieldExecution>,
+    mut pending: Local<bool>,
+    mut task: Local<BackgroundTask<(RoomGraph, GridMap<RoomLabel>)>>,
+){
+
+    if let Some(rooms) = task.poll() {
+        (*room_graph, *room_labels) = rooms;
+    }
+
+    *pending |= wall_distance.is_changed();
+
+    if task.is_running() || !std::mem::take(&mut *pending) {
+        return;
+    }
+
+    let obstacles = obstacles_map.clone();
+
+    if let Some(rooms) = task.start(*execution, move || find_rooms(&obstacles)) {
+        (*room_graph, *room_labels) = rooms;
+    }
+}
+
+/// Rooms and doors of the cells of `obstacles_map` that are not blocked, with
+/// the room of each cell. Cells of the largest room are left to the default
+/// value of the labels.
+fn find_rooms(obstacles_map: &GridMap<BlockedStatus>) -> (RoomGraph, GridMap<RoomLabel>){
+
+    let area = obstacles_map.area;
+
+    // The wall distance layer stops at the distances flow fields look at, the
+    // middle of a room may be farther.
+    let full_region = obstacles_map.full_region();
+    let wall_distances = window_distances(obstacles_map, full_region, f32::INFINITY);
+
+    let height = |cell: IVec2| -> Option<f32> {
+        if obstacles_map.get_value_at_cell(cell)? == BlockedStatus::Blocked {
+            return None;
+        }
+
+        let pos = obstacles_map.get_coord(cell);
+        let edge_distance = (pos - area.min).min(area.max - pos).min_element();
+
+        Some(wall_distances[(cell.x + cell.y * full_region.width()) as usize].min(edge_distance))
+    };
+
+    let mut cells: Vec<(IVec2, f32)> = obstacles_map.cells()
+        .filter_map(|cell| Some((cell, height(cell)?)))
+        .collect();
+
+    cells.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut basin_of = obstacles_map.new_like(None::<usize>);
+    let mut parent: Vec<usize> = Vec::new();
+    let mut depth: Vec<f32> = Vec::new();
+    let mut saddles: HashMap<(usize, usize), (f32, IVec2)> = HashMap::new();
+
+    fn find(parent: &mut [usize], basin: usize) -> usize {
+        let mut root = basin;
+        while parent[root] != root {
+            parent[root] = parent[parent[root]];
+            root = parent[root];
+        }
+        root
+    }
+
+    for &(cell, cell_height) in &cells {
+
+        let mut roots: Vec<usize> = Vec::new();
+
+        for delta in NEIGHBOURS_8{
+            if let Some(Some(basin)) = basin_of.get_value_at_cell(cell + delta) {
+                let root = find(&mut parent, basin);
+
+                if !roots.contains(&root) {
+                    roots.push(root);
+                }
+            }
+        }
+
+        let Some(&first) = roots.first() else {
+            basin_of.set_value(cell, Some(parent.len())).ok();
+            parent.push(parent.len());
+            depth.push(cell_height);
+            continue;
+        };
+
+        basin_of.set_value(cell, Some(first)).ok();
+
+        for &other in &roots[1..] {
+            let (a, b) = (find(&mut parent, first), find(&mut parent, other));
+
+            if a == b {
+                continue;
+            }
+
+            if cell_height >= DOOR_DEPTH_RATIO * depth[a].min(depth[b]) {
+                parent[b] = a;
+                depth[a] = depth[a].max(depth[b]);
+            } else {
+                saddles.entry((a.min(b), a.max(b))).or_insert((cell_height, cell));
+            }
+        }
+    }
+
+    let mut room_of_root: HashMap<usize, usize> = HashMap::new();
+    let mut rooms: Vec<Room> = Vec::new();
+    let cell_area = obstacles_map.cell_dimentions.x * obstacles_map.cell_dimentions.y;
+
+    let mut room_of = |cell: IVec2| {
+        let root = find(&mut parent, basin_of.get_value_at_cell(cell).flatten()?);
+
+        Some(*room_of_root.entry(root).or_insert_with(|| {
+            rooms.push(Room { area: 0., center: Vec2::ZERO, depth: depth[root] });
+            rooms.len() - 1
+        }))
+    };
+
+    let cell_rooms: Vec<(IVec2, usize)> = cells.iter().filter_map(|&(cell, _)| Some((cell, room_of(cell)?))).collect();
+
+    for &(cell, room) in &cell_rooms {
+        rooms[room].area += cell_area;
+        rooms[room].center += obstacles_map.get_coord(cell) * cell_area;
+    }
+
+    for room in &mut rooms {
+        room.center /= room.area;
+    }
+
+    let largest = (0..rooms.len()).max_by(|&a, &b| rooms[a].area.total_cmp(&rooms[b].area));
+    let mut room_labels = obstacles_map.new_like(RoomLabel(largest));
+
+    for &(cell, room) in &cell_rooms {
+        if Some(room) != largest {
+            room_labels.set_value(cell, RoomLabel(Some(room))).ok();
+        }
+    }
+
+    for cell in obstacles_map.chunk_regions().into_iter().flat_map(|region| obstacles_map.cells_in_region(region)) {
+        if obstacles_map.get_value_at_cell(cell) == Some(BlockedStatus::Blocked) {
+            room_labels.set_value(cell, RoomLabel(None)).ok();
+        }
+    }
+
+    let mut doors: HashMap<(usize, usize), Door> = HashMap::new();
+
+    for ((a, b), (saddle_height, cell)) in saddles {
+        let (a, b) = (room_of_root[&find(&mut parent, a)], room_of_root[&find(&mut parent, b)]);
+
+        if a == b {
+            continue;
+        }
+
+        let door = Door { rooms: (a.min(b), a.max(b)), position: obstacles_map.get_coord(cell), width: 2. * saddle_height };
+
+        match doors.get(&door.rooms) {
+            Some(existing) if existing.width >= door.width => {},
+            _ => {
+                doors.insert(door.rooms, door);
+            }
+        }
+    }
+
+    (RoomGraph { rooms, doors: doors.into_values().collect() }, room_labels)
+}
+
+pub fn apply_vector_map(
+    vector_field: ResMut<GridMap<Vec2>>,
+    proximity_map: Res<GridMap<TargetProximity>>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    class_fields: Res<ClassFields>,
+    destination_fields: Res<DestinationFields>,
+    sampling: Res<VectorFieldSampling>,
+    look_ahead: Res<LookAhead>,
+    objectives: Query<(&Transform, Option<&Follow>), With<Objective>>,
+    followed: Query<&Transform, Without<Objective>>,
+    mut agents: Query<(&mut MotivationForce, &Transform, &Speed, Option<&DesiredSpeed>, Option<&AgentClass>, Option<&Destination>), With<Agent>>,
+){
+    
+    for (mut motivation_force, transform, agent_speed, desired_speed, class, destination) in &mut agents {
+
+        let pos = transform.translation.truncate();
+
+        let destination_field = destination.and_then(|destination| destination_fields.0.get(&(destination.0, class.map(|class| class.0))));
+        let class_field = class.and_then(|class| class_fields.0.get(class.0));
+
+        let (vector_field, proximity_map, obstacles_map) = match (destination_field, class_field) {
+            (Some(field), Some(class_field)) => (&field.vectors, &field.proximity, &class_field.obstacles),
+            (Some(field), None) => (&field.vectors, &field.proximity, &*obstacles_map),
+            (None, Some(field)) => (&field.vectors, &field.proximity, &field.obstacles),
+            (None, None) => (&*vector_field, &*proximity_map, &*obstacles_map),
+        };
+
+        let is_walkable = |cell: IVec2| matches!(obstacles_map.get_value_at_cell(cell), Some(BlockedStatus::Empty | BlockedStatus::Clearance));
+
+        // Moving objectives only update the fields now and then, so agents
+        // that see theirs walk straight to it. Agents on a field shared by
+        // every objective do so for the nearest moving one, unless the field
+        // knows a shorter way to another objective.
+        let direct_target = match destination {
+            Some(destination) => destination_position(destination.0, &objectives, &followed),
+            None => nearest_moving_objective(pos, &objectives, &followed).filter(|&target| {
+                match proximity_map.get_value_at(pos) {
+                    Some(TargetProximity::Computed(distance)) => pos.distance(target) <= (distance + 1.) * proximity_map.cell_dimentions.x,
+                    _ => true,
+                }
+            }),
+        };
+
+        let direct_target = direct_target
+            .filter(|&target| match (obstacles_map.get_cell(pos), obstacles_map.get_cell(target)) {
+                (Some(from), Some(to)) => obstacles_map.has_line_of_sight(from, to, |status| status == BlockedStatus::Empty),
+                _ => false,
+            });
+
+        let visible_target = direct_target.or_else(|| obstacles_map.get_cell(pos)
+            .and_then(|cell| look_ahead_target(proximity_map, obstacles_map, cell, look_ahead.max_cells))
+            .map(|cell| obstacles_map.get_coord(cell)));
+
+        let sample = match (visible_target, *sampling) {
+            (Some(target), _) => Some((target - pos).normalize_or_zero() * AGENT_DESIRED_SPEED),
+            (None, VectorFieldSampling::Nearest) => vector_field.get_value_at(pos),
+            (None, VectorFieldSampling::Bilinear) => vector_field.get_interpolated_value_at(pos, is_walkable)
+                .or_else(|| vector_field.get_value_at(pos)),
+        };
+
+        // Fields are built for the default speed.
+        let base_vector = match sample{
+            Some(value) => value * DesiredSpeed::field_scale(desired_speed),
+            None => continue,
+        };
+
+        if base_vector.is_nan(){
+            continue;
+        }
+        
+        let final_force = base_vector - agent_speed.0;
+
+        motivation_force.0 = final_force;
+    }
+}
+
+/// Current position of an objective. Objectives that follow another entity
+/// report that entity's live position rather than their last field update.
+fn destination_position(
+    objective: Entity,
+    objectives: &Query<(&Transform, Option<&Follow>), With<Objective>>,
+    followed: &Query<&Transform, Without<Objective>>,
+) -> Option<Vec2>{
+
+    let (transform, follow) = objectives.get(objective).ok()?;
+
+    match follow.and_then(|follow| Some(followed.get(follow.entity).ok()?.translation.truncate() + follow.offset)) {
+        Some(position) => Some(position),
+        None => Some(transform.translation.truncate()),
+    }
+}
+
+/// Live position of the objective following another entity closest to
+/// `pos`, in a straight line.
+fn nearest_moving_objective(
+    pos: Vec2,
+    objectives: &Query<(&Transform, Option<&Follow>), With<Objective>>,
+    followed: &Query<&Transform, Without<Objective>>,
+) -> Option<Vec2>{
+
+    objectives.iter()
+        .filter_map(|(transform, follow)| {
+            let follow = follow?;
+
+            Some(match followed.get(follow.entity) {
+                Ok(followed) => followed.translation.truncate() + follow.offset,
+                Err(_) => transform.translation.truncate(),
+            })
+        })
+        .min_by(|a, b| a.distance_squared(pos).total_cmp(&b.distance_squared(pos)))
+}
+
+/// Farthest cell, at most `max_cells` steps down the proximity map from
+/// `start`, that can be seen from `start` without crossing obstacles or their
+/// clearance. Returns `None` when the next step is already out of sight.
+fn look_ahead_target(proximity_map: &GridMap<TargetProximity>, obstacles_map: &GridMap<BlockedStatus>, start: IVec2, max_cells: usize) -> Option<IVec2>{
+
+    let is_clear = |status: BlockedStatus| status == BlockedStatus::Empty;
+    let distance_at = |cell: IVec2| match proximity_map.get_value_at_cell(cell) {
+        Some(TargetProximity::Computed(value)) => Some(value),
+        _ => None,
+    };
+
+    let mut current = start;
+    let mut current_distance = distance_at(start)?;
+    let mut visible = None;
+
+    for _ in 0..max_cells {
+
+        let mut next = None;
+
+        for delta in NEIGHBOURS_8{
+            let cell = current + delta;
+
+            if let Some(distance) = distance_at(cell) {
+                if distance < current_distance - PROXIMITY_EPSILON {
+                    current_distance = distance;
+                    next = Some(cell);
+                }
+            }
+        }
+
+        let Some(next) = next else {
+            break;
+        };
+
+        if !obstacles_map.has_line_of_sight(start, next, is_clear) {
+            break;
+        }
+
+        visible = Some(next);
+        current = next;
+    }
+
+    visible
+}
+
+
+pub fn handle_grid_state_inputs(grid_state: Res<State<ShowGridState>>, mut nex_grid_state: ResMut<NextState<ShowGridState>>, keys: Res<ButtonInput<KeyCode>>) {
+    
+    if keys.just_pressed(KeyCode::KeyG) {
+        match grid_state.get() {
+            ShowGridState::HideGrid => nex_grid_state.set(ShowGridState::ShowGrid),
+            ShowGridState::ShowGrid => nex_grid_state.set(ShowGridState::HideGrid),
+        }
+    }
+}
+
+pub fn handle_overlay_inputs(state: Res<State<PathFindingOverlayState>>, mut next_state: ResMut<NextState<PathFindingOverlayState>>, keys: Res<ButtonInput<KeyCode>>) {
+    
+    let mut next = Option::None;
+
+    if keys.just_pressed(KeyCode::KeyO) {
+        next = Some(PathFindingOverlayState::ShowObstacles);
+    }
+
+    if keys.just_pressed(KeyCode::KeyP) {
+        next = Some(PathFindingOverlayState::ShowProimity);
+    }
+
+    if keys.just_pressed(KeyCode::KeyT) {
+        next = Some(PathFindingOverlayState::ShowTargets);
+    }
+
+    if keys.just_pressed(KeyCode::KeyV) {
+        next = Some(PathFindingOverlayState::ShowVectorField);
+    }
+
+    if keys.just_pressed(KeyCode::KeyR) {
+        next = Some(PathFindingOverlayState::ShowRooms);
+    }
+
+    if let Some(new_value) = next{
+        if new_value == *state.get(){
+            next_state.set(PathFindingOverlayState::ShowNone);
+        } else {
+            next_state.set(new_value);
+        }
+    }
+}
+
+
+/// Look-ahead distance switched on by the `L` key.
+const DEFAULT_LOOK_AHEAD_CELLS: usize = 8;
+
+pub fn handle_vector_field_inputs(mut sampling: ResMut<VectorFieldSampling>, mut mode: ResMut<VectorFieldMode>, mut look_ahead: ResMut<LookAhead>, keys: Res<ButtonInput<KeyCode>>) {
+    
+    if keys.just_pressed(KeyCode::KeyI) {
+        *sampling = match *sampling {
+            VectorFieldSampling::Nearest => VectorFieldSampling::Bilinear,
+            VectorFieldSampling::Bilinear => VectorFieldSampling::Nearest,
+        };
+    }
+
+    if keys.just_pressed(KeyCode::KeyL) {
+        look_ahead.max_cells = match look_ahead.max_cells {
+            0 => DEFAULT_LOOK_AHEAD_CELLS,
+            _ => 0,
+        };
+    }
+
+    if keys.just_pressed(KeyCode::KeyF) {
+        *mode = match *mode {
+            VectorFieldMode::Weighted => VectorFieldMode::Gradient,
+            VectorFieldMode::Gradient => VectorFieldMode::Weighted,
+        };
+    }
+}
+
+
+pub fn handle_overlay_class_inputs(mut overlay_class: ResMut<OverlayClass>, classes: Res<NavigationClasses>, keys: Res<ButtonInput<KeyCode>>) {
+    
+    if keys.just_pressed(KeyCode::KeyC) {
+        overlay_class.0 = match overlay_class.0 {
+            None if !classes.0.is_empty() => Some(0),
+            Some(i) if i + 1 < classes.0.len() => Some(i + 1),
+            _ => None,
+        };
+
+        match overlay_class.0 {
+            Some(i) => info!("Overlays show navigation class {}", classes.0[i].name),
+            None => info!("Overlays show the shared navigation layers"),
+        }
+    }
+}
+
+/// Directory the layers are written to when pressing E.
+const EXPORT_DIRECTORY: &str = "exports";
+
+pub fn handle_export_inputs(
+    keys: Res<ButtonInput<KeyCode>>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    target_map: Res<GridMap<TargetStatus>>,
+    proximity_map: Res<GridMap<TargetProximity>>,
+    vector_field: Res<GridMap<Vec2>>,
+    room_labels: Res<GridMap<RoomLabel>>,
+){
+
+    if !keys.just_pressed(KeyCode::KeyE) {
+        return;
+    }
+
+    let directory = Path::new(EXPORT_DIRECTORY);
+
+    let max_proximity = (0..proximity_map.columns * proximity_map.rows)
+        .filter_map(|i| match proximity_map.get_value_by_index(i) {
+            Some(TargetProximity::Computed(value)) => Some(value),
+            _ => None,
+        })
+        .fold(0_f32, f32::max)
+        .max(1.);
+
+    let results = [
+        export_layer(&obstacles_map, &directory.join("obstacles.png"), |status| match status {
+            BlockedStatus::Empty => Color::WHITE,
+            BlockedStatus::Blocked => Color::BLACK,
+            BlockedStatus::Clearance => Color::from(ORANGE_500),
+        }),
+        export_layer(&target_map, &directory.join("targets.png"), |status| match status {
+            TargetStatus::IsTarget => Color::from(GREEN_500),
+            TargetStatus::NotTarget => Color::WHITE,
+        }),
+        export_layer(&proximity_map, &directory.join("proximity.png"), |proximity| match proximity {
+            TargetProximity::Computed(value) => Color::srgb(1. - value / max_proximity, 1. - value / max_proximity, 1. - value / max_proximity),
+            TargetProximity::Unreachable => Color::BLACK,
+            TargetProximity::NotComputed => Color::from(RED_500),
+        }),
+        export_layer(&vector_field, &directory.join("vectors.png"), |vector| {
+            let direction = vector.normalize_or_zero() * 0.5 + 0.5;
+            Color::srgb(direction.x, direction.y, 0.5)
+        }),
+        export_layer(&room_labels, &directory.join("rooms.png"), |RoomLabel(room)| room.map_or(Color::BLACK, room_color)),
+    ];
+
+    for result in results {
+        if let Err(error) = result {
+            warn!("Could not export layer: {error:#}");
+        }
+    }
+
+    info!("Exported the navigation layers to {}", directory.display());
+}
+
+fn room_color(room: usize) -> Color{
+    Color::from(RED_500).rotate_hue(room as f32 * 47.)
+}
+
+pub fn draw_grid(mut gizmos: Gizmos, map: Res<GridMap<BlockedStatus>>){
     
+    gizmos
+        .grid_2d(
+            map.area.center(),
+            0.,
+            UVec2::new(map.columns as u32, map.rows as u32),
+            map.cell_dimentions,
+            LinearRgba::gray(0.05),
+        )
+        .outer_edges();
+}
+
+pub fn draw_obstacles(mut gizmos: Gizmos, map: Res<GridMap<BlockedStatus>>, class_fields: Res<ClassFields>, overlay_class: Res<OverlayClass>){
+
+    let map = match overlay_class.0.and_then(|i| class_fields.0.get(i)) {
+        Some(field) => &field.obstacles,
+        None => &*map,
+    };
+
+    for x in 0..map.columns {
+        for y in 0..map.rows {
+            
+            let color = match map.get_value_at_cell(IVec2::new(x as i32, y as i32)) {
+                None | Some(BlockedStatus::Empty) => continue,
+                Some(BlockedStatus::Blocked) => Color::from(RED_500),
+                Some(BlockedStatus::Clearance) => Color::from(ORANGE_500),
+            };
+
+        
+            let cell_top_left = map.get_cell_rect(IVec2::new(x as i32, y as i32)).min;
+
+            gizmos.line_2d(cell_top_left, cell_top_left + map.cell_dimentions, color);
+            gizmos.line_2d(cell_top_left + map.cell_dimentions.with_x(0.), cell_top_left + map.cell_dimentions.with_y(0.), color);
+        }
+    }
+
+}
+
+pub fn draw_targets(mut gizmos: Gizmos, map: Res<GridMap<TargetStatus>>){
+
+    let color = Color::from(GREEN_500);
+
+    for x in 0..map.columns {
+        for y in 0..map.rows {
+            
+            if let Some(TargetStatus::NotTarget) = map.get_value_at_cell(IVec2::new(x as i32, y as i32)){
+                continue;
+            }
+
+        
+            let cell_top_left = map.get_cell_rect(IVec2::new(x as i32, y as i32)).min;
+
+            gizmos.line_2d(cell_top_left, cell_top_left + map.cell_dimentions, color);
+            gizmos.line_2d(cell_top_left + map.cell_dimentions.with_x(0.), cell_top_left + map.cell_dimentions.with_y(0.), color);
+        }
+    }
+}
+
+pub fn draw_proximity(mut gizmos: Gizmos, map: Res<GridMap<TargetProximity>>, class_fields: Res<ClassFields>, overlay_class: Res<OverlayClass>){
+
+    let map = match overlay_class.0.and_then(|i| class_fields.0.get(i)) {
+        Some(field) => &field.proximity,
+        None => &*map,
+    };
+
+    for x in 0..map.columns {
+        for y in 0..map.rows {
+            
+            if let Some(TargetProximity::Unreachable) = map.get_value_at_cell(IVec2::new(x as i32, y as i32)){
+                continue;
+            }
+
+            let color = match map.get_value_at_cell(IVec2::new(x as i32, y as i32)) {
+                None | Some(TargetProximity::Unreachable) => continue,
+                Some(TargetProximity::NotComputed) => Color::from(PURPLE_500),
+                Some(TargetProximity::Computed(value)) => Color::from(GREEN_500).with_alpha(1./(value + 1.)),
+            };
+        
+            let cell_top_left = map.get_cell_rect(IVec2::new(x as i32, y as i32)).min;
+
+            gizmos.line_2d(cell_top_left, cell_top_left + map.cell_dimentions, color);
+            gizmos.line_2d(cell_top_left + map.cell_dimentions.with_x(0.), cell_top_left + map.cell_dimentions.with_y(0.), color);
+        }
+    }
+
+}
+
+pub fn draw_vectors(mut gizmos: Gizmos, map: Res<GridMap<Vec2>>, class_fields: Res<ClassFields>, overlay_class: Res<OverlayClass>){
+
+    let map = match overlay_class.0.and_then(|i| class_fields.0.get(i)) {
+        Some(field) => &field.vectors,
+        None => &*map,
+    };
 
     for x in 0..map.columns {
         for y in 0..map.rows {
             
             if let Some(value) = map.get_value_at_cell(IVec2::new(x as i32,  y as i32)){
                 
-                let cell_top_left = map.area.center() + (Vec2::new(x as f32,  y as f32) - global_offset) * map.cell_dimentions;
-                let cell_center = cell_top_left + Vec2::new(0.5, 0.5) * map.cell_dimentions;
+                let cell_center = map.get_coord(IVec2::new(x as i32, y as i32));
                 gizmos.arrow_2d(cell_center, cell_center + value * AGENT_MASS / 10., PURPLE_500);
 
             }
@@ -373,4 +2522,504 @@ pub fn draw_vectors(mut gizmos: Gizmos, map: Res<GridMap<Vec2>>){
         }
     }
 
-}
\ No newline at end of file
+}
+
+pub fn draw_rooms(mut gizmos: Gizmos, map: Res<GridMap<RoomLabel>>, room_graph: Res<RoomGraph>){
+
+    for x in 0..map.columns {
+        for y in 0..map.rows {
+            
+            let Some(RoomLabel(Some(room))) = map.get_value_at_cell(IVec2::new(x as i32, y as i32)) else {
+                continue;
+            };
+
+            let color = room_color(room);
+        
+            let cell_top_left = map.get_cell_rect(IVec2::new(x as i32, y as i32)).min;
+
+            gizmos.line_2d(cell_top_left, cell_top_left + map.cell_dimentions, color);
+            gizmos.line_2d(cell_top_left + map.cell_dimentions.with_x(0.), cell_top_left + map.cell_dimentions.with_y(0.), color);
+        }
+    }
+
+    for room in &room_graph.rooms {
+        gizmos.circle_2d(room.center, room.depth, Color::BLACK);
+    }
+
+    for door in &room_graph.doors {
+        gizmos.circle_2d(door.position, door.width / 2., Color::WHITE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 5 x 5 map of unit cells where every cell is reached, at its octile
+    /// distance from a target in the middle.
+    fn proximity_map() -> GridMap<TargetProximity> {
+
+        let mut map = GridMap::new(5, 5, Rect::new(0., 0., 5., 5.), TargetProximity::NotComputed);
+        let target = IVec2::new(2, 2);
+
+        for cell in map.cells().collect::<Vec<_>>() {
+            let delta = (cell - target).abs();
+            let distance = delta.max_element().abs_diff(delta.min_element()) as f32 + delta.min_element() as f32 * 2_f32.sqrt();
+            map.set_value(cell, TargetProximity::Computed(distance)).unwrap();
+        }
+
+        map
+    }
+
+    fn assert_finite(map: &GridMap<TargetProximity>) {
+        for mode in [VectorFieldMode::Gradient, VectorFieldMode::Weighted] {
+            let mut vectors = map.new_like(Vec2::ONE);
+            fill_vector_map(&mut vectors, map, map.full_region(), mode);
+
+            for cell in map.cells() {
+                let vector = vectors.get_value_at_cell(cell).unwrap();
+                assert!(vector.is_finite(), "{mode:?} vector {vector} at {cell}");
+            }
+        }
+    }
+
+    #[test]
+    fn vectors_lead_to_the_target() {
+
+        let map = proximity_map();
+        assert_finite(&map);
+
+        let target = IVec2::new(2, 2);
+
+        for cell in map.cells().filter(|cell| *cell != target) {
+            let towards = (target - cell).as_vec2().normalize();
+
+            assert!(gradient_vector(&map, cell).normalize().dot(towards) > 0.95, "gradient at {cell}");
+            assert!(weighted_vector(&map, cell).normalize().dot(towards) > 0., "weighted at {cell}");
+        }
+    }
+
+    #[test]
+    fn targets_fall_back() {
+
+        let map = proximity_map();
+
+        assert_eq!(gradient_vector(&map, IVec2::new(2, 2)), FALLBACK_VECTOR);
+        assert_eq!(weighted_vector(&map, IVec2::new(2, 2)), FALLBACK_VECTOR);
+    }
+
+    #[test]
+    fn plateaus_fall_back() {
+
+        let mut map = proximity_map();
+        map.reset(TargetProximity::Computed(3.));
+        assert_finite(&map);
+
+        for cell in map.cells() {
+            assert_eq!(gradient_vector(&map, cell), FALLBACK_VECTOR);
+        }
+    }
+
+    #[test]
+    fn unreached_cells_fall_back() {
+
+        let mut map = proximity_map();
+        map.set_value(IVec2::new(0, 0), TargetProximity::NotComputed).unwrap();
+        map.set_value(IVec2::new(4, 2), TargetProximity::NotComputed).unwrap();
+        assert_finite(&map);
+
+        for cell in [IVec2::new(0, 0), IVec2::new(4, 2)] {
+            assert_eq!(gradient_vector(&map, cell), FALLBACK_VECTOR);
+            assert_eq!(weighted_vector(&map, cell), FALLBACK_VECTOR);
+        }
+
+        // Their neighbours still go downhill.
+        assert!(gradient_vector(&map, IVec2::new(1, 1)).normalize().dot(Vec2::ONE.normalize()) > 0.99);
+    }
+
+    #[test]
+    fn blocked_cells_lead_out() {
+
+        let mut map = proximity_map();
+        map.set_value(IVec2::new(2, 3), TargetProximity::Unreachable).unwrap();
+        assert_finite(&map);
+
+        // The target is the closest reached neighbour.
+        assert_eq!(gradient_vector(&map, IVec2::new(2, 3)), Vec2::new(0., -AGENT_DESIRED_SPEED));
+    }
+
+    #[test]
+    fn enclosed_blocked_cells_fall_back() {
+
+        let mut map = proximity_map();
+        map.reset(TargetProximity::Unreachable);
+        map.set_value(IVec2::new(4, 4), TargetProximity::Computed(0.)).unwrap();
+        assert_finite(&map);
+
+        assert_eq!(gradient_vector(&map, IVec2::new(1, 1)), FALLBACK_VECTOR);
+        assert_eq!(weighted_vector(&map, IVec2::new(1, 1)), FALLBACK_VECTOR);
+        assert!(gradient_vector(&map, IVec2::new(3, 3)).normalize().dot(Vec2::ONE.normalize()) > 0.99);
+    }
+
+    #[test]
+    fn diagonals_between_blocked_cells_are_cut() {
+
+        let blocked = [IVec2::new(1, 0), IVec2::new(0, 1)];
+        let is_blocked = |cell: IVec2| blocked.contains(&cell);
+
+        assert!(cuts_corner(IVec2::ZERO, IVec2::ONE, is_blocked));
+        assert!(cuts_corner(IVec2::ONE, IVec2::NEG_ONE, is_blocked));
+        assert!(cuts_corner(IVec2::new(1, 1), IVec2::new(-1, 1), is_blocked), "one blocked side is enough");
+        assert!(!cuts_corner(IVec2::new(1, 1), IVec2::ONE, is_blocked));
+        assert!(!cuts_corner(IVec2::ZERO, IVec2::X, is_blocked), "orthogonal steps never cut corners");
+
+        // The proximity map does not leak through the gap either.
+        let mut obstacles = GridMap::new(3, 3, Rect::new(0., 0., 3., 3.), BlockedStatus::Empty);
+        let mut targets = obstacles.new_like(TargetStatus::NotTarget);
+        targets.set_value(IVec2::ZERO, TargetStatus::IsTarget).unwrap();
+
+        let mut proximity = obstacles.new_like(TargetProximity::NotComputed);
+        rebuild_proximity_map(&mut proximity, &obstacles, &targets, |_| 1.);
+
+        assert!(matches!(proximity.get_value_at_cell(IVec2::ONE), Some(TargetProximity::Computed(distance)) if (distance - 2_f32.sqrt()).abs() < PROXIMITY_EPSILON));
+
+        for &cell in &blocked {
+            obstacles.set_value(cell, BlockedStatus::Blocked).unwrap();
+        }
+
+        rebuild_proximity_map(&mut proximity, &obstacles, &targets, |_| 1.);
+
+        for cell in proximity.cells().filter(|&cell| cell != IVec2::ZERO) {
+            assert!(!matches!(proximity.get_value_at_cell(cell), Some(TargetProximity::Computed(_))), "{cell} reached");
+        }
+    }
+
+    #[test]
+    fn interpolated_vectors_leave_out_blocked_cells() {
+
+        let blocked = IVec2::new(3, 2);
+        let mut proximity = proximity_map();
+        proximity.set_value(blocked, TargetProximity::Unreachable).unwrap();
+
+        let mut obstacles = proximity.new_like(BlockedStatus::Empty);
+        obstacles.set_value(blocked, BlockedStatus::Blocked).unwrap();
+
+        let mut vectors = proximity.new_like(Vec2::ZERO);
+        fill_vector_map(&mut vectors, &proximity, proximity.full_region(), VectorFieldMode::Gradient);
+
+        let is_walkable = |cell: IVec2| matches!(obstacles.get_value_at_cell(cell), Some(BlockedStatus::Empty | BlockedStatus::Clearance));
+        let sample = |pos: Vec2| vectors.get_interpolated_value_at(pos, is_walkable).unwrap();
+
+        // The blocked cell points out of the wall, towards the target.
+        assert_eq!(vectors.get_value_at_cell(blocked), Some(Vec2::new(-AGENT_DESIRED_SPEED, 0.)));
+
+        // Between the blocked cell and its neighbours only the latter count.
+        assert_eq!(sample(Vec2::new(3.5, 3.)), vectors.get_value_at_cell(IVec2::new(3, 3)).unwrap());
+        assert_eq!(sample(Vec2::new(4., 2.5)), vectors.get_value_at_cell(IVec2::new(4, 2)).unwrap());
+
+        // Next to the target its fallback vector slows agents down without
+        // turning them away from it.
+        let near_target = sample(Vec2::new(2.5, 3.));
+        assert_eq!(near_target, vectors.get_value_at_cell(IVec2::new(2, 3)).unwrap() * 0.5);
+        assert!(near_target.normalize().dot(Vec2::NEG_Y) > 0.99);
+    }
+
+    #[test]
+    fn look_ahead_stops_at_the_last_visible_cell() {
+
+        // A wall along x = 5 with a door at the top row.
+        let mut obstacles = GridMap::new(10, 4, Rect::new(0., 0., 10., 4.), BlockedStatus::Empty);
+        let mut targets = obstacles.new_like(TargetStatus::NotTarget);
+        targets.set_value(IVec2::new(9, 0), TargetStatus::IsTarget).unwrap();
+
+        for y in 0..3 {
+            obstacles.set_value(IVec2::new(5, y), BlockedStatus::Blocked).unwrap();
+        }
+
+        let mut proximity = obstacles.new_like(TargetProximity::NotComputed);
+        rebuild_proximity_map(&mut proximity, &obstacles, &targets, |_| 1.);
+
+        // In the open the path is followed for as many cells as allowed.
+        assert_eq!(look_ahead_target(&proximity, &obstacles, IVec2::new(6, 3), 2), Some(IVec2::new(8, 1)));
+
+        // Before the wall it follows the path up to the door, and stops there
+        // as the cells behind the wall are out of sight.
+        assert_eq!(look_ahead_target(&proximity, &obstacles, IVec2::new(0, 0), 20), Some(IVec2::new(5, 3)));
+
+        // Targets, unreached cells and a zero budget give nothing to look at.
+        assert_eq!(look_ahead_target(&proximity, &obstacles, IVec2::new(9, 0), 5), None);
+        assert_eq!(look_ahead_target(&proximity, &obstacles, IVec2::new(5, 0), 5), None);
+        assert_eq!(look_ahead_target(&proximity, &obstacles, IVec2::new(0, 0), 0), None);
+    }
+
+    /// Inflates the obstacles of `obstacles` from scratch into fresh layers.
+    fn rebuilt(obstacles: &GridMap<BlockedStatus>, cap: f32, clearance: f32) -> (GridMap<BlockedStatus>, GridMap<WallDistance>) {
+
+        let mut obstacles = obstacles.clone();
+        let mut distances = obstacles.new_like(WallDistance::default());
+
+        inflate(&obstacles, &distances, None, cap, clearance).apply(&mut &mut obstacles, &mut &mut distances);
+
+        (obstacles, distances)
+    }
+
+    #[test]
+    fn repaired_inflation_matches_a_rebuild() {
+
+        let (cap, clearance) = (3.5, 1.5);
+        let mut obstacles = GridMap::new_chunked(40, 30, Rect::new(0., 0., 40., 30.), BlockedStatus::Empty, 8);
+
+        for cell in [IVec2::new(5, 5), IVec2::new(20, 12), IVec2::new(21, 12), IVec2::new(39, 29)] {
+            obstacles.set_value(cell, BlockedStatus::Blocked).unwrap();
+        }
+
+        let (mut obstacles, mut distances) = rebuilt(&obstacles, cap, clearance);
+
+        // Far from every obstacle, chunks stay unallocated.
+        assert_eq!(distances.chunk_regions().len(), 6);
+        assert_eq!(distances.get_value_at_cell(IVec2::new(12, 25)), Some(WallDistance(cap)));
+        assert_eq!(distances.get_value_at_cell(IVec2::new(5, 7)), Some(WallDistance(2.)));
+        assert_eq!(obstacles.get_value_at_cell(IVec2::new(5, 6)), Some(BlockedStatus::Clearance));
+
+        // One obstacle moves: the cells it leaves go back to the base value,
+        // as the collision map draws them.
+        obstacles.take_dirty_region();
+        obstacles.set_value(IVec2::new(20, 12), BlockedStatus::Empty).unwrap();
+        obstacles.set_value(IVec2::new(21, 12), BlockedStatus::Empty).unwrap();
+        obstacles.set_value(IVec2::new(23, 14), BlockedStatus::Blocked).unwrap();
+
+        for cell in obstacles.cells_in_region(IRect::new(17, 9, 25, 16)).collect::<Vec<_>>() {
+            if obstacles.get_value_at_cell(cell) == Some(BlockedStatus::Clearance) {
+                obstacles.set_value(cell, BlockedStatus::Empty).unwrap();
+            }
+        }
+
+        let region = obstacles.take_dirty_region();
+        distances.take_dirty_region();
+
+        let inflation = inflate(&obstacles, &distances, region, cap, clearance);
+        assert!(inflation.rebuilt.is_none());
+        inflation.apply(&mut &mut obstacles, &mut &mut distances);
+
+        let (expected_obstacles, expected_distances) = rebuilt(&obstacles, cap, clearance);
+
+        for cell in obstacles.cells() {
+            assert_eq!(obstacles.get_value_at_cell(cell), expected_obstacles.get_value_at_cell(cell), "status at {cell}");
+
+            let (WallDistance(distance), WallDistance(expected)) = (distances.get_value_at_cell(cell).unwrap(), expected_distances.get_value_at_cell(cell).unwrap());
+            assert!((distance - expected).abs() <= WALL_DISTANCE_EPSILON, "distance at {cell}: {distance} != {expected}");
+        }
+
+        // Only the distances around the moved obstacle were written.
+        let dirty = distances.dirty_region().unwrap();
+        assert!(dirty.min.cmpge(IVec2::new(16, 8)).all() && dirty.max.cmple(IVec2::new(28, 19)).all(), "{dirty:?}");
+    }
+
+    #[test]
+    fn rooms_meet_at_doors() {
+
+        // Two 10 x 12 rooms split by a wall with a two cell wide gap.
+        let mut obstacles = GridMap::new_chunked(21, 12, Rect::new(0., 0., 21., 12.), BlockedStatus::Empty, 8);
+
+        for y in (0..5).chain(7..12) {
+            obstacles.set_value(IVec2::new(10, y), BlockedStatus::Blocked).unwrap();
+        }
+
+        let (graph, labels) = find_rooms(&obstacles);
+
+        assert_eq!(graph.rooms.len(), 2);
+        assert_eq!(graph.doors.len(), 1);
+        assert!(graph.doors[0].position.distance(Vec2::new(10.5, 6.)) <= 1., "{:?}", graph.doors[0]);
+
+        let (left, right) = (labels.get_value_at_cell(IVec2::new(2, 2)).unwrap(), labels.get_value_at_cell(IVec2::new(18, 9)).unwrap());
+        assert!(left.0.is_some() && right.0.is_some() && left != right);
+        assert_eq!(labels.get_value_at_cell(IVec2::new(10, 2)), Some(RoomLabel(None)));
+    }
+
+    #[test]
+    fn free_chunks_are_labelled_whole() {
+
+        let area = Rect::new(0., 0., 32., 32.);
+        let mut regions = Vec::new();
+
+        for chunk_size in [None, Some(8)] {
+            let mut obstacles = GridMap::new_layer(32, 32, area, BlockedStatus::Empty, chunk_size);
+            let mut targets = GridMap::new_layer(32, 32, area, TargetStatus::NotTarget, chunk_size);
+
+            // A wall splits the map, with a pocket closed by a ring.
+            for y in 0..32 {
+                obstacles.set_value(IVec2::new(12, y), BlockedStatus::Blocked).unwrap();
+            }
+
+            for cell in obstacles.cells_in_region(IRect::new(20, 20, 25, 25)).collect::<Vec<_>>() {
+                if cell.x == 20 || cell.x == 24 || cell.y == 20 || cell.y == 24 {
+                    obstacles.set_value(cell, BlockedStatus::Blocked).unwrap();
+                }
+            }
+
+            targets.set_value(IVec2::new(30, 2), TargetStatus::IsTarget).unwrap();
+
+            regions.push(find_regions(&obstacles, &targets));
+        }
+
+        let (dense, chunked) = (&regions[0], &regions[1]);
+        let mut same_region = HashMap::new();
+
+        for cell in dense.cells() {
+            let (RegionLabel(expected), RegionLabel(label)) = (dense.get_value_at_cell(cell).unwrap(), chunked.get_value_at_cell(cell).unwrap());
+
+            assert_eq!(expected.is_some(), label.is_some(), "label at {cell}");
+            assert_eq!(*same_region.entry(expected).or_insert(label), label, "region at {cell}");
+        }
+
+        assert_eq!(same_region.len(), 4);
+
+        // Left of the wall lie the most free chunks: they keep the default
+        // label, those of the right side are written.
+        assert!(!chunked.is_allocated(IVec2::new(2, 2)));
+        assert!(chunked.is_allocated(IVec2::new(28, 12)));
+    }
+
+    #[test]
+    fn updated_sectors_match_a_rebuild() {
+
+        let area = Rect::new(0., 0., 40., 40.);
+        let mut obstacles = GridMap::new_layer(40, 40, area, BlockedStatus::Empty, None);
+        let mut targets = GridMap::new_layer(40, 40, area, TargetStatus::NotTarget, None);
+        let cell_cost = |cell: IVec2| 1. + ((cell.x + 2 * cell.y) % 4) as f32 * 0.25;
+
+        targets.set_value(IVec2::new(35, 35), TargetStatus::IsTarget).unwrap();
+
+        let mut graph = SectorGraph::new(10);
+        update_sectors(&mut graph, &obstacles, &targets, obstacles.full_region(), cell_cost);
+        graph.built_sectors.extend([IVec2::new(2, 0), IVec2::new(3, 3)]);
+
+        // A wall grows inside one sector.
+        obstacles.take_dirty_region();
+
+        for y in 0..6 {
+            obstacles.set_value(IVec2::new(25, y), BlockedStatus::Blocked).unwrap();
+        }
+
+        let region = obstacles.take_dirty_region().unwrap();
+        let stale = update_sectors(&mut graph, &obstacles, &targets, region, cell_cost);
+
+        assert_eq!(stale, vec![IVec2::new(2, 0)]);
+        assert_eq!(graph.built_sectors, HashSet::from([IVec2::new(3, 3)]));
+
+        let mut expected = SectorGraph::new(10);
+        update_sectors(&mut expected, &obstacles, &targets, obstacles.full_region(), cell_cost);
+
+        assert_eq!(graph.nodes.len(), expected.nodes.len());
+
+        for sector in obstacles.cells_in_region(IRect::new(0, 0, 4, 4)) {
+            assert!(same_seeds(&portal_seeds(&graph, sector), &portal_seeds(&expected, sector)), "portals of {sector}");
+        }
+
+        // Walls go up along the sector borders, leaving a one cell door in
+        // each, so every path between sectors goes through a single portal
+        // cell and the sector fields match the flat field.
+        obstacles.take_dirty_region();
+
+        for cell in obstacles.cells().collect::<Vec<_>>() {
+            let on_border = (cell % 10).cmpeq(IVec2::splat(9));
+            let door = cell % 10 == IVec2::new(9, 4) || cell % 10 == IVec2::new(4, 9);
+
+            if on_border.any() && !door {
+                obstacles.set_value(cell, BlockedStatus::Blocked).unwrap();
+            }
+        }
+
+        let region = obstacles.take_dirty_region().unwrap();
+        update_sectors(&mut graph, &obstacles, &targets, region, cell_cost);
+
+        let mut proximity = obstacles.new_like(TargetProximity::NotComputed);
+        let mut vectors = obstacles.new_like(Vec2::ZERO);
+
+        for sector in obstacles.cells_in_region(IRect::new(0, 0, 4, 4)) {
+            build_sector_field(&mut graph, sector, &mut proximity, &mut vectors, &obstacles, &targets, cell_cost, VectorFieldMode::Gradient);
+        }
+
+        let mut flat = obstacles.new_like(TargetProximity::NotComputed);
+        rebuild_proximity_map(&mut flat, &obstacles, &targets, cell_cost);
+
+        assert_same_proximity(&proximity, &flat);
+    }
+
+    fn assert_same_proximity(map: &GridMap<TargetProximity>, expected: &GridMap<TargetProximity>) {
+        for cell in expected.cells() {
+            match (map.get_value_at_cell(cell).unwrap(), expected.get_value_at_cell(cell).unwrap()) {
+                (TargetProximity::Computed(value), TargetProximity::Computed(expected)) => assert!((value - expected).abs() <= PROXIMITY_EPSILON, "proximity at {cell}: {value} != {expected}"),
+                (value, expected) => assert_eq!(std::mem::discriminant(&value), std::mem::discriminant(&expected), "proximity at {cell}: {value:?} != {expected:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn repaired_destination_fields_match_a_rebuild() {
+
+        let mut obstacles = GridMap::new(30, 30, Rect::new(0., 0., 30., 30.), BlockedStatus::Empty);
+        let (center, shape) = (Vec2::new(25., 25.), Shape::Circle(2.));
+        let mut field = build_destination_field(&obstacles, center, &shape, |_| 1., VectorFieldMode::Gradient, None);
+
+        // A wall goes up, then part of it comes down again.
+        for (cells, status) in [((0..20).map(|y| IVec2::new(15, y)).collect::<Vec<_>>(), BlockedStatus::Blocked), ((5..12).map(|y| IVec2::new(15, y)).collect(), BlockedStatus::Empty)] {
+            obstacles.take_dirty_region();
+
+            for cell in cells {
+                obstacles.set_value(cell, status).unwrap();
+            }
+
+            let region = obstacles.take_dirty_region().unwrap();
+            field = repair_destination_field(field, &obstacles, region, |_| 1., VectorFieldMode::Gradient);
+            let expected = build_destination_field(&obstacles, center, &shape, |_| 1., VectorFieldMode::Gradient, None);
+
+            assert_same_proximity(&field.proximity, &expected.proximity);
+
+            for cell in obstacles.cells() {
+                assert_eq!(field.vectors.get_value_at_cell(cell), expected.vectors.get_value_at_cell(cell), "vector at {cell}");
+            }
+        }
+    }
+
+    #[test]
+    fn repaired_proximity_matches_a_rebuild() {
+
+        let mut rng = fastrand::Rng::with_seed(7);
+        let mut obstacles = GridMap::new(32, 24, Rect::new(0., 0., 32., 24.), BlockedStatus::Empty);
+        let mut targets = obstacles.new_like(TargetStatus::NotTarget);
+
+        for cell in [IVec2::new(3, 3), IVec2::new(28, 20), IVec2::new(29, 20)] {
+            targets.set_value(cell, TargetStatus::IsTarget).unwrap();
+        }
+
+        let cell_cost = |cell: IVec2| 1. + (cell.x % 3) as f32 * 0.5;
+        let mut proximity = obstacles.new_like(TargetProximity::NotComputed);
+        rebuild_proximity_map(&mut proximity, &obstacles, &targets, cell_cost);
+
+        for _ in 0..40 {
+            obstacles.take_dirty_region();
+
+            // A few cells around one spot are blocked or cleared.
+            let spot = IVec2::new(rng.i32(0..32), rng.i32(0..24));
+
+            for _ in 0..rng.usize(1..6) {
+                let cell = spot + IVec2::new(rng.i32(-2..=2), rng.i32(-2..=2));
+                let status = if rng.bool() { BlockedStatus::Blocked } else { BlockedStatus::Empty };
+
+                obstacles.set_value(cell, status).ok();
+            }
+
+            let Some(region) = obstacles.take_dirty_region() else {
+                continue;
+            };
+
+            repair_proximity_map(&mut proximity, &obstacles, &targets, region, cell_cost);
+
+            let mut expected = obstacles.new_like(TargetProximity::NotComputed);
+            rebuild_proximity_map(&mut expected, &obstacles, &targets, cell_cost);
+
+            assert_same_proximity(&proximity, &expected);
+        }
+    }
+}
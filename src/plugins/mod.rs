@@ -1,2 +1,4 @@
 pub mod flow_field_pathfinding;
-pub mod simulation_area;
\ No newline at end of file
+pub mod simulation_area;
+pub mod navmesh;
+pub mod scenario;
\ No newline at end of file
@@ -0,0 +1,5 @@
+pub mod plugin;
+pub mod models;
+pub mod resources;
+pub mod systems;
+pub mod triangulation;
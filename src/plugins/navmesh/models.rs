@@ -0,0 +1,70 @@
+use bevy::{ecs::entity::Entity, math::{IVec2, Rect, Vec2}};
+
+/// Walkable triangle of the navigation mesh.
+#[derive(Clone, Copy, Debug)]
+pub struct NavTriangle {
+    /// Vertex indices in counter-clockwise order.
+    pub vertices: [usize; 3],
+    /// Triangle across the edge from `vertices[i]` to `vertices[(i + 1) % 3]`.
+    pub neighbours: [Option<usize>; 3],
+}
+
+/// Uniform grid listing, for each bucket, the triangles whose bounds overlap
+/// it.
+#[derive(Debug, Default)]
+pub struct TriangleGrid {
+    pub origin: Vec2,
+    pub bucket_size: f32,
+    pub columns: i32,
+    pub rows: i32,
+    pub buckets: Vec<Vec<usize>>,
+}
+
+impl TriangleGrid {
+    pub fn new(bounds: Rect, bucket_size: f32) -> Self {
+        let bucket_size = bucket_size.max(f32::EPSILON);
+        let columns = (bounds.width() / bucket_size).ceil().max(1.) as i32;
+        let rows = (bounds.height() / bucket_size).ceil().max(1.) as i32;
+
+        Self {
+            origin: bounds.min,
+            bucket_size,
+            columns,
+            rows,
+            buckets: vec![Vec::new(); (columns * rows) as usize],
+        }
+    }
+
+    /// Bucket containing `pos`, which may lie outside the grid.
+    pub fn bucket_at(&self, pos: Vec2) -> IVec2 {
+        ((pos - self.origin) / self.bucket_size).floor().as_ivec2()
+    }
+
+    pub fn get(&self, bucket: IVec2) -> &[usize] {
+        match bucket.cmpge(IVec2::ZERO).all() && bucket.x < self.columns && bucket.y < self.rows {
+            true => &self.buckets[(bucket.y * self.columns + bucket.x) as usize],
+            false => &[],
+        }
+    }
+
+    pub fn insert(&mut self, triangle: usize, bounds: Rect) {
+        let (min, max) = (self.bucket_at(bounds.min).max(IVec2::ZERO), self.bucket_at(bounds.max).min(IVec2::new(self.columns - 1, self.rows - 1)));
+
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                self.buckets[(y * self.columns + x) as usize].push(triangle);
+            }
+        }
+    }
+}
+
+/// Triangles an agent walks through to reach its goal, found with A* on a
+/// given revision of the navigation mesh.
+#[derive(Debug, Clone)]
+pub struct NavPath {
+    pub revision: u32,
+    /// Objective searched for, any of them when `None`.
+    pub destination: Option<Entity>,
+    pub triangles: Vec<usize>,
+    pub goal: Vec2,
+}
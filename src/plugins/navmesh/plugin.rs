@@ -0,0 +1,25 @@
+use bevy::prelude::*;
+
+use crate::{plugins::flow_field_pathfinding::resources::ShowGridState, systems::apply_social_foces};
+
+use super::{resources::*, systems::*};
+
+/// Navigation mesh backend. It is built and steers the agents when
+/// [`NavigationBackend::NavMesh`] is selected, in place of the flow field
+/// steering. Agent classes are not taken into account.
+pub struct NavMeshPlugin;
+
+impl Plugin for NavMeshPlugin {
+    fn build(&self, app: &mut App) {
+
+        app.init_resource::<NavigationBackend>()
+        .init_resource::<NavMeshSettings>()
+        .init_resource::<NavMesh>();
+
+        app.add_systems(First, handle_backend_inputs)
+        .add_systems(PreUpdate, (build_navmesh, locate_navmesh_goals).chain().run_if(resource_equals(NavigationBackend::NavMesh)))
+        .add_systems(Update, apply_navmesh_directions.before(apply_social_foces).run_if(resource_equals(NavigationBackend::NavMesh)))
+        .add_systems(Update, warn_classless_navmesh.run_if(resource_equals(NavigationBackend::NavMesh)))
+        .add_systems(PostUpdate, draw_navmesh.run_if(resource_equals(NavigationBackend::NavMesh)).run_if(in_state(ShowGridState::ShowGrid)));
+    }
+}
@@ -0,0 +1,278 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use bevy::{ecs::{entity::Entity, system::Resource}, math::{IVec2, Rect, Vec2}};
+
+use crate::consts::AGENT_RADIUS;
+
+use super::models::{NavTriangle, TriangleGrid};
+
+/// Which structure steers the agents.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NavigationBackend {
+    #[default]
+    FlowField,
+    /// A single mesh for every agent, built with [`NavMeshSettings::clearance`].
+    /// The clearance and forbidden zones of agent classes are not honoured.
+    NavMesh,
+}
+
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct NavMeshSettings {
+    /// Longest edge used to sample the area boundary and obstacle outlines.
+    pub spacing: f32,
+    /// Distance agents keep from obstacles.
+    pub clearance: f32,
+}
+
+impl Default for NavMeshSettings {
+    fn default() -> Self {
+        Self {
+            spacing: 20.,
+            clearance: AGENT_RADIUS,
+        }
+    }
+}
+
+/// Triangulation of the walkable space and the objectives agents head to.
+#[derive(Resource, Debug, Default)]
+pub struct NavMesh {
+    pub vertices: Vec<Vec2>,
+    pub triangles: Vec<NavTriangle>,
+    /// Spatial index of the triangles.
+    pub index: TriangleGrid,
+    /// Objectives with the triangle containing them.
+    pub goals: Vec<(Entity, usize, Vec2)>,
+    /// Bumped whenever the triangles or the goals change, so paths found on
+    /// an earlier revision are searched again.
+    pub revision: u32,
+}
+
+impl NavMesh {
+    pub fn new(vertices: Vec<Vec2>, triangles: Vec<NavTriangle>) -> Self {
+
+        let bounds = |triangle: &NavTriangle| triangle.vertices.iter()
+            .fold(Rect::from_center_size(vertices[triangle.vertices[0]], Vec2::ZERO), |rect, &vertex| rect.union_point(vertices[vertex]));
+
+        let area = triangles.iter().map(bounds).reduce(|a, b| a.union(b)).unwrap_or_default();
+        let bucket_size = 2. * (area.width() * area.height() / triangles.len().max(1) as f32).sqrt();
+
+        let mut index = TriangleGrid::new(area, bucket_size);
+
+        for (triangle, nav_triangle) in triangles.iter().enumerate() {
+            index.insert(triangle, bounds(nav_triangle));
+        }
+
+        Self { vertices, triangles, index, goals: Vec::new(), revision: 0 }
+    }
+
+    pub fn centroid(&self, triangle: usize) -> Vec2 {
+        let [a, b, c] = self.triangles[triangle].vertices;
+        (self.vertices[a] + self.vertices[b] + self.vertices[c]) / 3.
+    }
+
+    pub fn contains(&self, triangle: usize, pos: Vec2) -> bool {
+        let [a, b, c] = self.triangles[triangle].vertices;
+        let [a, b, c] = [self.vertices[a], self.vertices[b], self.vertices[c]];
+
+        (b - a).perp_dot(pos - a) >= 0. && (c - b).perp_dot(pos - b) >= 0. && (a - c).perp_dot(pos - c) >= 0.
+    }
+
+    fn distance_to(&self, triangle: usize, pos: Vec2) -> f32 {
+
+        if self.contains(triangle, pos) {
+            return 0.;
+        }
+
+        let [a, b, c] = self.triangles[triangle].vertices.map(|vertex| self.vertices[vertex]);
+
+        [(a, b), (b, c), (c, a)].into_iter()
+            .map(|(start, end)| {
+                let t = ((pos - start).dot(end - start) / (end - start).length_squared().max(f32::EPSILON)).clamp(0., 1.);
+                pos.distance(start.lerp(end, t))
+            })
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    /// Triangle containing `pos`, if it lies in walkable space.
+    pub fn locate(&self, pos: Vec2) -> Option<usize> {
+        self.index.get(self.index.bucket_at(pos)).iter()
+            .copied()
+            .find(|&triangle| self.contains(triangle, pos))
+    }
+
+    /// Triangle closest to `pos`, searching the buckets of the index in
+    /// growing rings.
+    pub fn closest_triangle(&self, pos: Vec2) -> Option<usize> {
+
+        let last = IVec2::new(self.index.columns - 1, self.index.rows - 1);
+        let center = self.index.bucket_at(pos).clamp(IVec2::ZERO, last);
+        let mut closest: Option<(usize, f32)> = None;
+
+        for ring in 0..=self.index.columns.max(self.index.rows) {
+            for y in center.y - ring..=center.y + ring {
+                for x in center.x - ring..=center.x + ring {
+                    if (x - center.x).abs() != ring && (y - center.y).abs() != ring {
+                        continue;
+                    }
+
+                    for &triangle in self.index.get(IVec2::new(x, y)) {
+                        let distance = self.distance_to(triangle, pos);
+
+                        if closest.is_none_or(|(_, closest)| distance < closest) {
+                            closest = Some((triangle, distance));
+                        }
+                    }
+                }
+            }
+
+            // Triangles in the next rings are at least this far.
+            if closest.is_some_and(|(_, distance)| distance <= ring as f32 * self.index.bucket_size) {
+                break;
+            }
+        }
+
+        closest.map(|(triangle, _)| triangle)
+    }
+
+    /// Edge shared by `from` and `to` as `(left, right)` seen when walking from
+    /// `from` into `to`.
+    fn portal(&self, from: usize, to: usize) -> Option<(Vec2, Vec2)> {
+        let triangle = &self.triangles[from];
+        let edge = triangle.neighbours.iter().position(|&neighbour| neighbour == Some(to))?;
+
+        let p = self.vertices[triangle.vertices[edge]];
+        let q = self.vertices[triangle.vertices[(edge + 1) % 3]];
+        let center = self.centroid(from);
+
+        if (p - center).perp_dot(q - center) > 0. {
+            Some((q, p))
+        } else {
+            Some((p, q))
+        }
+    }
+
+    /// Triangles from `start` to the closest of `goals`, found with A* over
+    /// the triangle centroids, and the goal reached.
+    pub fn find_path(&self, start: usize, goals: &[(usize, Vec2)]) -> Option<(Vec<usize>, Vec2)> {
+
+        let heuristic = |triangle: usize| goals.iter()
+            .map(|&(goal, _)| self.centroid(triangle).distance(self.centroid(goal)))
+            .fold(f32::INFINITY, f32::min);
+
+        let mut cost = vec![f32::INFINITY; self.triangles.len()];
+        let mut previous: Vec<Option<usize>> = vec![None; self.triangles.len()];
+        let mut closed = vec![false; self.triangles.len()];
+        let mut open = BinaryHeap::new();
+
+        cost[start] = 0.;
+        open.push(OpenTriangle { estimate: heuristic(start), triangle: start });
+
+        while let Some(OpenTriangle { triangle, .. }) = open.pop() {
+
+            // The heuristic is consistent, so a triangle's first pop is final.
+            if std::mem::replace(&mut closed[triangle], true) {
+                continue;
+            }
+
+            if let Some(&(_, goal)) = goals.iter().find(|(goal, _)| *goal == triangle) {
+                let mut path = vec![triangle];
+
+                while let Some(before) = previous[*path.last().unwrap()] {
+                    path.push(before);
+                }
+
+                path.reverse();
+                return Some((path, goal));
+            }
+
+            for neighbour in self.triangles[triangle].neighbours.into_iter().flatten() {
+                let new_cost = cost[triangle] + self.centroid(triangle).distance(self.centroid(neighbour));
+
+                if new_cost < cost[neighbour] {
+                    cost[neighbour] = new_cost;
+                    previous[neighbour] = Some(triangle);
+                    open.push(OpenTriangle { estimate: new_cost + heuristic(neighbour), triangle: neighbour });
+                }
+            }
+        }
+
+        None
+    }
+
+    /// First corner of the straight-line path from `pos` through the
+    /// triangles of `path` to `goal`, smoothed with the funnel algorithm.
+    pub fn corner_along(&self, pos: Vec2, path: &[usize], goal: Vec2) -> Option<Vec2> {
+
+        let mut portals = vec![(pos, pos)];
+
+        for pair in path.windows(2) {
+            portals.push(self.portal(pair[0], pair[1])?);
+        }
+
+        portals.push((goal, goal));
+
+        Some(first_corner(&portals))
+    }
+}
+
+/// Entry of the A* open list, popped lowest estimate first.
+struct OpenTriangle {
+    estimate: f32,
+    triangle: usize,
+}
+
+impl PartialEq for OpenTriangle {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OpenTriangle {}
+
+impl PartialOrd for OpenTriangle {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenTriangle {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate).then(other.triangle.cmp(&self.triangle))
+    }
+}
+
+/// Twice the signed area of the triangle, positive when `c` lies clockwise of
+/// the direction from `a` to `b`.
+fn triangle_area_2(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+    -(b - a).perp_dot(c - a)
+}
+
+/// First corner of the shortest path through `portals` (simple stupid funnel
+/// algorithm). The first portal holds the start position twice and the last
+/// one the goal twice.
+fn first_corner(portals: &[(Vec2, Vec2)]) -> Vec2 {
+    let apex = portals[0].0;
+    let mut left = apex;
+    let mut right = apex;
+
+    for &(new_left, new_right) in &portals[1..] {
+
+        if triangle_area_2(apex, right, new_right) <= 0. {
+            if apex == right || triangle_area_2(apex, left, new_right) > 0. {
+                right = new_right;
+            } else {
+                return left;
+            }
+        }
+
+        if triangle_area_2(apex, left, new_left) >= 0. {
+            if apex == left || triangle_area_2(apex, right, new_left) < 0. {
+                left = new_left;
+            } else {
+                return right;
+            }
+        }
+    }
+
+    portals[portals.len() - 1].0
+}
@@ -0,0 +1,356 @@
+use std::{collections::HashMap, f32::consts::PI};
+
+use bevy::{color::palettes::tailwind::TEAL_500, math::vec2, prelude::*};
+
+use crate::{components::{bounds, contains_point, Agent, AgentClass, DesiredSpeed, Destination, MotivationForce, Objective, Obstacle, Shape, Speed}, consts::AGENT_DESIRED_SPEED, plugins::{flow_field_pathfinding::resources::{BackgroundTask, FlowFieldExecution}, simulation_area::resources::SimulationArea}};
+
+use super::{models::{NavPath, NavTriangle}, resources::{NavMesh, NavMeshSettings, NavigationBackend}, triangulation::{planar_segments, triangulate}};
+
+pub fn build_navmesh(
+    mut navmesh: ResMut<NavMesh>,
+    settings: Res<NavMeshSettings>,
+    backend: Res<NavigationBackend>,
+    simulation_area: Res<SimulationArea>,
+    execution: Res<FlowFieldExecution>,
+    obstacles: Query<(&Transform, &Shape), With<Obstacle>>,
+    changed: Query<(), (With<Obstacle>, Or<(Changed<Transform>, Changed<Shape>)>)>,
+    mut removed_obstacles: RemovedComponents<Obstacle>,
+    mut pending: Local<bool>,
+    mut task: Local<BackgroundTask<NavMesh>>,
+){
+
+    if let Some(built) = task.poll() {
+        replace_navmesh(&mut navmesh, built);
+    }
+
+    let removed = removed_obstacles.read().count() > 0;
+
+    *pending |= !changed.is_empty() || removed || settings.is_changed() || simulation_area.is_changed() || backend.is_changed();
+
+    if task.is_running() || !std::mem::take(&mut *pending) {
+        return;
+    }
+
+    let footprints: Vec<(Vec2, Shape)> = obstacles.iter()
+        .map(|(transform, shape)| (transform.translation.truncate(), shape.clone()))
+        .collect();
+
+    let (area, clearance, spacing) = (simulation_area.0, settings.clearance, settings.spacing);
+
+    if let Some(built) = task.start(*execution, move || triangulate_walkable(area, &footprints, clearance, spacing)) {
+        replace_navmesh(&mut navmesh, built);
+    }
+}
+
+/// Swaps in a newly built mesh under the next revision. Its goals are placed
+/// by `locate_navmesh_goals`.
+fn replace_navmesh(navmesh: &mut NavMesh, built: NavMesh){
+    let revision = navmesh.revision + 1;
+
+    *navmesh = built;
+    navmesh.revision = revision;
+}
+
+/// Places the objectives on the mesh. Moving objectives only move their goal,
+/// the mesh is left as it is.
+pub fn locate_navmesh_goals(
+    mut navmesh: ResMut<NavMesh>,
+    objectives: Query<(Entity, &Transform), With<Objective>>,
+    changed: Query<(), (With<Objective>, Changed<Transform>)>,
+    mut removed_objectives: RemovedComponents<Objective>,
+){
+
+    let removed = removed_objectives.read().count() > 0;
+
+    if changed.is_empty() && !removed && !navmesh.is_changed() {
+        return;
+    }
+
+    let goals = objectives.iter()
+        .filter_map(|(objective, transform)| {
+            let goal = transform.translation.truncate();
+
+            match navmesh.locate(goal) {
+                Some(triangle) => Some((objective, triangle, goal)),
+                None => {
+                    warn!("Objective at {} is outside the navigation mesh", goal);
+                    None
+                },
+            }
+        })
+        .collect();
+
+    navmesh.goals = goals;
+    navmesh.revision += 1;
+}
+
+/// Constrained Delaunay triangulation of `area` around the footprints grown by
+/// `clearance`, keeping the walkable triangles.
+///
+/// The outlines of the grown footprints are edges of the triangulation, so a
+/// triangle lies either inside or outside them and its centroid tells which.
+pub fn triangulate_walkable(area: Rect, footprints: &[(Vec2, Shape)], clearance: f32, spacing: f32) -> NavMesh{
+
+    let mut blocked: Vec<Vec<Vec2>> = footprints.iter()
+        .flat_map(|(center, shape)| blocked_outlines(*center, shape, clearance, spacing))
+        .filter(|outline| bounds(outline).is_some_and(|bounds| !bounds.intersect(area).is_empty()))
+        .collect();
+
+    for outline in &mut blocked {
+        *outline = subdivide(outline, spacing);
+    }
+
+    let boundary = subdivide(&[area.min, vec2(area.max.x, area.min.y), area.max, vec2(area.min.x, area.max.y)], spacing);
+    let outlines: Vec<Vec<Vec2>> = std::iter::once(boundary).chain(blocked.iter().cloned()).collect();
+
+    let (points, segments) = planar_segments(&outlines);
+    let vertices: Vec<Vec2> = points.iter().map(|point| point.as_vec2()).collect();
+
+    let blocked_bounds: Vec<Option<Rect>> = blocked.iter().map(|outline| bounds(outline)).collect();
+
+    let is_walkable = |pos: Vec2| area.contains(pos) && blocked.iter().zip(&blocked_bounds)
+        .all(|(outline, bounds)| !bounds.is_some_and(|bounds| bounds.contains(pos)) || !contains_point(outline, pos));
+
+    let triangles: Vec<[usize; 3]> = triangulate(&points, &segments).into_iter()
+        .filter(|&[a, b, c]| is_walkable((vertices[a] + vertices[b] + vertices[c]) / 3.))
+        .collect();
+
+    let mut edges: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+    let mut nav_triangles: Vec<NavTriangle> = triangles.iter()
+        .map(|&vertices| NavTriangle { vertices, neighbours: [None; 3] })
+        .collect();
+
+    for (triangle, vertices) in triangles.iter().enumerate() {
+        for edge in 0..3 {
+            let (a, b) = (vertices[edge], vertices[(edge + 1) % 3]);
+
+            match edges.remove(&(b, a)) {
+                Some((other, other_edge)) => {
+                    nav_triangles[triangle].neighbours[edge] = Some(other);
+                    nav_triangles[other].neighbours[other_edge] = Some(triangle);
+                },
+                None => {
+                    edges.insert((a, b), (triangle, edge));
+                }
+            }
+        }
+    }
+
+    NavMesh::new(vertices, nav_triangles)
+}
+
+/// Closed outlines covering the footprint grown by `clearance`: the convex
+/// hull of the grown shape when it is convex, else a rounded outline around
+/// each side and the shape itself.
+fn blocked_outlines(center: Vec2, shape: &Shape, clearance: f32, spacing: f32) -> Vec<Vec<Vec2>>{
+
+    let world = |points: &[Vec2]| points.iter().map(|point| center + *point).collect::<Vec<_>>();
+
+    match shape {
+        Shape::Circle(radius) => vec![rounded_hull(&[center], radius + clearance, spacing)],
+        Shape::Rectangle(size) => {
+            let half = *size / 2.;
+            vec![rounded_hull(&world(&[-half, vec2(half.x, -half.y), half, vec2(-half.x, half.y)]), clearance, spacing)]
+        },
+        Shape::Polygon(points) if is_convex(points) => vec![rounded_hull(&world(points), clearance, spacing)],
+        Shape::Polygon(points) => {
+            let points = world(points);
+            let sides = (0..points.len()).map(|side| rounded_hull(&[points[side], points[(side + 1) % points.len()]], clearance, spacing));
+
+            sides.chain(std::iter::once(points.clone())).collect()
+        },
+        Shape::Polyline { points, thickness } => {
+            let points = world(points);
+
+            match points.len() {
+                0 => Vec::new(),
+                1 => vec![rounded_hull(&points, thickness / 2. + clearance, spacing)],
+                _ => points.windows(2).map(|pair| rounded_hull(pair, thickness / 2. + clearance, spacing)).collect(),
+            }
+        },
+    }
+}
+
+/// Convex hull of circles of `radius` around `points`, approximated by
+/// polygons drawn around the circles.
+fn rounded_hull(points: &[Vec2], radius: f32, spacing: f32) -> Vec<Vec2>{
+
+    if radius <= 0. {
+        return convex_hull(points.to_vec());
+    }
+
+    let sides = ((2. * PI * radius / spacing).ceil() as usize).max(8);
+    let outer_radius = radius / (PI / sides as f32).cos();
+
+    convex_hull(points.iter()
+        .flat_map(|&point| (0..sides).map(move |side| point + Vec2::from_angle(2. * PI * side as f32 / sides as f32) * outer_radius))
+        .collect())
+}
+
+/// Counter-clockwise convex hull (monotone chain).
+fn convex_hull(mut points: Vec<Vec2>) -> Vec<Vec2>{
+
+    points.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    points.dedup();
+
+    if points.len() < 3 {
+        return points;
+    }
+
+    let mut hull: Vec<Vec2> = Vec::new();
+
+    for chain in [points.clone(), points.into_iter().rev().collect()] {
+        let start = hull.len();
+
+        for point in chain {
+            while hull.len() >= start + 2 && (hull[hull.len() - 1] - hull[hull.len() - 2]).perp_dot(point - hull[hull.len() - 2]) <= 0. {
+                hull.pop();
+            }
+
+            hull.push(point);
+        }
+
+        hull.pop();
+    }
+
+    hull
+}
+
+fn is_convex(points: &[Vec2]) -> bool{
+    let turns: Vec<f32> = (0..points.len())
+        .map(|i| (points[(i + 1) % points.len()] - points[i]).perp_dot(points[(i + 2) % points.len()] - points[(i + 1) % points.len()]))
+        .collect();
+
+    turns.iter().all(|&turn| turn >= 0.) || turns.iter().all(|&turn| turn <= 0.)
+}
+
+/// Outline with its sides split so none is longer than `spacing`.
+fn subdivide(outline: &[Vec2], spacing: f32) -> Vec<Vec2>{
+
+    if outline.len() < 3 {
+        return outline.to_vec();
+    }
+
+    (0..outline.len())
+        .flat_map(|i| {
+            let (start, end) = (outline[i], outline[(i + 1) % outline.len()]);
+            let steps = ((end - start).length() / spacing).ceil().max(1.) as usize;
+
+            (0..steps).map(move |step| start.lerp(end, step as f32 / steps as f32))
+        })
+        .collect()
+}
+
+/// Steers each agent to the first corner of its path to its destination, or
+/// to the closest objective without one. Paths are searched with A* and kept
+/// while the agent stays on them and the mesh doesn't change.
+pub fn apply_navmesh_directions(
+    navmesh: Res<NavMesh>,
+    mut paths: Local<HashMap<Entity, NavPath>>,
+    mut agents: Query<(Entity, &mut MotivationForce, &Transform, &Speed, Option<&DesiredSpeed>, Option<&Destination>), With<Agent>>,
+){
+
+    paths.retain(|agent, _| agents.contains(*agent));
+
+    for (agent, mut motivation_force, transform, agent_speed, desired_speed, destination) in &mut agents {
+
+        let pos = transform.translation.truncate();
+        let destination = destination.map(|destination| destination.0);
+
+        let corner = match navmesh.locate(pos) {
+            Some(start) => {
+                // An empty path is a failed search, kept until the mesh or the goals change.
+                let cached = paths.get(&agent).is_some_and(|path| path.revision == navmesh.revision
+                    && path.destination == destination
+                    && (path.triangles.is_empty() || path.triangles.contains(&start)));
+
+                if !cached {
+                    let goals: Vec<(usize, Vec2)> = navmesh.goals.iter()
+                        .filter(|(objective, _, _)| destination.is_none_or(|destination| destination == *objective))
+                        .map(|&(_, triangle, goal)| (triangle, goal))
+                        .collect();
+
+                    let (triangles, goal) = navmesh.find_path(start, &goals).unwrap_or_default();
+                    paths.insert(agent, NavPath { revision: navmesh.revision, destination, triangles, goal });
+                }
+
+                let path = &paths[&agent];
+
+                path.triangles.iter()
+                    .position(|&triangle| triangle == start)
+                    .and_then(|along| navmesh.corner_along(pos, &path.triangles[along..], path.goal))
+            },
+            None => navmesh.closest_triangle(pos).map(|triangle| navmesh.centroid(triangle)),
+        };
+
+        let Some(corner) = corner else {
+            continue;
+        };
+
+        let direction = (corner - pos).normalize_or_zero() * AGENT_DESIRED_SPEED;
+
+        motivation_force.0 = direction * DesiredSpeed::field_scale(desired_speed) - agent_speed.0;
+    }
+}
+
+/// Warns once per switch to the navigation mesh when agents have a class,
+/// since the mesh doesn't honour class clearances and forbidden zones.
+pub fn warn_classless_navmesh(backend: Res<NavigationBackend>, mut warned: Local<bool>, classed_agents: Query<(), (With<Agent>, With<AgentClass>)>) {
+
+    if backend.is_changed() {
+        *warned = false;
+    }
+
+    if *warned || classed_agents.is_empty() {
+        return;
+    }
+
+    warn!("The navigation mesh ignores agent classes: their clearance and forbidden zones are not honoured");
+    *warned = true;
+}
+
+pub fn handle_backend_inputs(mut backend: ResMut<NavigationBackend>, keys: Res<ButtonInput<KeyCode>>) {
+    
+    if keys.just_pressed(KeyCode::KeyN) {
+        *backend = match *backend {
+            NavigationBackend::FlowField => NavigationBackend::NavMesh,
+            NavigationBackend::NavMesh => NavigationBackend::FlowField,
+        };
+    }
+}
+
+pub fn draw_navmesh(mut gizmos: Gizmos, navmesh: Res<NavMesh>){
+
+    for triangle in &navmesh.triangles {
+        let [a, b, c] = triangle.vertices.map(|vertex| navmesh.vertices[vertex]);
+
+        gizmos.linestrip_2d([a, b, c, a], TEAL_500);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thin_walls_split_the_mesh() {
+        let area = Rect::new(0., 0., 20., 20.);
+        let wall = (vec2(10., 0.), Shape::Polyline { points: vec![vec2(0., 0.), vec2(0., 15.)], thickness: 0.1 });
+        let navmesh = triangulate_walkable(area, std::slice::from_ref(&wall), 0.5, 1.);
+
+        for triangle in &navmesh.triangles {
+            let [a, b, c] = triangle.vertices.map(|vertex| navmesh.vertices[vertex]);
+
+            for pos in [(a + b + c) / 3., (a + b) / 2., (b + c) / 2., (c + a) / 2.] {
+                assert!(wall.1.distance(wall.0, pos) >= 0.5 - 1e-3, "{pos} is within the clearance");
+            }
+        }
+
+        let start = navmesh.locate(vec2(5., 5.)).unwrap();
+        let goal = navmesh.locate(vec2(15., 5.)).unwrap();
+        let (path, _) = navmesh.find_path(start, &[(goal, vec2(15., 5.))]).unwrap();
+
+        assert!(path.iter().any(|&triangle| navmesh.centroid(triangle).y > 15.));
+        assert_eq!(navmesh.corner_along(vec2(5., 5.), &path, vec2(15., 5.)).map(|corner| corner.y > 15.), Some(true));
+    }
+}
@@ -0,0 +1,697 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::math::{DVec2, Vec2};
+
+/// Index of the first input point: the three points before it are the corners
+/// of a triangle enclosing all the others.
+const FIRST_POINT: usize = 3;
+
+/// Constrained Delaunay triangulation of `points` in which every segment of
+/// `segments`, given as point indices, is a triangle edge. Segments must not
+/// cross each other; a segment passing through a point is split there.
+///
+/// Triangles are returned with their vertices in counter-clockwise order.
+pub fn triangulate(points: &[DVec2], segments: &[(usize, usize)]) -> Vec<[usize; 3]> {
+
+    if points.len() < 3 {
+        return Vec::new();
+    }
+
+    let mut mesh = Mesh::new(points);
+
+    for point in spatial_order(points) {
+        mesh.insert_point(FIRST_POINT + point);
+    }
+
+    for &(a, b) in segments {
+        mesh.insert_segment(FIRST_POINT + a, FIRST_POINT + b);
+    }
+
+    mesh.vertices.into_iter()
+        .filter(|triangle| triangle.iter().all(|&vertex| vertex >= FIRST_POINT))
+        .map(|triangle| triangle.map(|vertex| vertex - FIRST_POINT))
+        .collect()
+}
+
+/// Points and segments of the closed `outlines`, with the segments split where
+/// they cross or touch each other so they can be used as constraints.
+pub fn planar_segments(outlines: &[Vec<Vec2>]) -> (Vec<DVec2>, Vec<(usize, usize)>) {
+
+    let segments: Vec<(DVec2, DVec2)> = outlines.iter()
+        .flat_map(|outline| {
+            let closing = (outline.len() > 2).then(|| (outline[outline.len() - 1], outline[0]));
+            outline.windows(2).map(|pair| (pair[0], pair[1])).chain(closing)
+        })
+        .map(|(start, end)| (start.as_dvec2(), end.as_dvec2()))
+        .filter(|(start, end)| start != end)
+        .collect();
+
+    let size = segments.iter()
+        .flat_map(|&(start, end)| [start, end])
+        .fold((DVec2::MAX, DVec2::MIN), |(min, max), point| (min.min(point), max.max(point)));
+    let epsilon = 1e-9 * (size.1 - size.0).max_element().max(1.);
+
+    let mut splits: Vec<Vec<(f64, DVec2)>> = segments.iter()
+        .map(|&(start, end)| vec![(0., start), (1., end)])
+        .collect();
+
+    // Sweep over the segments sorted by their left end, so only those whose
+    // x ranges overlap are compared.
+    let mut order: Vec<usize> = (0..segments.len()).collect();
+    order.sort_by(|&a, &b| segments[a].0.x.min(segments[a].1.x).total_cmp(&segments[b].0.x.min(segments[b].1.x)));
+
+    for (position, &first) in order.iter().enumerate() {
+        let (p, p_end) = segments[first];
+
+        for &second in &order[position + 1..] {
+            let (q, q_end) = segments[second];
+
+            if q.x.min(q_end.x) > p.x.max(p_end.x) + epsilon {
+                break;
+            }
+
+            if q.y.min(q_end.y) > p.y.max(p_end.y) + epsilon || p.y.min(p_end.y) > q.y.max(q_end.y) + epsilon {
+                continue;
+            }
+
+            let (r, s) = (p_end - p, q_end - q);
+            let denominator = r.perp_dot(s);
+
+            if denominator.abs() <= epsilon * epsilon {
+                continue;
+            }
+
+            let t = (q - p).perp_dot(s) / denominator;
+            let u = (q - p).perp_dot(r) / denominator;
+            let (t_margin, u_margin) = (epsilon / r.length(), epsilon / s.length());
+
+            if t < -t_margin || t > 1. + t_margin || u < -u_margin || u > 1. + u_margin {
+                continue;
+            }
+
+            // Snap to an end point when the segments only touch, so both
+            // segments share that point.
+            let point = match (t, u) {
+                _ if t <= t_margin => p,
+                _ if t >= 1. - t_margin => p_end,
+                _ if u <= u_margin => q,
+                _ if u >= 1. - u_margin => q_end,
+                _ => p + r * t,
+            };
+
+            if t > t_margin && t < 1. - t_margin {
+                splits[first].push((t, point));
+            }
+
+            if u > u_margin && u < 1. - u_margin {
+                splits[second].push((u, point));
+            }
+        }
+    }
+
+    let mut points = Vec::new();
+    let mut indices: HashMap<(i64, i64), usize> = HashMap::new();
+    let mut constraints = Vec::new();
+
+    let mut index_of = |point: DVec2| *indices
+        .entry(((point.x / epsilon).round() as i64, (point.y / epsilon).round() as i64))
+        .or_insert_with(|| {
+            points.push(point);
+            points.len() - 1
+        });
+
+    for mut split in splits {
+        split.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let chain: Vec<usize> = split.into_iter().map(|(_, point)| index_of(point)).collect();
+
+        constraints.extend(chain.windows(2)
+            .filter(|pair| pair[0] != pair[1])
+            .map(|pair| (pair[0], pair[1])));
+    }
+
+    (points, constraints)
+}
+
+/// Insertion order visiting the points row by row in a snake, so each point
+/// is found close to the previous one.
+fn spatial_order(points: &[DVec2]) -> Vec<usize> {
+
+    let (min, max) = points.iter().fold((DVec2::MAX, DVec2::MIN), |(min, max), point| (min.min(*point), max.max(*point)));
+    let rows = (points.len() as f64).sqrt().ceil().max(1.);
+    let row_height = ((max.y - min.y) / rows).max(f64::EPSILON);
+
+    let key = |point: DVec2| {
+        let row = ((point.y - min.y) / row_height).floor() as i64;
+        let x = if row % 2 == 0 { point.x } else { -point.x };
+        (row, x)
+    };
+
+    let mut order: Vec<usize> = (0..points.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (a, b) = (key(points[a]), key(points[b]));
+        a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+    });
+    order
+}
+
+/// Twice the signed area of `a`, `b`, `c`: positive when they turn
+/// counter-clockwise.
+fn orient(a: DVec2, b: DVec2, c: DVec2) -> f64 {
+    (b - a).perp_dot(c - a)
+}
+
+/// Positive when `d` lies inside the circumcircle of the counter-clockwise
+/// triangle `a`, `b`, `c`.
+fn in_circle(a: DVec2, b: DVec2, c: DVec2, d: DVec2) -> f64 {
+    let (a, b, c) = (a - d, b - d, c - d);
+
+    a.length_squared() * b.perp_dot(c) - b.length_squared() * a.perp_dot(c) + c.length_squared() * a.perp_dot(b)
+}
+
+enum Location {
+    Inside(usize),
+    Edge(usize, usize),
+    Vertex(usize),
+}
+
+/// Segment being inserted, as the edges it crosses, or the point it passes
+/// through first.
+enum Crossing {
+    Edges(Vec<(usize, usize)>),
+    Point(usize),
+    Blocked,
+}
+
+/// Triangles with, for each edge `i` from `vertices[i]` to `vertices[i + 1]`,
+/// the triangle on its other side and whether it is a constraint.
+struct Mesh {
+    points: Vec<DVec2>,
+    vertices: Vec<[usize; 3]>,
+    neighbours: Vec<[Option<usize>; 3]>,
+    constrained: Vec<[bool; 3]>,
+    /// A triangle touching each point.
+    point_triangle: Vec<usize>,
+    /// Point each input point was merged into, itself unless it coincides
+    /// with an earlier one.
+    alias: Vec<usize>,
+    /// Triangle point location starts from.
+    last: usize,
+    /// Orientations below this are treated as collinear.
+    epsilon: f64,
+    /// Circumcircle tests below this are treated as cocircular.
+    circle_epsilon: f64,
+}
+
+impl Mesh {
+    fn new(points: &[DVec2]) -> Self {
+
+        let (min, max) = points.iter().fold((DVec2::MAX, DVec2::MIN), |(min, max), point| (min.min(*point), max.max(*point)));
+        let size = (max - min).max_element().max(1.);
+        let middle = (min + max) / 2.;
+
+        let mut all = vec![
+            middle + DVec2::new(-20. * size, -10. * size),
+            middle + DVec2::new(20. * size, -10. * size),
+            middle + DVec2::new(0., 20. * size),
+        ];
+        all.extend_from_slice(points);
+
+        Self {
+            point_triangle: vec![0; all.len()],
+            alias: (0..all.len()).collect(),
+            points: all,
+            vertices: vec![[0, 1, 2]],
+            neighbours: vec![[None; 3]],
+            constrained: vec![[false; 3]],
+            last: 0,
+            epsilon: 1e-12 * size * size,
+            circle_epsilon: 1e-12 * size.powi(4),
+        }
+    }
+
+    fn point(&self, triangle: usize, corner: usize) -> DVec2 {
+        self.points[self.vertices[triangle][corner % 3]]
+    }
+
+    fn set(&mut self, triangle: usize, vertices: [usize; 3], neighbours: [Option<usize>; 3], constrained: [bool; 3]) {
+
+        if triangle == self.vertices.len() {
+            self.vertices.push(vertices);
+            self.neighbours.push(neighbours);
+            self.constrained.push(constrained);
+        } else {
+            self.vertices[triangle] = vertices;
+            self.neighbours[triangle] = neighbours;
+            self.constrained[triangle] = constrained;
+        }
+
+        for vertex in vertices {
+            self.point_triangle[vertex] = triangle;
+        }
+
+        self.last = triangle;
+    }
+
+    /// Points `triangle`'s neighbour across the edge it shared with `old` to
+    /// `new`.
+    fn relink(&mut self, triangle: Option<usize>, old: usize, new: usize) {
+        let Some(triangle) = triangle else {
+            return;
+        };
+
+        for neighbour in &mut self.neighbours[triangle] {
+            if *neighbour == Some(old) {
+                *neighbour = Some(new);
+            }
+        }
+    }
+
+    /// Edge of `triangle` going from `from` to `to`.
+    fn edge_index(&self, triangle: usize, from: usize, to: usize) -> Option<usize> {
+        (0..3).find(|&edge| self.vertices[triangle][edge] == from && self.vertices[triangle][(edge + 1) % 3] == to)
+    }
+
+    fn locate(&self, point: DVec2) -> Location {
+
+        let mut triangle = self.last;
+        let mut steps = 0;
+
+        'walk: loop {
+            let mut on_edges = Vec::new();
+            steps += 1;
+
+            for edge in 0..3 {
+                let side = orient(self.point(triangle, edge), self.point(triangle, edge + 1), point);
+
+                if side < -self.epsilon {
+                    if let Some(next) = self.neighbours[triangle][edge].filter(|_| steps <= self.vertices.len()) {
+                        triangle = next;
+                        continue 'walk;
+                    }
+                } else if side <= self.epsilon {
+                    on_edges.push(edge);
+                }
+            }
+
+            return match on_edges[..] {
+                [] => Location::Inside(triangle),
+                [edge] => Location::Edge(triangle, edge),
+                // On two edges: the point shared by both.
+                [first, second] if (first + 1) % 3 == second => Location::Vertex(self.vertices[triangle][second]),
+                [first, _] => Location::Vertex(self.vertices[triangle][first]),
+                _ => Location::Vertex(self.vertices[triangle][0]),
+            };
+        }
+    }
+
+    fn insert_point(&mut self, point: usize) {
+
+        match self.locate(self.points[point]) {
+            Location::Inside(triangle) => self.split_triangle(triangle, point),
+            Location::Edge(triangle, edge) => self.split_edge(triangle, edge, point),
+            Location::Vertex(vertex) => self.alias[point] = vertex,
+        }
+    }
+
+    fn split_triangle(&mut self, triangle: usize, point: usize) {
+
+        let [a, b, c] = self.vertices[triangle];
+        let [ab, bc, ca] = self.neighbours[triangle];
+        let [ab_constrained, bc_constrained, ca_constrained] = self.constrained[triangle];
+        let (second, third) = (self.vertices.len(), self.vertices.len() + 1);
+
+        self.set(triangle, [a, b, point], [ab, Some(second), Some(third)], [ab_constrained, false, false]);
+        self.set(second, [b, c, point], [bc, Some(third), Some(triangle)], [bc_constrained, false, false]);
+        self.set(third, [c, a, point], [ca, Some(triangle), Some(second)], [ca_constrained, false, false]);
+
+        self.relink(bc, triangle, second);
+        self.relink(ca, triangle, third);
+
+        self.legalize(vec![(triangle, 0), (second, 0), (third, 0)]);
+    }
+
+    fn split_edge(&mut self, triangle: usize, edge: usize, point: usize) {
+
+        let (a, b, c) = (self.vertices[triangle][edge], self.vertices[triangle][(edge + 1) % 3], self.vertices[triangle][(edge + 2) % 3]);
+        let (bc, ca) = (self.neighbours[triangle][(edge + 1) % 3], self.neighbours[triangle][(edge + 2) % 3]);
+        let (ab_constrained, bc_constrained, ca_constrained) = (self.constrained[triangle][edge], self.constrained[triangle][(edge + 1) % 3], self.constrained[triangle][(edge + 2) % 3]);
+
+        let Some(other) = self.neighbours[triangle][edge] else {
+            return;
+        };
+
+        let other_edge = self.edge_index(other, b, a).expect("neighbours share the edge");
+        let d = self.vertices[other][(other_edge + 2) % 3];
+        let (ad, db) = (self.neighbours[other][(other_edge + 1) % 3], self.neighbours[other][(other_edge + 2) % 3]);
+        let (ad_constrained, db_constrained) = (self.constrained[other][(other_edge + 1) % 3], self.constrained[other][(other_edge + 2) % 3]);
+
+        let (second, other_second) = (self.vertices.len(), self.vertices.len() + 1);
+
+        self.set(triangle, [a, point, c], [Some(other_second), Some(second), ca], [ab_constrained, false, ca_constrained]);
+        self.set(second, [point, b, c], [Some(other), bc, Some(triangle)], [ab_constrained, bc_constrained, false]);
+        self.set(other, [b, point, d], [Some(second), Some(other_second), db], [ab_constrained, false, db_constrained]);
+        self.set(other_second, [point, a, d], [Some(triangle), ad, Some(other)], [ab_constrained, ad_constrained, false]);
+
+        self.relink(bc, triangle, second);
+        self.relink(ad, other, other_second);
+
+        self.legalize(vec![(triangle, 2), (second, 1), (other, 2), (other_second, 1)]);
+    }
+
+    /// Flips the edge `edge` of `triangle`, whose vertices `a`, `b`, `c` become
+    /// `c`, `a`, `d` while the neighbour's become `d`, `b`, `c`, `d` being the
+    /// neighbour's vertex across the edge.
+    fn flip(&mut self, triangle: usize, edge: usize) -> (usize, usize) {
+
+        let (a, b, c) = (self.vertices[triangle][edge], self.vertices[triangle][(edge + 1) % 3], self.vertices[triangle][(edge + 2) % 3]);
+        let (bc, ca) = (self.neighbours[triangle][(edge + 1) % 3], self.neighbours[triangle][(edge + 2) % 3]);
+        let (bc_constrained, ca_constrained) = (self.constrained[triangle][(edge + 1) % 3], self.constrained[triangle][(edge + 2) % 3]);
+
+        let other = self.neighbours[triangle][edge].expect("flipped edges are inner edges");
+        let other_edge = self.edge_index(other, b, a).expect("neighbours share the edge");
+        let d = self.vertices[other][(other_edge + 2) % 3];
+        let (ad, db) = (self.neighbours[other][(other_edge + 1) % 3], self.neighbours[other][(other_edge + 2) % 3]);
+        let (ad_constrained, db_constrained) = (self.constrained[other][(other_edge + 1) % 3], self.constrained[other][(other_edge + 2) % 3]);
+
+        self.set(triangle, [c, a, d], [ca, ad, Some(other)], [ca_constrained, ad_constrained, false]);
+        self.set(other, [d, b, c], [db, bc, Some(triangle)], [db_constrained, bc_constrained, false]);
+
+        self.relink(ad, other, triangle);
+        self.relink(bc, triangle, other);
+
+        (c, d)
+    }
+
+    /// Whether the edge `edge` of `triangle` fails the Delaunay condition.
+    fn is_illegal(&self, triangle: usize, edge: usize) -> bool {
+
+        if self.constrained[triangle][edge] {
+            return false;
+        }
+
+        let Some(other) = self.neighbours[triangle][edge] else {
+            return false;
+        };
+
+        let (a, b) = (self.vertices[triangle][edge], self.vertices[triangle][(edge + 1) % 3]);
+        let Some(other_edge) = self.edge_index(other, b, a) else {
+            return false;
+        };
+        let d = self.points[self.vertices[other][(other_edge + 2) % 3]];
+
+        in_circle(self.point(triangle, edge), self.point(triangle, edge + 1), self.point(triangle, edge + 2), d) > self.circle_epsilon
+    }
+
+    /// Flips the given edges, each facing the point just inserted, and those
+    /// behind them until the triangulation is Delaunay again.
+    fn legalize(&mut self, mut stack: Vec<(usize, usize)>) {
+
+        while let Some((triangle, edge)) = stack.pop() {
+            if !self.is_illegal(triangle, edge) {
+                continue;
+            }
+
+            let other = self.neighbours[triangle][edge].expect("illegal edges are inner edges");
+            self.flip(triangle, edge);
+
+            // The inserted point is the first vertex of `triangle` and the
+            // last of `other`.
+            stack.push((triangle, 1));
+            stack.push((other, 0));
+        }
+    }
+
+    /// Triangles around `point`, each with the corner `point` is.
+    fn fan(&self, point: usize) -> Vec<(usize, usize)> {
+
+        let start = self.point_triangle[point];
+        let mut fan = Vec::new();
+        let mut triangle = start;
+
+        while let Some(corner) = self.vertices[triangle].iter().position(|&vertex| vertex == point) {
+            fan.push((triangle, corner));
+
+            match self.neighbours[triangle][(corner + 2) % 3] {
+                Some(next) if next != start && fan.len() <= self.vertices.len() => triangle = next,
+                _ => break,
+            }
+        }
+
+        fan
+    }
+
+    /// Triangle and edge between `a` and `b`, in either direction.
+    fn find_edge(&self, a: usize, b: usize) -> Option<(usize, usize)> {
+        self.fan(a).into_iter().find_map(|(triangle, corner)| {
+            match self.vertices[triangle] {
+                vertices if vertices[(corner + 1) % 3] == b => Some((triangle, corner)),
+                vertices if vertices[(corner + 2) % 3] == b => Some((triangle, (corner + 2) % 3)),
+                _ => None,
+            }
+        })
+    }
+
+    fn set_constrained(&mut self, triangle: usize, edge: usize) {
+
+        self.constrained[triangle][edge] = true;
+
+        let (a, b) = (self.vertices[triangle][edge], self.vertices[triangle][(edge + 1) % 3]);
+
+        if let Some(other) = self.neighbours[triangle][edge] {
+            if let Some(other_edge) = self.edge_index(other, b, a) {
+                self.constrained[other][other_edge] = true;
+            }
+        }
+    }
+
+    fn insert_segment(&mut self, a: usize, b: usize) {
+
+        let mut pending = vec![(self.alias[a], self.alias[b])];
+
+        while let Some((a, b)) = pending.pop() {
+            if a == b {
+                continue;
+            }
+
+            if let Some((triangle, edge)) = self.find_edge(a, b) {
+                self.set_constrained(triangle, edge);
+                continue;
+            }
+
+            match self.crossing(a, b) {
+                Crossing::Point(point) => pending.extend([(a, point), (point, b)]),
+                Crossing::Edges(edges) => self.force_edge(a, b, edges),
+                Crossing::Blocked => {},
+            }
+        }
+    }
+
+    /// Edges crossed by the segment from `a` to `b`, each as its vertices on
+    /// the right and on the left of the segment.
+    fn crossing(&self, a: usize, b: usize) -> Crossing {
+
+        let (start, end) = (self.points[a], self.points[b]);
+        let ahead = |point: usize| (self.points[point] - start).dot(end - start) > 0.;
+
+        let mut first = None;
+
+        for (triangle, corner) in self.fan(a) {
+            let (right, left) = (self.vertices[triangle][(corner + 1) % 3], self.vertices[triangle][(corner + 2) % 3]);
+            let (right_side, left_side) = (orient(start, end, self.points[right]), orient(start, end, self.points[left]));
+
+            for (point, side) in [(right, right_side), (left, left_side)] {
+                if side.abs() <= self.epsilon && ahead(point) {
+                    return Crossing::Point(point);
+                }
+            }
+
+            if right_side < -self.epsilon && left_side > self.epsilon {
+                first = Some((triangle, (corner + 1) % 3));
+                break;
+            }
+        }
+
+        let Some((mut triangle, mut edge)) = first else {
+            return Crossing::Blocked;
+        };
+
+        let mut edges = Vec::new();
+
+        loop {
+            if self.constrained[triangle][edge] {
+                return Crossing::Blocked;
+            }
+
+            let (right, left) = (self.vertices[triangle][edge], self.vertices[triangle][(edge + 1) % 3]);
+            edges.push((right, left));
+
+            let Some(other) = self.neighbours[triangle][edge] else {
+                return Crossing::Blocked;
+            };
+
+            let Some(other_edge) = self.edge_index(other, left, right) else {
+                return Crossing::Blocked;
+            };
+
+            let far = self.vertices[other][(other_edge + 2) % 3];
+
+            if far == b {
+                return Crossing::Edges(edges);
+            }
+
+            let side = orient(start, end, self.points[far]);
+
+            if side.abs() <= self.epsilon {
+                return Crossing::Point(far);
+            }
+
+            // The segment leaves through the edge between the far point and
+            // the vertex on the other side.
+            (triangle, edge) = match side > 0. {
+                true => (other, (other_edge + 1) % 3),
+                false => (other, (other_edge + 2) % 3),
+            };
+        }
+    }
+
+    /// Flips the `crossed` edges until the segment from `a` to `b` is an edge,
+    /// then restores the Delaunay condition around it.
+    fn force_edge(&mut self, a: usize, b: usize, crossed: Vec<(usize, usize)>) {
+
+        let (start, end) = (self.points[a], self.points[b]);
+        let crosses = |mesh: &Self, c: usize, d: usize| {
+            c != a && c != b && d != a && d != b
+                && orient(start, end, mesh.points[c]) * orient(start, end, mesh.points[d]) < 0.
+        };
+
+        let limit = 4 * crossed.len() * crossed.len() + 16;
+        let mut queue: VecDeque<(usize, usize)> = crossed.into();
+        let mut created = Vec::new();
+        let mut attempts = 0;
+
+        while let Some((u, v)) = queue.pop_front() {
+            attempts += 1;
+
+            if attempts > limit {
+                return;
+            }
+
+            let Some((triangle, edge)) = self.find_edge(u, v) else {
+                continue;
+            };
+
+            let Some(other) = self.neighbours[triangle][edge] else {
+                continue;
+            };
+
+            let (from, to) = (self.vertices[triangle][edge], self.vertices[triangle][(edge + 1) % 3]);
+            let c = self.vertices[triangle][(edge + 2) % 3];
+            let Some(other_edge) = self.edge_index(other, to, from) else {
+                continue;
+            };
+            let d = self.vertices[other][(other_edge + 2) % 3];
+
+            // Only the diagonal of a strictly convex quadrilateral can be
+            // flipped.
+            let (pc, pd) = (self.points[c], self.points[d]);
+            let is_convex = orient(pc, pd, self.points[from]) * orient(pc, pd, self.points[to]) < -self.epsilon * self.epsilon;
+
+            if !is_convex {
+                queue.push_back((u, v));
+                continue;
+            }
+
+            let (c, d) = self.flip(triangle, edge);
+
+            match crosses(self, c, d) {
+                true => queue.push_back((c, d)),
+                false => created.push((c, d)),
+            }
+        }
+
+        if let Some((triangle, edge)) = self.find_edge(a, b) {
+            self.set_constrained(triangle, edge);
+        }
+
+        for _ in 0..limit {
+            let mut flipped = false;
+
+            for edge in &mut created {
+                let Some((triangle, index)) = self.find_edge(edge.0, edge.1) else {
+                    continue;
+                };
+
+                if self.is_illegal(triangle, index) {
+                    *edge = self.flip(triangle, index);
+                    flipped = true;
+                }
+            }
+
+            if !flipped {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_edge(triangles: &[[usize; 3]], a: usize, b: usize) -> bool {
+        triangles.iter().any(|triangle| (0..3).any(|edge| {
+            let (from, to) = (triangle[edge], triangle[(edge + 1) % 3]);
+            (from, to) == (a, b) || (from, to) == (b, a)
+        }))
+    }
+
+    #[test]
+    fn segments_become_edges() {
+
+        // A long thin wall across a grid of points, which a plain Delaunay
+        // triangulation cuts through.
+        let mut points: Vec<DVec2> = (0..10)
+            .flat_map(|x| (0..10).map(move |y| DVec2::new(x as f64 * 10., y as f64 * 10.)))
+            .collect();
+
+        let wall = [DVec2::new(3., 44.), DVec2::new(87., 46.), DVec2::new(87., 46.5), DVec2::new(3., 44.5)];
+        let first = points.len();
+        points.extend(wall);
+
+        let segments: Vec<(usize, usize)> = (0..4).map(|side| (first + side, first + (side + 1) % 4)).collect();
+        let triangles = triangulate(&points, &segments);
+
+        for &(a, b) in &segments {
+            assert!(has_edge(&triangles, a, b), "missing edge {a} {b}");
+        }
+
+        let area: f64 = triangles.iter()
+            .map(|&[a, b, c]| orient(points[a], points[b], points[c]))
+            .inspect(|&area| assert!(area > 0., "triangle is not counter-clockwise"))
+            .sum();
+
+        assert!((area / 2. - 90. * 90.).abs() < 1e-6, "triangles cover {area}");
+    }
+
+    #[test]
+    fn crossing_outlines_are_split() {
+
+        let outlines = vec![
+            vec![Vec2::new(0., 0.), Vec2::new(10., 0.), Vec2::new(10., 10.), Vec2::new(0., 10.)],
+            vec![Vec2::new(5., 5.), Vec2::new(15., 5.), Vec2::new(15., 15.), Vec2::new(5., 15.)],
+        ];
+
+        let (points, segments) = planar_segments(&outlines);
+
+        // The squares cross at (10, 5) and (5, 10).
+        assert_eq!(points.len(), 10);
+        assert_eq!(segments.len(), 12);
+
+        let triangles = triangulate(&points, &segments);
+
+        for &(a, b) in &segments {
+            assert!(has_edge(&triangles, a, b), "missing edge {a} {b}");
+        }
+    }
+}
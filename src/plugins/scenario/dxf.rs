@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use bevy::math::Vec2;
+
+use crate::components::Shape;
+
+use super::models::{ImportedPlan, LayerRole, ObjectiveDescription, ObstacleDescription, PlanImport, SpawnRegion};
+
+/// Segments a full circle is flattened into.
+const CIRCLE_SIDES: usize = 32;
+
+/// Entities that belong to a `POLYLINE` or `INSERT`, already reported with it.
+const SUBENTITIES: [&str; 3] = ["VERTEX", "SEQEND", "ATTRIB"];
+
+/// Turns the `LINE`, `LWPOLYLINE`, `CIRCLE` and `ARC` entities of an ASCII
+/// DXF drawing into scenario entries, according to the role of their layer.
+/// Other entities on the imported layers are reported in the warnings of the
+/// plan.
+///
+/// A drawing point `p` lands on `import.origin + import.scale * p`.
+pub fn import_dxf(text: &str, import: &PlanImport) -> Result<ImportedPlan> {
+
+    if text.starts_with("AutoCAD Binary DXF") {
+        bail!("binary DXF is not supported, save the drawing as ASCII DXF");
+    }
+
+    let mut plan = ImportedPlan::default();
+    let mut warnings: BTreeMap<String, usize> = BTreeMap::new();
+    let mut areas: BTreeMap<(String, LayerRole), Vec<(Vec2, Shape)>> = BTreeMap::new();
+
+    let thickness = |width: Option<f32>| import.wall_thickness.or(width.filter(|width| *width > 0.)).unwrap_or(1.) * import.scale;
+    let world = |point: Vec2| import.origin + import.scale * point;
+
+    for entity in entities(text)? {
+        let layer = entity.value(8).unwrap_or("0");
+
+        let Some(role) = layer_role(import, layer) else {
+            continue;
+        };
+
+        if SUBENTITIES.contains(&entity.kind.as_str()) {
+            continue;
+        }
+
+        let solid = role != LayerRole::Wall;
+
+        let shape = match entity.kind.as_str() {
+            "LINE" => entity.point(10).zip(entity.point(11))
+                .and_then(|(start, end)| Shape::from_outline(vec![world(start), world(end)], false, false, thickness(None))),
+            "CIRCLE" => match (entity.point(10), entity.number(40)) {
+                (Some(center), Some(radius)) if radius > 0. && solid => Some((world(entity.to_wcs(center)), Shape::Circle(radius * import.scale))),
+                (Some(center), Some(radius)) if radius > 0. => {
+                    let points = arc(center, radius, 0., 360.).into_iter().map(|point| world(entity.to_wcs(point))).collect();
+                    Shape::from_outline(points, false, true, thickness(None))
+                },
+                _ => None,
+            },
+            "ARC" => match (entity.point(10), entity.number(40), entity.number(50), entity.number(51)) {
+                (Some(center), Some(radius), Some(start), Some(end)) if radius > 0. => {
+                    let points = arc(center, radius, start, end).into_iter().map(|point| world(entity.to_wcs(point))).collect();
+                    Shape::from_outline(points, false, false, thickness(None))
+                },
+                _ => None,
+            },
+            "LWPOLYLINE" => {
+                let closed = entity.number(70).is_some_and(|flags| flags as i32 & 1 != 0);
+                let points = lwpolyline(&entity, closed).into_iter().map(|point| world(entity.to_wcs(point))).collect();
+                Shape::from_outline(points, solid && closed, closed, thickness(entity.number(43)))
+            },
+            kind => {
+                let hint = match kind {
+                    "INSERT" => ", explode the block references",
+                    "POLYLINE" => ", convert them to LWPOLYLINE",
+                    _ => "",
+                };
+
+                *warnings.entry(format!("unsupported {kind} entities on layer `{layer}`{hint}")).or_default() += 1;
+                continue;
+            },
+        };
+
+        let Some(shape) = shape else {
+            *warnings.entry(format!("degenerate {} entities on layer `{layer}`", entity.kind)).or_default() += 1;
+            continue;
+        };
+
+        match role {
+            LayerRole::Wall | LayerRole::Obstacle => plan.obstacles.push(ObstacleDescription { name: None, position: shape.0, shape: shape.1 }),
+            LayerRole::Exit | LayerRole::Spawn => areas.entry((layer.to_owned(), role)).or_default().push(shape),
+        }
+    }
+
+    // Exits and spawn regions are named after their layer, numbered when the
+    // layer has several.
+    for ((layer, role), shapes) in areas {
+        let count = shapes.len();
+
+        for (index, (position, shape)) in shapes.into_iter().enumerate() {
+            let name = match count {
+                1 => layer.clone(),
+                _ => format!("{layer}-{}", index + 1),
+            };
+
+            match role {
+                LayerRole::Exit => plan.objectives.push(ObjectiveDescription { name, position, shape, follow: None, sink: None }),
+                _ => plan.spawn_regions.push(SpawnRegion { name, position, shape }),
+            }
+        }
+    }
+
+    plan.warnings = warnings.into_iter()
+        .map(|(warning, count)| format!("{count} {warning}"))
+        .collect();
+
+    Ok(plan)
+}
+
+/// Role of the entities of `layer`. Without roles, the entities of the
+/// selected layers are obstacles.
+fn layer_role(import: &PlanImport, layer: &str) -> Option<LayerRole> {
+
+    if import.roles.is_empty() {
+        let selected = import.layers.is_empty() || import.layers.iter().any(|name| name.eq_ignore_ascii_case(layer));
+        return selected.then_some(LayerRole::Obstacle);
+    }
+
+    import.roles.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(layer))
+        .map(|(_, role)| *role)
+}
+
+/// Entity of the drawing with its group codes.
+struct DxfEntity {
+    kind: String,
+    groups: Vec<(i32, String)>,
+}
+
+impl DxfEntity {
+    fn value(&self, code: i32) -> Option<&str> {
+        self.groups.iter().find(|(group, _)| *group == code).map(|(_, value)| value.as_str())
+    }
+
+    fn number(&self, code: i32) -> Option<f32> {
+        self.value(code).and_then(|value| value.parse().ok())
+    }
+
+    /// Point whose x has group code `code` and y `code + 10`.
+    fn point(&self, code: i32) -> Option<Vec2> {
+        Some(Vec2::new(self.number(code)?, self.number(code + 10)?))
+    }
+
+    /// Circles, arcs and polylines are drawn in the entity's coordinate
+    /// system, mirrored when its extrusion direction points down.
+    fn to_wcs(&self, point: Vec2) -> Vec2 {
+        match self.number(230).is_some_and(|z| z < 0.) {
+            true => Vec2::new(-point.x, point.y),
+            false => point,
+        }
+    }
+}
+
+/// Entities of the `ENTITIES` section. Files cut short, in the middle of a
+/// group or before the section ends, are rejected.
+fn entities(text: &str) -> Result<Vec<DxfEntity>> {
+
+    let mut lines: Vec<&str> = text.lines().map(str::trim).collect();
+
+    // A blank line after the last group is not a group code.
+    if lines.len() % 2 == 1 && lines.last() == Some(&"") {
+        lines.pop();
+    }
+
+    let mut entities = Vec::new();
+    let mut section = None;
+    let mut found = false;
+
+    for (index, pair) in lines.chunks(2).enumerate() {
+        let Ok(code) = pair[0].parse::<i32>() else {
+            bail!("line {}: expected a group code, found `{}`", 2 * index + 1, pair[0]);
+        };
+
+        let Some(&value) = pair.get(1) else {
+            bail!("line {}: truncated file, group code {code} has no value", 2 * index + 1);
+        };
+
+        match (code, section) {
+            (0, _) if value == "ENDSEC" => section = None,
+            (0, None) if value == "SECTION" => section = Some(""),
+            (2, Some("")) => {
+                section = Some(if value == "ENTITIES" { "ENTITIES" } else { "other" });
+                found |= value == "ENTITIES";
+            },
+            (0, Some("ENTITIES")) => entities.push(DxfEntity { kind: value.to_owned(), groups: Vec::new() }),
+            (_, Some("ENTITIES")) => {
+                if let Some(entity) = entities.last_mut() {
+                    entity.groups.push((code, value.to_owned()));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    if !found {
+        bail!("the drawing has no ENTITIES section");
+    }
+
+    if section == Some("ENTITIES") {
+        bail!("truncated file, the ENTITIES section does not end");
+    }
+
+    Ok(entities)
+}
+
+/// Points along the arc from `start` to `end` degrees, counterclockwise.
+fn arc(center: Vec2, radius: f32, start: f32, end: f32) -> Vec<Vec2> {
+
+    let sweep = (end - start).rem_euclid(360.);
+    let sweep = if sweep == 0. { 360. } else { sweep };
+
+    sweep_points(center, radius, start.to_radians(), sweep.to_radians())
+}
+
+/// Vertices of a light polyline, with its bulged segments flattened into
+/// arcs.
+fn lwpolyline(entity: &DxfEntity, closed: bool) -> Vec<Vec2> {
+
+    let mut vertices: Vec<(Vec2, f32)> = Vec::new();
+
+    for (code, value) in &entity.groups {
+        let value = value.parse().unwrap_or(0.);
+
+        match (code, vertices.last_mut()) {
+            (10, _) => vertices.push((Vec2::new(value, 0.), 0.)),
+            (20, Some((vertex, _))) => vertex.y = value,
+            (42, Some((_, bulge))) => *bulge = value,
+            _ => {},
+        }
+    }
+
+    let mut points = Vec::new();
+    let segments = if closed { vertices.len() } else { vertices.len().saturating_sub(1) };
+
+    for index in 0..segments {
+        let (start, bulge) = vertices[index];
+        let end = vertices[(index + 1) % vertices.len()].0;
+
+        points.push(start);
+
+        if bulge.abs() > 1e-6 && start != end {
+            // The bulge is the tangent of a quarter of the arc's angle,
+            // positive counterclockwise.
+            let angle = 4. * bulge.atan();
+            let center = (start + end) / 2. + (end - start).perp() * (1. - bulge * bulge) / (4. * bulge);
+
+            let mut arc = sweep_points(center, start.distance(center), (start - center).to_angle(), angle);
+            arc.remove(0);
+            arc.pop();
+            points.extend(arc);
+        }
+    }
+
+    if !closed {
+        points.extend(vertices.last().map(|(vertex, _)| *vertex));
+    }
+
+    points
+}
+
+/// Points from angle `start` over a signed `sweep`, both in radians, ends
+/// included.
+fn sweep_points(center: Vec2, radius: f32, start: f32, sweep: f32) -> Vec<Vec2> {
+
+    let steps = ((sweep.abs() / std::f32::consts::TAU * CIRCLE_SIDES as f32).ceil() as usize).max(2);
+
+    (0..=steps)
+        .map(|step| center + radius * Vec2::from_angle(start + sweep * step as f32 / steps as f32))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(roles: &[(&str, LayerRole)]) -> PlanImport {
+        PlanImport {
+            path: "plan.dxf".into(),
+            scale: 1.,
+            origin: Vec2::ZERO,
+            layers: Vec::new(),
+            roles: roles.iter().map(|(layer, role)| (layer.to_string(), *role)).collect(),
+            styles: Vec::new(),
+            wall_thickness: None,
+        }
+    }
+
+    /// Entity of `kind` on `layer` with numeric groups.
+    fn entity(kind: &str, layer: &str, groups: &[(i32, f32)]) -> String {
+        groups.iter().fold(format!("  0\n{kind}\n  8\n{layer}\n"), |text, (code, value)| text + &format!("{code:>3}\n{value}\n"))
+    }
+
+    fn drawing(entities: &[String]) -> String {
+        format!("  0\nSECTION\n  2\nHEADER\n  0\nENDSEC\n  0\nSECTION\n  2\nENTITIES\n{}  0\nENDSEC\n  0\nEOF\n", entities.concat())
+    }
+
+    fn square(layer: &str) -> String {
+        entity("LWPOLYLINE", layer, &[(90, 4.), (70, 1.), (10, 0.), (20, 0.), (10, 2.), (20, 0.), (10, 2.), (20, 2.), (10, 0.), (20, 2.)])
+    }
+
+    /// World points of an imported line.
+    fn line_points(position: Vec2, shape: &Shape) -> Vec<Vec2> {
+        match shape {
+            Shape::Polyline { points, .. } => points.iter().map(|point| position + *point).collect(),
+            shape => panic!("{shape:?}"),
+        }
+    }
+
+    #[test]
+    fn layers_get_their_role() {
+
+        let text = drawing(&[
+            square("Walls"),
+            square("FURNITURE"),
+            entity("CIRCLE", "Exit", &[(10, 10.), (20, 0.), (40, 1.)]),
+            entity("CIRCLE", "Exit", &[(10, 20.), (20, 0.), (40, 1.)]),
+            entity("CIRCLE", "hall", &[(10, -10.), (20, 0.), (40, 3.)]),
+            entity("LINE", "Notes", &[(10, 0.), (20, 0.), (11, 1.), (21, 1.)]),
+        ]);
+
+        let plan = import_dxf(&text, &import(&[
+            ("WALLS", LayerRole::Wall),
+            ("Furniture", LayerRole::Obstacle),
+            ("Exit", LayerRole::Exit),
+            ("Hall", LayerRole::Spawn),
+        ])).unwrap();
+
+        let [wall, furniture] = &plan.obstacles[..] else {
+            panic!("{:?}", plan.obstacles);
+        };
+
+        // Walls stay lines even when closed, other obstacles are solid.
+        assert_eq!(line_points(wall.position, &wall.shape).len(), 5);
+        assert!(matches!(furniture.shape, Shape::Rectangle(size) if size == Vec2::splat(2.)));
+
+        let exits: Vec<_> = plan.objectives.iter().map(|objective| (objective.name.as_str(), objective.position)).collect();
+        assert_eq!(exits, [("Exit-1", Vec2::new(10., 0.)), ("Exit-2", Vec2::new(20., 0.))]);
+
+        assert_eq!(plan.spawn_regions.len(), 1);
+        assert_eq!(plan.spawn_regions[0].name, "hall");
+        assert!(matches!(plan.spawn_regions[0].shape, Shape::Circle(radius) if radius == 3.));
+
+        // Layers without a role are skipped without a warning.
+        assert!(plan.warnings.is_empty(), "{:?}", plan.warnings);
+
+        // Without roles, the selected layers are obstacles.
+        let mut import = import(&[]);
+        import.layers = vec!["notes".to_owned()];
+        let plan = import_dxf(&text, &import).unwrap();
+
+        assert_eq!(plan.obstacles.len(), 1);
+        assert!(plan.objectives.is_empty() && plan.spawn_regions.is_empty());
+    }
+
+    #[test]
+    fn bulges_become_arcs() {
+
+        let half_circle = |bulge: f32| {
+            let text = drawing(&[entity("LWPOLYLINE", "0", &[(90, 2.), (70, 0.), (10, 0.), (20, 0.), (42, bulge), (10, 2.), (20, 0.)])]);
+            let plan = import_dxf(&text, &import(&[])).unwrap();
+            line_points(plan.obstacles[0].position, &plan.obstacles[0].shape)
+        };
+
+        // A bulge of 1 is a half circle, counterclockwise from the start.
+        for (bulge, side) in [(1., -1.), (-1., 1.)] {
+            let points = half_circle(bulge);
+
+            assert_eq!(points.first(), Some(&Vec2::ZERO));
+            assert!(points.last().unwrap().abs_diff_eq(Vec2::new(2., 0.), 1e-4));
+            assert!(points.len() > 4);
+            assert!(points.iter().all(|point| (point.distance(Vec2::X) - 1.).abs() < 1e-4), "{points:?}");
+            assert!(points.iter().any(|point| point.abs_diff_eq(Vec2::new(1., side), 1e-4)), "bulge {bulge}: {points:?}");
+        }
+    }
+
+    #[test]
+    fn arcs_sweep_counterclockwise() {
+
+        let arc_points = |start: f32, end: f32| {
+            let text = drawing(&[entity("ARC", "0", &[(10, 0.), (20, 0.), (40, 1.), (50, start), (51, end)])]);
+            let plan = import_dxf(&text, &import(&[])).unwrap();
+            line_points(plan.obstacles[0].position, &plan.obstacles[0].shape)
+        };
+
+        // From 350° to 10° the arc goes through 0°, not around the circle.
+        let points = arc_points(350., 10.);
+        assert!(points.iter().all(|point| point.x > 0.98), "{points:?}");
+        assert!(points.iter().any(|point| point.abs_diff_eq(Vec2::X, 1e-3)));
+
+        // From 90° to 0° it goes the long way, through 180° and 270°.
+        let points = arc_points(90., 0.);
+        assert!(points.iter().any(|point| point.abs_diff_eq(Vec2::NEG_X, 1e-3)));
+        assert!(points.iter().any(|point| point.abs_diff_eq(Vec2::NEG_Y, 1e-3)));
+        assert!(!points.iter().any(|point| point.abs_diff_eq(Vec2::ONE.normalize(), 1e-2)));
+
+        // Equal angles make a full circle.
+        assert_eq!(arc_points(45., 45.).len(), CIRCLE_SIDES + 1);
+    }
+
+    #[test]
+    fn unsupported_entities_are_reported() {
+
+        let text = drawing(&[
+            entity("INSERT", "A", &[(10, 0.), (20, 0.)]),
+            entity("TEXT", "A", &[(10, 0.), (20, 0.)]),
+            entity("INSERT", "A", &[(10, 5.), (20, 0.)]),
+            entity("POLYLINE", "B", &[(70, 0.)]),
+            entity("VERTEX", "B", &[(10, 0.), (20, 0.)]),
+            entity("VERTEX", "B", &[(10, 1.), (20, 0.)]),
+            entity("SEQEND", "B", &[]),
+            entity("CIRCLE", "A", &[(10, 0.), (20, 0.), (40, 0.)]),
+            entity("TEXT", "C", &[(10, 0.), (20, 0.)]),
+        ]);
+
+        let mut import = import(&[]);
+        import.layers = vec!["A".to_owned(), "B".to_owned()];
+
+        let plan = import_dxf(&text, &import).unwrap();
+
+        assert!(plan.obstacles.is_empty());
+        assert_eq!(plan.warnings, [
+            "1 degenerate CIRCLE entities on layer `A`",
+            "2 unsupported INSERT entities on layer `A`, explode the block references",
+            "1 unsupported POLYLINE entities on layer `B`, convert them to LWPOLYLINE",
+            "1 unsupported TEXT entities on layer `A`",
+        ]);
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+
+        let error = |text: &str| import_dxf(text, &import(&[])).unwrap_err().to_string();
+        let text = drawing(&[square("0")]);
+
+        // A trailing blank line is fine, a missing value is not.
+        assert!(import_dxf(&format!("{text}\n"), &import(&[])).is_ok());
+        assert_eq!(error(&format!("{text}  0")), "line 39: truncated file, group code 0 has no value");
+
+        let cut = text.lines().take(30).collect::<Vec<_>>().join("\n");
+        assert_eq!(error(&cut), "truncated file, the ENTITIES section does not end");
+
+        assert_eq!(error("  0\nSECTION\n  2\nHEADER\n  0\nENDSEC\n  0\nEOF"), "the drawing has no ENTITIES section");
+        assert_eq!(error("  0\nSECTION\n  X\nHEADER"), "line 3: expected a group code, found `X`");
+        assert!(error("AutoCAD Binary DXF\r\n").starts_with("binary DXF is not supported"));
+    }
+}
@@ -0,0 +1,54 @@
+use std::path::Path;
+
+use anyhow::Context;
+use bevy::asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext};
+
+use super::models::Scenario;
+
+/// Loads scenario files as assets, so that they are reloaded when they or
+/// the plans they import are edited.
+#[derive(Default)]
+pub struct ScenarioLoader;
+
+impl AssetLoader for ScenarioLoader {
+    type Asset = Scenario;
+    type Settings = ();
+    type Error = anyhow::Error;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Scenario, anyhow::Error> {
+
+        let mut text = String::new();
+        reader.read_to_string(&mut text).await?;
+
+        let path = load_context.path().to_owned();
+        let mut scenario = Scenario::parse(&text, &path)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+
+        // Reading the plans through the context makes their edits reload the
+        // scenario too.
+        for import in scenario.imports.clone() {
+            let bytes = load_context.read_asset_bytes(directory.join(&import.path)).await?;
+            scenario.add_plan(&import, &bytes)?;
+        }
+
+        if let Some(floor_plan) = &scenario.floor_plan {
+            let floor_plan = directory.join(floor_plan);
+            let bytes = load_context.read_asset_bytes(floor_plan.clone()).await?;
+
+            scenario.add_floor_plan(&bytes, &floor_plan)?;
+        }
+
+        scenario.validate().with_context(|| format!("in {}", path.display()))?;
+
+        Ok(scenario)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron", "scenario"]
+    }
+}
@@ -0,0 +1,7 @@
+pub mod plugin;
+pub mod dxf;
+pub mod loader;
+pub mod models;
+pub mod resources;
+pub mod svg;
+pub mod systems;
@@ -0,0 +1,619 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bevy::{asset::{Asset, Handle}, ecs::{component::Component, entity::Entity}, math::{Rect, Vec2}, reflect::TypePath, sprite::{ColorMaterial, Mesh2dHandle}};
+use serde::Deserialize;
+
+use super::{dxf, svg};
+
+use crate::{components::{Distribution, ItineraryStop, RouteChoiceModel, Shape, Sink}, consts::AGENT_DESIRED_SPEED, plugins::flow_field_pathfinding::{bitmap::{cell_areas, decode_floor_plan}, resources::{NavigationClass, RouteChoiceSettings, WallAvoidance}}};
+
+/// Layout and population of a simulation, read from a RON file.
+#[derive(Asset, TypePath, Debug, Clone, Deserialize)]
+pub struct Scenario {
+    pub area: AreaDescription,
+    pub cell_size: f32,
+    #[serde(default)]
+    pub sector_size: Option<usize>,
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    /// Floor plan image, relative to the scenario file. Its exits and spawn
+    /// zones are added to the objectives and spawn regions.
+    #[serde(default)]
+    pub floor_plan: Option<PathBuf>,
+    /// Plans whose elements are added to the obstacles, objectives and spawn
+    /// regions.
+    #[serde(default)]
+    pub imports: Vec<PlanImport>,
+    #[serde(default)]
+    pub obstacles: Vec<ObstacleDescription>,
+    #[serde(default)]
+    pub zones: Vec<ZoneDescription>,
+    #[serde(default)]
+    pub objectives: Vec<ObjectiveDescription>,
+    #[serde(default)]
+    pub spawn_regions: Vec<SpawnRegion>,
+    #[serde(default)]
+    pub populations: Vec<Population>,
+    #[serde(default)]
+    pub parameters: ModelParameters,
+    /// Respawn the populations when the file is edited while the app runs.
+    /// Otherwise the agents already walking keep going.
+    #[serde(default)]
+    pub reset_agents_on_reload: bool,
+    /// What the plan imports left out, logged when the scenario is spawned.
+    #[serde(skip)]
+    pub import_warnings: Vec<String>,
+}
+
+/// Obstacle, zone, objective or source spawned from the scenario, replaced
+/// when the file is reloaded.
+#[derive(Component)]
+pub struct ScenarioEntity;
+
+/// Region spawning the agents of a population, both the initial ones and
+/// those arriving over time. Arrivals that find no free spot in the region
+/// wait for the following frames.
+#[derive(Component)]
+pub struct Source {
+    pub arrivals: Option<Arrivals>,
+    /// Total number of agents the source spawns, unlimited when `None`.
+    pub cap: Option<usize>,
+    pub spawned: usize,
+    /// Agents that arrived but have not been placed yet.
+    pub pending: usize,
+    /// Seconds until the next arrival.
+    pub next_arrival: f32,
+    pub template: AgentTemplate,
+}
+
+impl Source {
+    pub fn is_exhausted(&self) -> bool {
+        self.cap.is_some_and(|cap| self.spawned + self.pending >= cap)
+    }
+}
+
+/// Attributes of the agents spawned by a [`Source`], with the objectives
+/// already resolved.
+pub struct AgentTemplate {
+    pub desired_speed: Distribution,
+    pub class: Option<usize>,
+    pub destination: Option<Entity>,
+    pub route_choice: Option<RouteChoiceModel>,
+    pub stops: Vec<ItineraryStop>,
+    pub mesh: Mesh2dHandle,
+    pub material: Handle<ColorMaterial>,
+}
+
+/// Vector plan, such as an architect's SVG drawing, imported into the
+/// scenario.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlanImport {
+    /// Plan file, relative to the scenario file.
+    pub path: PathBuf,
+    /// World units per plan unit: the user units of an SVG drawing, the
+    /// drawing units of a DXF one.
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// World position of the plan's origin.
+    #[serde(default)]
+    pub origin: Vec2,
+    /// Ids or labels of the layers to import obstacles from, all when empty.
+    #[serde(default)]
+    pub layers: Vec<String>,
+    /// What the entities of each CAD layer become, for DXF plans. Layers
+    /// without a role are skipped, unless there are no roles at all and
+    /// `layers` selects them.
+    #[serde(default)]
+    pub roles: HashMap<String, LayerRole>,
+    /// Style properties, such as `stroke:#000000`, an element must have to
+    /// become an obstacle.
+    #[serde(default)]
+    pub styles: Vec<String>,
+    /// Thickness of the walls drawn as lines, in plan units. Their stroke
+    /// width, or polyline width in DXF, by default.
+    #[serde(default)]
+    pub wall_thickness: Option<f32>,
+}
+
+fn default_scale() -> f32 {
+    1.
+}
+
+/// Name of the `index`-th of `count` areas called `name`.
+fn numbered(name: &str, index: usize, count: usize) -> String {
+    match count {
+        1 => name.to_owned(),
+        _ => format!("{name}-{}", index + 1),
+    }
+}
+
+/// What the entities of a CAD layer become. Exits and spawn regions are
+/// named after their layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub enum LayerRole {
+    /// Lines of the wall thickness, even when closed.
+    Wall,
+    /// Solid when closed, lines otherwise.
+    Obstacle,
+    Exit,
+    Spawn,
+}
+
+/// Entries read from a plan.
+#[derive(Debug, Default)]
+pub struct ImportedPlan {
+    pub obstacles: Vec<ObstacleDescription>,
+    pub objectives: Vec<ObjectiveDescription>,
+    pub spawn_regions: Vec<SpawnRegion>,
+    /// Parts of the plan that were left out.
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct AreaDescription {
+    pub center: Vec2,
+    pub size: Vec2,
+}
+
+impl AreaDescription {
+    pub fn rect(&self) -> Rect {
+        Rect::from_center_size(self.center, self.size)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ObstacleDescription {
+    /// Lets objectives follow the obstacle.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub position: Vec2,
+    pub shape: Shape,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZoneDescription {
+    pub label: String,
+    pub position: Vec2,
+    pub shape: Shape,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ObjectiveDescription {
+    pub name: String,
+    pub position: Vec2,
+    pub shape: Shape,
+    #[serde(default)]
+    pub follow: Option<FollowDescription>,
+    /// Limits how fast agents get through. Agents leave unhindered otherwise.
+    #[serde(default)]
+    pub sink: Option<SinkDescription>,
+}
+
+/// Capacity of a sink objective.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum SinkDescription {
+    /// Door letting at most this many agents through per second.
+    Throughput(f32),
+    /// Turnstiles or gates, each letting one agent through at a time.
+    Gates { servers: usize, service_seconds: Distribution },
+}
+
+impl SinkDescription {
+    pub fn sink(&self) -> Sink {
+        match *self {
+            SinkDescription::Throughput(rate) => Sink::with_throughput(rate),
+            SinkDescription::Gates { servers, service_seconds } => Sink::new(servers, service_seconds),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match *self {
+            SinkDescription::Throughput(rate) => rate.is_finite() && rate > 0.,
+            SinkDescription::Gates { servers, .. } => servers > 0,
+        }
+    }
+}
+
+/// Attaches an objective to a named obstacle. Objectives cannot follow each
+/// other.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FollowDescription {
+    pub target: String,
+    #[serde(default)]
+    pub offset: Vec2,
+    pub update_seconds: f32,
+}
+
+/// Area the agents of a population are placed in.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpawnRegion {
+    pub name: String,
+    pub position: Vec2,
+    pub shape: Shape,
+}
+
+/// Group of agents sharing a spawn region, a way of choosing their
+/// destination and the distributions their parameters are drawn from.
+///
+/// Agents without a destination, route choice or itinerary follow the shared
+/// flow field.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Population {
+    pub spawn_region: String,
+    /// Agents placed when the simulation starts.
+    #[serde(default)]
+    pub count: usize,
+    /// Agents arriving afterwards.
+    #[serde(default)]
+    pub arrivals: Option<Arrivals>,
+    /// Total number of agents, including the initial ones.
+    #[serde(default)]
+    pub cap: Option<usize>,
+    #[serde(default = "default_desired_speed")]
+    pub desired_speed: Distribution,
+    /// Name of an entry of `parameters.classes`.
+    #[serde(default)]
+    pub class: Option<String>,
+    /// Name of the objective every agent heads to.
+    #[serde(default)]
+    pub destination: Option<String>,
+    #[serde(default)]
+    pub route_choice: Option<RouteChoiceDescription>,
+    #[serde(default)]
+    pub itinerary: Vec<StopDescription>,
+}
+
+fn default_desired_speed() -> Distribution {
+    Distribution::Constant(AGENT_DESIRED_SPEED)
+}
+
+/// How the agents of a population arrive over time.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum Arrivals {
+    /// `rate` agents per second at regular intervals.
+    Constant { rate: f32 },
+    /// Poisson process averaging `rate` agents per second.
+    Poisson { rate: f32 },
+    /// `size` agents at once every `interval_seconds`, such as a train
+    /// unloading. The first burst arrives after `offset_seconds`.
+    Bursts {
+        size: usize,
+        interval_seconds: f32,
+        #[serde(default)]
+        offset_seconds: f32,
+    },
+}
+
+impl Arrivals {
+    /// Seconds until the first arrival.
+    pub fn first_delay(&self, rng: &mut fastrand::Rng) -> f32 {
+        match *self {
+            Arrivals::Bursts { offset_seconds, .. } => offset_seconds,
+            _ => self.next_delay(rng),
+        }
+    }
+
+    /// Seconds between two arrivals.
+    pub fn next_delay(&self, rng: &mut fastrand::Rng) -> f32 {
+        match *self {
+            Arrivals::Constant { rate } => 1. / rate,
+            Arrivals::Poisson { rate } => -(1. - rng.f32()).ln() / rate,
+            Arrivals::Bursts { interval_seconds, .. } => interval_seconds,
+        }
+    }
+
+    /// Agents arriving together.
+    pub fn group_size(&self) -> usize {
+        match *self {
+            Arrivals::Bursts { size, .. } => size,
+            _ => 1,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match *self {
+            Arrivals::Constant { rate } | Arrivals::Poisson { rate } => rate.is_finite() && rate > 0.,
+            Arrivals::Bursts { interval_seconds, offset_seconds, .. } => interval_seconds.is_finite() && interval_seconds > 0. && offset_seconds >= 0.,
+        }
+    }
+}
+
+/// `RouteChoiceModel` with objectives referred to by name.
+#[derive(Debug, Clone, Deserialize)]
+pub enum RouteChoiceDescription {
+    Nearest,
+    Familiar { preferred: String, tolerance: f32 },
+    TravelTime,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StopDescription {
+    pub objective: String,
+    #[serde(default)]
+    pub dwell_seconds: f32,
+}
+
+/// Overrides of the model resources. Missing entries keep their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ModelParameters {
+    /// Seed of the parameter distributions and spawn positions.
+    pub seed: Option<u64>,
+    pub clearance: Option<f32>,
+    pub wall_avoidance: Option<WallAvoidance>,
+    pub look_ahead_cells: Option<usize>,
+    pub route_choice: Option<RouteChoiceSettings>,
+    pub classes: Vec<NavigationClass>,
+}
+
+impl ModelParameters {
+    pub fn rng(&self) -> fastrand::Rng {
+        self.seed.map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed)
+    }
+}
+
+impl Scenario {
+    /// Reads a scenario file and the plans it imports. Syntax errors are
+    /// reported as `path:line:column: message`.
+    pub fn load(path: &Path) -> Result<Self> {
+
+        let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+        let mut scenario = Self::parse(&text, path)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+
+        for import in scenario.imports.clone() {
+            let plan_path = directory.join(&import.path);
+            let bytes = fs::read(&plan_path).with_context(|| format!("reading {}", plan_path.display()))?;
+
+            scenario.add_plan(&import, &bytes)?;
+        }
+
+        if let Some(floor_plan) = &scenario.floor_plan {
+            let floor_plan = directory.join(floor_plan);
+            let bytes = fs::read(&floor_plan).with_context(|| format!("reading {}", floor_plan.display()))?;
+
+            scenario.add_floor_plan(&bytes, &floor_plan)?;
+            scenario.floor_plan = Some(floor_plan);
+        }
+
+        scenario.validate().with_context(|| format!("in {}", path.display()))?;
+
+        Ok(scenario)
+    }
+
+    /// Parses the content of the scenario file at `path`, which is only used
+    /// in error messages. The plans are not imported yet, so the result
+    /// still needs [`Scenario::validate`].
+    pub fn parse(text: &str, path: &Path) -> Result<Self> {
+        ron::from_str(text).map_err(|error| anyhow!("{}:{error}", path.display()))
+    }
+
+    /// Adds the entries of a plan, given the content of its file.
+    pub fn add_plan(&mut self, import: &PlanImport, bytes: &[u8]) -> Result<()> {
+
+        let context = || format!("importing {}", import.path.display());
+
+        let plan = match import.path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("svg") => {
+                let text = std::str::from_utf8(bytes).with_context(context)?;
+                svg::import_svg(text, import).with_context(context)?
+            },
+            // Drawings older than AutoCAD 2007 are not UTF-8, only their
+            // layer names may suffer.
+            Some(extension) if extension.eq_ignore_ascii_case("dxf") => {
+                dxf::import_dxf(&String::from_utf8_lossy(bytes), import).with_context(context)?
+            },
+            _ => bail!("{}: unsupported plan format", context()),
+        };
+
+        self.obstacles.extend(plan.obstacles);
+        self.objectives.extend(plan.objectives);
+        self.spawn_regions.extend(plan.spawn_regions);
+        self.import_warnings.extend(plan.warnings.into_iter()
+            .map(|warning| format!("{}: {warning}", import.path.display())));
+
+        Ok(())
+    }
+
+    /// Adds the exits of the floor plan as objectives and its spawn zones as
+    /// spawn regions, given the content of the image at `path`. Each group of
+    /// touching cells becomes one area, named `exit` or `spawn`, numbered
+    /// when the plan has several.
+    pub fn add_floor_plan(&mut self, bytes: &[u8], path: &Path) -> Result<()> {
+
+        // Invalid grids are reported by `validate`.
+        if self.cell_size <= 0. || self.area.size.min_element() <= 0. {
+            return Ok(());
+        }
+
+        let cells = (self.area.size / self.cell_size).ceil();
+        let plan = decode_floor_plan(bytes, path, cells.x as usize, cells.y as usize, self.area.rect(), None)
+            .with_context(|| format!("reading {}", path.display()))?;
+
+        let exits = cell_areas(&plan.exit_cells, &plan.obstacles);
+        let count = exits.len();
+
+        self.objectives.extend(exits.into_iter().enumerate().map(|(index, (position, shape))| ObjectiveDescription {
+            name: numbered("exit", index, count),
+            position,
+            shape,
+            follow: None,
+            sink: None,
+        }));
+
+        let spawn_zones = cell_areas(&plan.spawn_cells, &plan.obstacles);
+        let count = spawn_zones.len();
+
+        self.spawn_regions.extend(spawn_zones.into_iter().enumerate().map(|(index, (position, shape))| SpawnRegion {
+            name: numbered("spawn", index, count),
+            position,
+            shape,
+        }));
+
+        Ok(())
+    }
+
+    /// Layout used when no scenario file is given.
+    pub fn demo() -> Self {
+        ron::from_str(include_str!("../../../scenarios/demo.ron")).expect("the demo scenario is valid")
+    }
+
+    /// Checks the names the entries refer to each other by.
+    pub fn validate(&self) -> Result<()> {
+
+        if self.cell_size <= 0. || self.area.size.min_element() <= 0. {
+            bail!("the area and cell size must be positive");
+        }
+
+        let objective = |name: &str| -> Result<()> {
+            match self.objectives.iter().any(|objective| objective.name == name) {
+                true => Ok(()),
+                false => Err(anyhow!("unknown objective `{name}`")),
+            }
+        };
+
+        for description in &self.objectives {
+            if description.sink.is_some_and(|sink| !sink.is_valid()) {
+                bail!("objective `{}`: sink throughput and servers must be positive", description.name);
+            }
+
+            let Some(follow) = &description.follow else {
+                continue;
+            };
+
+            let is_named = |name: &Option<String>| name.as_deref() == Some(follow.target.as_str());
+
+            if self.obstacles.iter().any(|obstacle| is_named(&obstacle.name)) {
+                continue;
+            }
+
+            match objective(&follow.target) {
+                Ok(()) => bail!("objective `{}` follows objective `{}`, only obstacles can be followed", description.name, follow.target),
+                Err(_) => bail!("objective `{}` follows unknown obstacle `{}`", description.name, follow.target),
+            }
+        }
+
+        for region in &self.spawn_regions {
+            let area = region.shape.area();
+
+            if area.is_nan() || area <= 0. {
+                bail!("spawn region `{}` has no area", region.name);
+            }
+        }
+
+        for (index, population) in self.populations.iter().enumerate() {
+            let context = || format!("population {index}");
+
+            if !self.spawn_regions.iter().any(|region| region.name == population.spawn_region) {
+                bail!("{}: unknown spawn region `{}`", context(), population.spawn_region);
+            }
+
+            if population.arrivals.is_some_and(|arrivals| !arrivals.is_valid()) {
+                bail!("{}: arrival rates and intervals must be positive", context());
+            }
+
+            if let Some(class) = &population.class {
+                if !self.parameters.classes.iter().any(|navigation_class| &navigation_class.name == class) {
+                    bail!("{}: unknown class `{class}`", context());
+                }
+            }
+
+            if let Some(destination) = &population.destination {
+                objective(destination).with_context(context)?;
+            }
+
+            if let Some(RouteChoiceDescription::Familiar { preferred, .. }) = &population.route_choice {
+                objective(preferred).with_context(context)?;
+            }
+
+            for stop in &population.itinerary {
+                objective(&stop.objective).with_context(context)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scenario with one exit, one spawn region, a named pillar and the
+    /// given population.
+    fn scenario(population: &str) -> Scenario {
+        let text = format!(r#"Scenario(
+            area: (center: (0., 0.), size: (100., 100.)),
+            cell_size: 1.,
+            obstacles: [(name: Some("pillar"), position: (10., 0.), shape: Circle(2.))],
+            objectives: [(name: "exit", position: (40., 0.), shape: Circle(5.))],
+            spawn_regions: [(name: "west", position: (-40., 0.), shape: Circle(10.))],
+            populations: [{population}],
+        )"#);
+
+        Scenario::parse(&text, Path::new("test.ron")).unwrap()
+    }
+
+    fn error(scenario: &Scenario) -> String {
+        format!("{:#}", scenario.validate().unwrap_err())
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_file_position() {
+
+        let text = "Scenario(\n    area: (center: (0., 0.), size: (100., 100.)),\n    cell_size: 1.,,\n)";
+        let message = Scenario::parse(text, Path::new("scenarios/broken.ron")).unwrap_err().to_string();
+
+        assert_eq!(message, "scenarios/broken.ron:3:19: Expected identifier");
+    }
+
+    #[test]
+    fn valid_scenarios_pass() {
+        scenario(r#"(spawn_region: "west", count: 5, destination: Some("exit"), itinerary: [(objective: "exit")])"#).validate().unwrap();
+        Scenario::demo().validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_spawn_regions_are_rejected() {
+
+        let message = error(&scenario(r#"(spawn_region: "east", count: 5)"#));
+
+        assert_eq!(message, "population 0: unknown spawn region `east`");
+    }
+
+    #[test]
+    fn unknown_objectives_are_rejected() {
+
+        for population in [
+            r#"(spawn_region: "west", destination: Some("entrance"))"#,
+            r#"(spawn_region: "west", route_choice: Some(Familiar(preferred: "entrance", tolerance: 1.)))"#,
+            r#"(spawn_region: "west", itinerary: [(objective: "exit"), (objective: "entrance")])"#,
+        ] {
+            assert_eq!(error(&scenario(population)), "population 0: unknown objective `entrance`", "{population}");
+        }
+    }
+
+    #[test]
+    fn objectives_only_follow_obstacles() {
+
+        let follow = |target: &str| FollowDescription { target: target.to_owned(), offset: Vec2::ZERO, update_seconds: 1. };
+        let mut scenario = scenario("");
+
+        scenario.objectives.push(ObjectiveDescription {
+            name: "guide".to_owned(),
+            position: Vec2::ZERO,
+            shape: Shape::Circle(1.),
+            follow: Some(follow("pillar")),
+            sink: None,
+        });
+        scenario.validate().unwrap();
+
+        scenario.objectives[1].follow = Some(follow("exit"));
+        assert_eq!(error(&scenario), "objective `guide` follows objective `exit`, only obstacles can be followed");
+
+        scenario.objectives[1].follow = Some(follow("bus"));
+        assert_eq!(error(&scenario), "objective `guide` follows unknown obstacle `bus`");
+    }
+}
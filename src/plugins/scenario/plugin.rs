@@ -0,0 +1,56 @@
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+
+use crate::plugins::flow_field_pathfinding::systems::label_regions;
+
+use super::{loader::ScenarioLoader, models::Scenario, resources::*, systems::*};
+
+/// Spawns the obstacles, objectives and agent sources of a scenario and applies its
+/// model parameters. Must be added after `FlowFieldPathfindingPlugin`, whose
+/// defaults it overrides.
+pub struct ScenarioPlugin{
+    pub scenario: Scenario,
+    /// File the scenario was read from, watched for changes. The asset
+    /// source must point at its directory, see [`scenario_asset_plugin`].
+    pub file: Option<PathBuf>,
+}
+
+impl Plugin for ScenarioPlugin {
+    fn build(&self, app: &mut App) {
+
+        apply_parameters(app.world_mut(), &self.scenario.parameters);
+
+        app.insert_resource(CurrentScenario(self.scenario.clone()))
+        .init_asset::<Scenario>()
+        .init_asset_loader::<ScenarioLoader>();
+
+        app.add_systems(Startup, spawn_scenario)
+        .add_systems(Update, source_system)
+        .add_systems(PreUpdate, report_unreachable_sources.after(label_regions))
+        .add_systems(First, reload_scenario.run_if(resource_exists::<ScenarioHandle>));
+
+        if let Some(file_name) = self.file.as_ref().and_then(|file| file.file_name()) {
+            let asset_path = PathBuf::from(file_name);
+
+            app.add_systems(Startup, move |asset_server: Res<AssetServer>, mut commands: Commands| {
+                commands.insert_resource(ScenarioHandle(asset_server.load(asset_path.clone())));
+            });
+        }
+    }
+}
+
+/// Asset settings that read assets from the directory of the scenario `file`
+/// and reload them when they change.
+pub fn scenario_asset_plugin(file: Option<&Path>) -> AssetPlugin {
+
+    let Some(directory) = file.and_then(|file| file.canonicalize().ok()).and_then(|file| file.parent().map(Path::to_path_buf)) else {
+        return AssetPlugin::default();
+    };
+
+    AssetPlugin {
+        file_path: directory.to_string_lossy().into_owned(),
+        watch_for_changes_override: Some(true),
+        ..default()
+    }
+}
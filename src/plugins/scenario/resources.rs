@@ -0,0 +1,16 @@
+use bevy::{asset::Handle, ecs::system::Resource};
+
+use super::models::Scenario;
+
+/// Scenario the world was built from.
+#[derive(Resource, Debug, Clone)]
+pub struct CurrentScenario(pub Scenario);
+
+/// Scenario file watched for changes, when one was given.
+#[derive(Resource, Debug)]
+pub struct ScenarioHandle(pub Handle<Scenario>);
+
+/// Random numbers of the parameter distributions, spawn positions and
+/// arrivals, seeded from the scenario when it sets a seed.
+#[derive(Resource)]
+pub struct ScenarioRng(pub fastrand::Rng);
@@ -0,0 +1,471 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{Context, Result};
+use bevy::math::{Affine2, Vec2};
+use roxmltree::{Document, Node};
+use svgtypes::{Length, LengthUnit, PointsParser, SimplePathSegment, SimplifyingPathParser, Transform, ViewBox};
+
+use crate::components::Shape;
+
+use super::models::{ImportedPlan, ObjectiveDescription, ObstacleDescription, PlanImport, SpawnRegion};
+
+/// Id prefix of the elements imported as objectives, named after the rest of
+/// the id.
+const OBJECTIVE_PREFIX: &str = "objective-";
+/// Id prefix of the elements imported as spawn regions.
+const SPAWN_PREFIX: &str = "spawn-";
+/// Segments a Bézier curve is flattened into.
+const CURVE_STEPS: usize = 8;
+/// Sides of the polygon an ellipse, or a distorted circle, becomes.
+const ELLIPSE_SIDES: usize = 32;
+
+/// User units per inch, as CSS converts absolute lengths.
+const UNITS_PER_INCH: f32 = 96.;
+
+/// Elements whose content is never drawn by itself.
+const HIDDEN_ELEMENTS: [&str; 7] = ["defs", "clipPath", "mask", "symbol", "marker", "pattern", "metadata"];
+
+/// Turns the paths, rectangles, circles, ellipses, lines and polylines of an
+/// SVG plan into obstacles. Elements whose id starts with `objective-` or
+/// `spawn-` become objectives and spawn regions instead, whatever the filters
+/// of `import`.
+///
+/// Plan units are the user units the drawing is written in, lengths in
+/// absolute units such as `mm` being converted to them. The top-left corner
+/// of the root `viewBox` is the plan's origin, but the viewBox does not scale
+/// the plan: documents drawn in millimetres keep millimetre coordinates.
+///
+/// The plan's y axis points down, so it is flipped: a plan point `p` lands on
+/// `import.origin + import.scale * (p.x, -p.y)`.
+pub fn import_svg(text: &str, import: &PlanImport) -> Result<ImportedPlan> {
+
+    let document = Document::parse(text).context("parsing SVG")?;
+    let root = document.root_element();
+
+    let mut plan = ImportedPlan::default();
+    let mut world = Affine2::from_translation(import.origin) * Affine2::from_scale(Vec2::new(import.scale, -import.scale));
+
+    match root.attribute("viewBox").map(ViewBox::from_str) {
+        Some(Ok(view_box)) => world *= Affine2::from_translation(-Vec2::new(view_box.x as f32, view_box.y as f32)),
+        Some(Err(_)) => plan.warnings.push("invalid viewBox ignored".to_owned()),
+        None => {},
+    }
+
+    visit(root, &Inherited {
+        transform: world,
+        style: HashMap::new(),
+        selected: import.layers.is_empty(),
+    }, import, &mut plan);
+
+    Ok(plan)
+}
+
+/// State inherited from the ancestors of an element.
+struct Inherited {
+    transform: Affine2,
+    style: HashMap<String, String>,
+    /// Whether the element lies in one of the imported layers.
+    selected: bool,
+}
+
+fn visit(node: Node, parent: &Inherited, import: &PlanImport, plan: &mut ImportedPlan) {
+
+    if HIDDEN_ELEMENTS.contains(&node.tag_name().name()) {
+        return;
+    }
+
+    let context = Inherited {
+        transform: parent.transform * element_transform(node),
+        style: element_style(node, &parent.style),
+        selected: parent.selected || [node.attribute("id"), label(node)].into_iter().flatten()
+            .any(|name| import.layers.iter().any(|layer| layer == name)),
+    };
+
+    if context.style.get("display").is_some_and(|display| display == "none") {
+        return;
+    }
+
+    match element_shapes(node, &context, import) {
+        Ok(Some(shapes)) => add_element(node, &context, import, shapes, plan),
+        Ok(None) => {},
+        Err(error) => {
+            let element = match node.attribute("id") {
+                Some(id) => format!("<{}> `{id}`", node.tag_name().name()),
+                None => format!("<{}>", node.tag_name().name()),
+            };
+
+            plan.warnings.push(format!("{element} skipped: {error}"));
+        },
+    }
+
+    for child in node.children().filter(Node::is_element) {
+        visit(child, &context, import, plan);
+    }
+}
+
+fn add_element(node: Node, context: &Inherited, import: &PlanImport, shapes: Vec<(Vec2, Shape)>, plan: &mut ImportedPlan) {
+
+    let id = node.attribute("id").unwrap_or_default();
+
+    if let Some(name) = id.strip_prefix(OBJECTIVE_PREFIX) {
+        plan.objectives.extend(shapes.into_iter().map(|(position, shape)| ObjectiveDescription {
+            name: name.to_owned(),
+            position,
+            shape,
+            follow: None,
+            sink: None,
+        }));
+        return;
+    }
+
+    if let Some(name) = id.strip_prefix(SPAWN_PREFIX) {
+        plan.spawn_regions.extend(shapes.into_iter().map(|(position, shape)| SpawnRegion {
+            name: name.to_owned(),
+            position,
+            shape,
+        }));
+        return;
+    }
+
+    let style_matches = import.styles.iter().all(|fragment| {
+        let (key, value) = fragment.split_once(':').unwrap_or((fragment, ""));
+        context.style.get(key.trim()).is_some_and(|actual| actual.eq_ignore_ascii_case(value.trim()))
+    });
+
+    if context.selected && style_matches {
+        plan.obstacles.extend(shapes.into_iter().map(|(position, shape)| ObstacleDescription {
+            name: (!id.is_empty()).then(|| id.to_owned()),
+            position,
+            shape,
+        }));
+    }
+}
+
+/// World shapes drawn by an element, each with its position, or `None` for
+/// elements that draw nothing. Fails on lengths that cannot be converted to
+/// user units.
+fn element_shapes(node: Node, context: &Inherited, import: &PlanImport) -> Result<Option<Vec<(Vec2, Shape)>>, String> {
+
+    let number = |name: &str| node.attribute(name).map_or(Ok(0.), parse_length);
+    let transform = context.transform;
+
+    // Objectives and spawn regions are areas, however they are drawn.
+    let id = node.attribute("id").unwrap_or_default();
+    let filled = context.style.get("fill").is_none_or(|fill| fill != "none")
+        || id.starts_with(OBJECTIVE_PREFIX) || id.starts_with(SPAWN_PREFIX);
+    let stroke_width = match context.style.get("stroke-width") {
+        Some(width) => Some(parse_length(width)?),
+        None => None,
+    };
+    let thickness = import.wall_thickness.or(stroke_width).unwrap_or(1.) * scale_factor(transform);
+
+    let outlines: Vec<(Vec<Vec2>, bool)> = match node.tag_name().name() {
+        "circle" => {
+            let center = Vec2::new(number("cx")?, number("cy")?);
+            let radius = number("r")?;
+
+            if is_similarity(transform) && filled {
+                return Ok(Some(vec![(transform.transform_point2(center), Shape::Circle(radius * scale_factor(transform)))]));
+            }
+
+            vec![(ellipse(center, Vec2::splat(radius)), true)]
+        },
+        "ellipse" => vec![(ellipse(Vec2::new(number("cx")?, number("cy")?), Vec2::new(number("rx")?, number("ry")?)), true)],
+        "rect" => {
+            let (min, size) = (Vec2::new(number("x")?, number("y")?), Vec2::new(number("width")?, number("height")?));
+            vec![(vec![min, min + Vec2::new(size.x, 0.), min + size, min + Vec2::new(0., size.y)], true)]
+        },
+        "line" => vec![(vec![Vec2::new(number("x1")?, number("y1")?), Vec2::new(number("x2")?, number("y2")?)], false)],
+        "polyline" | "polygon" => vec![(
+            PointsParser::from(node.attribute("points").unwrap_or_default())
+                .map(|(x, y)| Vec2::new(x as f32, y as f32))
+                .collect(),
+            node.tag_name().name() == "polygon",
+        )],
+        "path" => path_outlines(node.attribute("d").unwrap_or_default()),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(outlines.into_iter()
+        .filter_map(|(points, closed)| {
+            let points: Vec<Vec2> = points.into_iter().map(|point| transform.transform_point2(point)).collect();
+            Shape::from_outline(points, closed && filled, closed, thickness)
+        })
+        .collect()))
+}
+
+/// Flattened subpaths of path data, each with whether it is closed. Parsing
+/// stops at the first error, as SVG renderers do.
+fn path_outlines(data: &str) -> Vec<(Vec<Vec2>, bool)> {
+
+    let mut outlines = Vec::new();
+    let mut current: Vec<Vec2> = Vec::new();
+
+    for segment in SimplifyingPathParser::from(data).map_while(|segment| segment.ok()) {
+        let last = current.last().copied().unwrap_or_default();
+
+        match segment {
+            SimplePathSegment::MoveTo { x, y } => {
+                if current.len() > 1 {
+                    outlines.push((std::mem::take(&mut current), false));
+                }
+
+                current = vec![Vec2::new(x as f32, y as f32)];
+            },
+            SimplePathSegment::LineTo { x, y } => current.push(Vec2::new(x as f32, y as f32)),
+            SimplePathSegment::CurveTo { x1, y1, x2, y2, x, y } => {
+                let [c1, c2, end] = [(x1, y1), (x2, y2), (x, y)].map(|(x, y)| Vec2::new(x as f32, y as f32));
+
+                current.extend((1..=CURVE_STEPS).map(|step| {
+                    let t = step as f32 / CURVE_STEPS as f32;
+                    let u = 1. - t;
+                    last * u * u * u + c1 * 3. * u * u * t + c2 * 3. * u * t * t + end * t * t * t
+                }));
+            },
+            SimplePathSegment::Quadratic { x1, y1, x, y } => {
+                let [control, end] = [(x1, y1), (x, y)].map(|(x, y)| Vec2::new(x as f32, y as f32));
+
+                current.extend((1..=CURVE_STEPS).map(|step| {
+                    let t = step as f32 / CURVE_STEPS as f32;
+                    let u = 1. - t;
+                    last * u * u + control * 2. * u * t + end * t * t
+                }));
+            },
+            SimplePathSegment::ClosePath => {
+                let start = current.first().copied();
+
+                if current.len() > 1 {
+                    outlines.push((std::mem::take(&mut current), true));
+                }
+
+                // A new subpath starts where the closed one started.
+                current.extend(start);
+            },
+        }
+    }
+
+    if current.len() > 1 {
+        outlines.push((current, false));
+    }
+
+    outlines
+}
+
+fn ellipse(center: Vec2, radii: Vec2) -> Vec<Vec2> {
+    (0..ELLIPSE_SIDES)
+        .map(|side| center + radii * Vec2::from_angle(std::f32::consts::TAU * side as f32 / ELLIPSE_SIDES as f32))
+        .collect()
+}
+
+fn element_transform(node: Node) -> Affine2 {
+    let Some(Ok(transform)) = node.attribute("transform").map(Transform::from_str) else {
+        return Affine2::IDENTITY;
+    };
+
+    let Transform { a, b, c, d, e, f } = transform;
+    Affine2::from_cols_array(&[a, b, c, d, e, f].map(|value| value as f32))
+}
+
+/// Presentation attributes and `style` declarations of an element, on top of
+/// those it inherits.
+fn element_style(node: Node, inherited: &HashMap<String, String>) -> HashMap<String, String> {
+
+    let mut style = inherited.clone();
+
+    for property in ["fill", "stroke", "stroke-width", "display"] {
+        if let Some(value) = node.attribute(property) {
+            style.insert(property.to_owned(), value.trim().to_owned());
+        }
+    }
+
+    for declaration in node.attribute("style").unwrap_or_default().split(';') {
+        if let Some((key, value)) = declaration.split_once(':') {
+            style.insert(key.trim().to_owned(), value.trim().to_owned());
+        }
+    }
+
+    style
+}
+
+/// Layer name given by Inkscape.
+fn label<'a>(node: Node<'a, '_>) -> Option<&'a str> {
+    node.attributes()
+        .find(|attribute| attribute.name() == "label")
+        .map(|attribute| attribute.value())
+}
+
+/// Length in user units. Lengths relative to the font or the viewport are
+/// not supported, as the import knows neither.
+fn parse_length(text: &str) -> Result<f32, String> {
+
+    let length = Length::from_str(text).map_err(|_| format!("invalid length `{text}`"))?;
+
+    let units_per = match length.unit {
+        LengthUnit::None | LengthUnit::Px => 1.,
+        LengthUnit::In => UNITS_PER_INCH,
+        LengthUnit::Cm => UNITS_PER_INCH / 2.54,
+        LengthUnit::Mm => UNITS_PER_INCH / 25.4,
+        LengthUnit::Pt => UNITS_PER_INCH / 72.,
+        LengthUnit::Pc => UNITS_PER_INCH / 6.,
+        LengthUnit::Em | LengthUnit::Ex | LengthUnit::Percent => return Err(format!("relative length `{text}` is not supported")),
+    };
+
+    Ok(length.number as f32 * units_per)
+}
+
+/// Average factor by which the transform scales lengths.
+fn scale_factor(transform: Affine2) -> f32 {
+    transform.matrix2.determinant().abs().sqrt()
+}
+
+/// Whether the transform keeps circles round.
+fn is_similarity(transform: Affine2) -> bool {
+    let (x, y) = (transform.matrix2.x_axis, transform.matrix2.y_axis);
+    x.dot(y).abs() < 1e-4 * x.length_squared() && (x.length_squared() - y.length_squared()).abs() < 1e-4 * x.length_squared()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(scale: f32, origin: Vec2) -> PlanImport {
+        PlanImport {
+            path: "plan.svg".into(),
+            scale,
+            origin,
+            layers: Vec::new(),
+            roles: HashMap::new(),
+            styles: Vec::new(),
+            wall_thickness: None,
+        }
+    }
+
+    fn plan(body: &str, import: &PlanImport) -> ImportedPlan {
+        let text = format!(r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape">{body}</svg>"#);
+        import_svg(&text, import).unwrap()
+    }
+
+    fn obstacle_names(plan: &ImportedPlan) -> Vec<&str> {
+        plan.obstacles.iter().map(|obstacle| obstacle.name.as_deref().unwrap_or_default()).collect()
+    }
+
+    #[test]
+    fn transforms_are_applied_and_y_flipped() {
+
+        let plan = plan(r#"
+            <g transform="translate(5 5)">
+                <rect x="0" y="0" width="4" height="2"/>
+                <circle transform="scale(3)" cx="1" cy="1" r="1"/>
+                <rect transform="rotate(45)" width="2" height="2"/>
+            </g>
+        "#, &import(2., Vec2::new(10., 0.)));
+
+        let [rectangle, circle, rotated] = &plan.obstacles[..] else {
+            panic!("{:?}", plan.obstacles);
+        };
+
+        assert_eq!(rectangle.position, Vec2::new(24., -12.));
+        assert!(matches!(rectangle.shape, Shape::Rectangle(size) if size == Vec2::new(8., 4.)));
+
+        assert_eq!(circle.position, Vec2::new(26., -16.));
+        assert!(matches!(circle.shape, Shape::Circle(radius) if radius == 6.));
+
+        // The rotated square stands on a corner, which the flip turns into
+        // its top one, at the origin of the group.
+        let Shape::Polygon(points) = &rotated.shape else {
+            panic!("{:?}", rotated.shape);
+        };
+        let top = points.iter().map(|point| rotated.position + *point).max_by(|a, b| a.y.total_cmp(&b.y)).unwrap();
+        assert!(top.abs_diff_eq(Vec2::new(20., -10.), 1e-4), "{top}");
+    }
+
+    #[test]
+    fn view_box_corner_is_the_origin() {
+
+        let text = r#"<svg xmlns="http://www.w3.org/2000/svg" width="200mm" height="100mm" viewBox="-10 -20 200 100">
+            <rect x="-10" y="-20" width="2" height="2"/>
+        </svg>"#;
+        let plan = import_svg(text, &import(1., Vec2::ZERO)).unwrap();
+
+        // Plan units stay the user units, whatever the size of the page.
+        assert_eq!(plan.obstacles[0].position, Vec2::new(1., -1.));
+        assert!(matches!(plan.obstacles[0].shape, Shape::Rectangle(size) if size == Vec2::splat(2.)));
+        assert!(plan.warnings.is_empty());
+
+        let plan = import_svg(r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 nope"/>"#, &import(1., Vec2::ZERO)).unwrap();
+        assert_eq!(plan.warnings, ["invalid viewBox ignored"]);
+    }
+
+    #[test]
+    fn lengths_are_converted_to_user_units() {
+
+        let plan = plan(r#"
+            <rect width="1in" height="25.4mm"/>
+            <line x2="72pt" stroke-width="0.5cm"/>
+            <rect id="half" width="50%" height="10"/>
+            <circle r="2em"/>
+        "#, &import(1., Vec2::ZERO));
+
+        assert!(matches!(plan.obstacles[0].shape, Shape::Rectangle(size) if size.abs_diff_eq(Vec2::splat(96.), 1e-3)));
+        assert!(matches!(plan.obstacles[1].shape, Shape::Polyline { thickness, .. } if (thickness - 96. / 2.54 * 0.5).abs() < 1e-3));
+        assert_eq!(plan.obstacles.len(), 2);
+        assert_eq!(plan.warnings, [
+            "<rect> `half` skipped: relative length `50%` is not supported",
+            "<circle> skipped: relative length `2em` is not supported",
+        ]);
+    }
+
+    #[test]
+    fn layers_and_styles_filter_obstacles() {
+
+        let body = r##"
+            <g id="walls" style="stroke:#000000">
+                <rect id="wall" width="1" height="1"/>
+                <rect id="red-wall" width="1" height="1" stroke="#FF0000"/>
+            </g>
+            <g inkscape:label="Furniture">
+                <rect id="table" width="1" height="1" style="stroke: #000000"/>
+            </g>
+            <rect id="loose" width="1" height="1"/>
+            <rect id="hidden" width="1" height="1" display="none"/>
+            <defs><rect id="template" width="1" height="1"/></defs>
+        "##;
+
+        let mut import = import(1., Vec2::ZERO);
+        assert_eq!(obstacle_names(&plan(body, &import)), ["wall", "red-wall", "table", "loose"]);
+
+        import.layers = vec!["walls".to_owned(), "Furniture".to_owned()];
+        assert_eq!(obstacle_names(&plan(body, &import)), ["wall", "red-wall", "table"]);
+
+        import.styles = vec!["stroke:#000000".to_owned()];
+        assert_eq!(obstacle_names(&plan(body, &import)), ["wall", "table"]);
+
+        import.layers.clear();
+        assert_eq!(obstacle_names(&plan(body, &import)), ["wall", "table"]);
+    }
+
+    #[test]
+    fn prefixed_ids_become_objectives_and_spawn_regions() {
+
+        let mut import = import(1., Vec2::ZERO);
+        import.layers = vec!["walls".to_owned()];
+        import.styles = vec!["stroke:#000000".to_owned()];
+
+        let plan = plan(r#"
+            <rect id="objective-exit" x="10" width="2" height="2" fill="none"/>
+            <circle id="spawn-hall" cx="-5" r="3" fill="none"/>
+            <path id="objective-gate" d="M 0 0 L 4 0 L 4 4 Z"/>
+        "#, &import);
+
+        assert!(plan.obstacles.is_empty());
+
+        let objectives: Vec<_> = plan.objectives.iter().map(|objective| (objective.name.as_str(), objective.position)).collect();
+        assert_eq!(objectives, [("exit", Vec2::new(11., -1.)), ("gate", Vec2::new(2., -2.))]);
+        assert!(matches!(plan.objectives[0].shape, Shape::Rectangle(_)));
+        assert!(matches!(plan.objectives[1].shape, Shape::Polygon(_)));
+
+        assert_eq!(plan.spawn_regions.len(), 1);
+        assert_eq!(plan.spawn_regions[0].name, "hall");
+        assert!(matches!(plan.spawn_regions[0].shape, Shape::Circle(radius) if radius == 3.));
+    }
+}
@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+
+use bevy::{
+    color::palettes::tailwind::*,
+    prelude::*,
+    render::{mesh::{Indices, PrimitiveTopology}, render_asset::RenderAssetUsages},
+    sprite::{MaterialMesh2dBundle, Mesh2dHandle},
+};
+
+use crate::{components::*, consts::AGENT_RADIUS, plugins::flow_field_pathfinding::{models::{BlockedStatus, RegionLabel}, resources::{Clearance, LookAhead, NavigationClasses, ReachableRegions}, systems::can_reach_destination}};
+
+use super::{models::{AgentTemplate, ModelParameters, RouteChoiceDescription, Scenario, ScenarioEntity, Source}, resources::{CurrentScenario, ScenarioHandle, ScenarioRng}};
+
+/// Random positions tried before an arrival waits for the next frame.
+const PLACEMENT_ATTEMPTS: usize = 32;
+
+pub fn spawn_scenario(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut rng: ResMut<ScenarioRng>,
+    scenario: Res<CurrentScenario>,
+) {
+    log_import_warnings(&scenario.0);
+
+    let objectives = spawn_layout(&mut commands, &mut meshes, &mut materials, &scenario.0, HashMap::new());
+
+    spawn_sources(&mut commands, &mut meshes, &mut materials, &scenario.0, &objectives, &mut rng.0, true);
+}
+
+/// Rebuilds the layout and sources when the scenario file changes on disk.
+/// Objectives keep their entity when their name is unchanged, so the agents
+/// heading to them carry on unless the scenario asks for the populations to
+/// be reset.
+pub fn reload_scenario(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut events: EventReader<AssetEvent<Scenario>>,
+    handle: Res<ScenarioHandle>,
+    scenarios: Res<Assets<Scenario>>,
+    mut current: ResMut<CurrentScenario>,
+    layout: Query<Entity, (With<ScenarioEntity>, Without<Objective>)>,
+    objectives: Query<(Entity, &Name), (With<ScenarioEntity>, With<Objective>)>,
+    agents: Query<Entity, With<Agent>>,
+    queued: Query<Entity, With<Queued>>,
+) {
+    let modified = events.read()
+        .any(|event| matches!(event, AssetEvent::Modified { id } if *id == handle.0.id()));
+
+    let Some(scenario) = modified.then(|| scenarios.get(&handle.0)).flatten() else {
+        return;
+    };
+
+    let previous = &current.0;
+
+    if previous.area.rect() != scenario.area.rect()
+        || previous.cell_size != scenario.cell_size
+        || previous.sector_size != scenario.sector_size
+        || previous.chunk_size != scenario.chunk_size {
+        warn!("The area and grid of a scenario are only applied on restart");
+    }
+
+    log_import_warnings(scenario);
+
+    for entity in &layout {
+        commands.entity(entity).despawn();
+    }
+
+    let existing = objectives.iter()
+        .map(|(entity, name)| (name.as_str().to_owned(), entity))
+        .collect();
+
+    let objectives = spawn_layout(&mut commands, &mut meshes, &mut materials, scenario, existing);
+
+    if scenario.reset_agents_on_reload {
+        for agent in &agents {
+            commands.entity(agent).despawn();
+        }
+    }
+
+    // Sinks are rebuilt with empty queues, the waiting agents line up again.
+    for agent in &queued {
+        commands.entity(agent).remove::<Queued>();
+    }
+
+    let mut rng = scenario.parameters.rng();
+    spawn_sources(&mut commands, &mut meshes, &mut materials, scenario, &objectives, &mut rng, scenario.reset_agents_on_reload);
+
+    // Inserting the resources again would mark them changed and rebuild the
+    // fields that depend on them, and restart the scenario's random numbers.
+    if scenario.parameters != previous.parameters {
+        let parameters = scenario.parameters.clone();
+        commands.add(move |world: &mut World| apply_parameters(world, &parameters));
+    }
+
+    // The grid and floor plan were built from the scenario given at startup.
+    current.0 = Scenario {
+        area: previous.area,
+        cell_size: previous.cell_size,
+        sector_size: previous.sector_size,
+        chunk_size: previous.chunk_size,
+        floor_plan: previous.floor_plan.clone(),
+        ..scenario.clone()
+    };
+
+    info!("Reloaded scenario {:?}", handle.0.path());
+}
+
+fn log_import_warnings(scenario: &Scenario) {
+
+    if scenario.import_warnings.is_empty() {
+        return;
+    }
+
+    warn!("{} issues while importing the plans:", scenario.import_warnings.len());
+
+    for warning in &scenario.import_warnings {
+        warn!("  {warning}");
+    }
+}
+
+/// Inserts the model resources the scenario sets, or their defaults.
+pub fn apply_parameters(world: &mut World, parameters: &ModelParameters) {
+    world.insert_resource(parameters.clearance.map_or_else(Clearance::default, Clearance));
+    world.insert_resource(parameters.wall_avoidance.unwrap_or_default());
+    world.insert_resource(LookAhead { max_cells: parameters.look_ahead_cells.unwrap_or_default() });
+    world.insert_resource(parameters.route_choice.unwrap_or_default());
+    world.insert_resource(NavigationClasses(parameters.classes.clone()));
+    world.insert_resource(ScenarioRng(parameters.rng()));
+}
+
+/// Spawns the obstacles, zones and objectives of `scenario`. Objectives found
+/// in `existing` are updated in place and the others are despawned. Returns
+/// the objectives by name.
+fn spawn_layout(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    scenario: &Scenario,
+    mut existing: HashMap<String, Entity>,
+) -> HashMap<String, Entity> {
+
+    let mut obstacles = HashMap::new();
+
+    for obstacle in &scenario.obstacles {
+        let entity = commands.spawn((
+            Obstacle,
+            ScenarioEntity,
+            obstacle.shape.clone(),
+            MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(shape_mesh(&obstacle.shape))),
+                material: materials.add(Color::from(GRAY_500)),
+                transform: Transform::from_translation(obstacle.position.extend(-0.5)),
+                ..default()
+            },
+        )).id();
+
+        if let Some(name) = &obstacle.name {
+            commands.entity(entity).insert(Name::new(name.clone()));
+            obstacles.insert(name.clone(), entity);
+        }
+    }
+
+    for zone in &scenario.zones {
+        commands.spawn((
+            Zone { label: zone.label.clone() },
+            ScenarioEntity,
+            zone.shape.clone(),
+            MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(shape_mesh(&zone.shape))),
+                material: materials.add(Color::from(AMBER_500.with_alpha(0.2))),
+                transform: Transform::from_translation(zone.position.extend(-0.6)),
+                ..default()
+            },
+        ));
+    }
+
+    let mut objectives = HashMap::new();
+
+    for objective in &scenario.objectives {
+        let entity = existing.remove(&objective.name)
+            .unwrap_or_else(|| commands.spawn_empty().id());
+
+        commands.entity(entity).remove::<(Follow, Sink)>().insert((
+            Objective,
+            ScenarioEntity,
+            Name::new(objective.name.clone()),
+            objective.shape.clone(),
+            MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(shape_mesh(&objective.shape))),
+                material: materials.add(Color::from(RED_500)),
+                transform: Transform::from_translation(objective.position.extend(0.)),
+                ..default()
+            },
+        ));
+
+        if let Some(sink) = &objective.sink {
+            commands.entity(entity).insert(sink.sink());
+        }
+
+        objectives.insert(objective.name.clone(), entity);
+    }
+
+    for entity in existing.into_values() {
+        commands.entity(entity).despawn();
+    }
+
+    for objective in &scenario.objectives {
+        if let Some(follow) = &objective.follow {
+            commands.entity(objectives[&objective.name]).insert(Follow::new(obstacles[&follow.target], follow.offset, follow.update_seconds));
+        }
+    }
+
+    objectives
+}
+
+/// Spawns a [`Source`] in the spawn region of every population. When
+/// `initial` is false the populations' initial agents are skipped and only
+/// the later arrivals are spawned.
+fn spawn_sources(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    scenario: &Scenario,
+    objectives: &HashMap<String, Entity>,
+    rng: &mut fastrand::Rng,
+    initial: bool,
+) {
+    let mesh = Mesh2dHandle(meshes.add(Circle { radius: AGENT_RADIUS }));
+    let material = materials.add(Color::from(CYAN_500));
+
+    for population in &scenario.populations {
+        let Some(region) = scenario.spawn_regions.iter().find(|region| region.name == population.spawn_region) else {
+            continue;
+        };
+
+        let route_choice = population.route_choice.as_ref().map(|route_choice| match route_choice {
+            RouteChoiceDescription::Nearest => RouteChoiceModel::Nearest,
+            RouteChoiceDescription::Familiar { preferred, tolerance } => RouteChoiceModel::Familiar { preferred: objectives[preferred], tolerance: *tolerance },
+            RouteChoiceDescription::TravelTime => RouteChoiceModel::TravelTime,
+        });
+
+        let template = AgentTemplate {
+            desired_speed: population.desired_speed,
+            class: population.class.as_ref()
+                .and_then(|class| scenario.parameters.classes.iter().position(|navigation_class| &navigation_class.name == class)),
+            destination: population.destination.as_ref().map(|destination| objectives[destination]),
+            route_choice,
+            stops: population.itinerary.iter()
+                .map(|stop| ItineraryStop { destination: objectives[&stop.objective], dwell_seconds: stop.dwell_seconds })
+                .collect(),
+            mesh: mesh.clone(),
+            material: material.clone(),
+        };
+
+        let initial_count = if initial { population.count } else { 0 };
+
+        commands.spawn((
+            ScenarioEntity,
+            Name::new(region.name.clone()),
+            region.shape.clone(),
+            TransformBundle::from_transform(Transform::from_translation(region.position.extend(0.))),
+            Source {
+                arrivals: population.arrivals,
+                cap: population.cap,
+                spawned: 0,
+                pending: population.cap.map_or(initial_count, |cap| initial_count.min(cap)),
+                next_arrival: population.arrivals.map_or(f32::INFINITY, |arrivals| arrivals.first_delay(rng)),
+                template,
+            },
+        ));
+    }
+}
+
+/// Counts the arrivals of every source and places the waiting agents on free
+/// cells of its region, away from the other agents.
+pub fn source_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut rng: ResMut<ScenarioRng>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    mut sources: Query<(&Transform, &Shape, &mut Source)>,
+    agents: Query<&Transform, With<Agent>>,
+) {
+    let mut occupied: Vec<Vec2> = agents.iter()
+        .map(|transform| transform.translation.truncate())
+        .collect();
+
+    for (transform, shape, mut source) in &mut sources {
+
+        if let Some(arrivals) = source.arrivals {
+            source.next_arrival -= time.delta_seconds();
+
+            while source.next_arrival <= 0. && !source.is_exhausted() {
+                source.pending += arrivals.group_size();
+                source.next_arrival += arrivals.next_delay(&mut rng.0);
+            }
+
+            if let Some(cap) = source.cap {
+                source.pending = source.pending.min(cap - source.spawned);
+            }
+        }
+
+        while source.pending > 0 {
+            let Some(position) = free_position(shape, transform.translation.truncate(), &obstacles_map, &occupied, &mut rng.0) else {
+                break;
+            };
+
+            spawn_agent(&mut commands, &source.template, position, &mut rng.0);
+            occupied.push(position);
+
+            source.pending -= 1;
+            source.spawned += 1;
+        }
+    }
+}
+
+/// Warns about the sources placing agents where they cannot reach their
+/// first objective, or any objective when they have none. Checked again
+/// whenever the walkable regions or the sources change; each source is only
+/// reported again when the number of such cells changes.
+pub fn report_unreachable_sources(
+    labels: Res<GridMap<RegionLabel>>,
+    reachable: Res<ReachableRegions>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    sources: Query<(Entity, &Name, &Transform, &Shape, &Source)>,
+    changed_sources: Query<(), (With<Source>, Or<(Added<Source>, Changed<Transform>, Changed<Shape>)>)>,
+    mut reported: Local<HashMap<Entity, usize>>,
+) {
+    if !reachable.is_changed() && changed_sources.is_empty() {
+        return;
+    }
+
+    reported.retain(|source, _| sources.contains(*source));
+
+    for (entity, name, transform, shape, source) in &sources {
+        let center = transform.translation.truncate();
+        let destination = source.template.destination
+            .or_else(|| source.template.stops.first().map(|stop| stop.destination));
+
+        let cells: Vec<Vec2> = obstacles_map.cells_within_rect(shape.get_rectangle_with_center(center))
+            .map(|region| obstacles_map.cells_in_region(region).map(|cell| obstacles_map.get_coord(cell)).collect())
+            .unwrap_or_default();
+
+        let free: Vec<Vec2> = cells.into_iter()
+            .filter(|&position| shape.contains(center, position) && obstacles_map.get_value_at(position) == Some(BlockedStatus::Empty))
+            .collect();
+
+        // Sources without a free cell are reported as `usize::MAX`.
+        let unreachable = match free.is_empty() {
+            true => usize::MAX,
+            false => free.iter()
+                .filter(|&&position| !can_reach_destination(&labels, &reachable, position, destination))
+                .count(),
+        };
+
+        if reported.get(&entity).copied().unwrap_or(0) == unreachable {
+            continue;
+        }
+
+        match unreachable {
+            0 => info!("Source `{name}` can reach its destination again"),
+            usize::MAX => warn!("Source `{name}` has no free cell to place agents on"),
+            _ => warn!("Source `{name}`: {unreachable} of its {} free cells cannot reach its destination", free.len()),
+        }
+
+        reported.insert(entity, unreachable);
+    }
+}
+
+fn spawn_agent(commands: &mut Commands, template: &AgentTemplate, position: Vec2, rng: &mut fastrand::Rng) {
+
+    let mut agent = commands.spawn((
+        Agent,
+        Speed(Vec2::ZERO),
+        DesiredSpeed(template.desired_speed.sample(rng).max(0.)),
+        ObstacleForce(Vec2::ZERO),
+        MotivationForce(Vec2::ZERO),
+        RepulsiveForce(Vec2::ZERO),
+        MaterialMesh2dBundle {
+            mesh: template.mesh.clone(),
+            material: template.material.clone(),
+            transform: Transform::from_translation(position.extend(0.1)),
+            ..default()
+        },
+    ));
+
+    if let Some(class) = template.class {
+        agent.insert(AgentClass(class));
+    }
+
+    if let Some(destination) = template.destination {
+        agent.insert(Destination(destination));
+    }
+
+    if let Some(route_choice) = template.route_choice {
+        agent.insert(RouteChoice(route_choice));
+    }
+
+    if !template.stops.is_empty() {
+        agent.insert(Itinerary { stops: template.stops.clone(), next_stop: 0 });
+    }
+}
+
+/// Random point of the region on a free cell and at least an agent diameter
+/// away from the `occupied` positions, if one is found within a few tries.
+fn free_position(shape: &Shape, center: Vec2, obstacles_map: &GridMap<BlockedStatus>, occupied: &[Vec2], rng: &mut fastrand::Rng) -> Option<Vec2> {
+
+    let min_distance_squared = (2. * AGENT_RADIUS).powi(2);
+
+    (0..PLACEMENT_ATTEMPTS)
+        .filter_map(|_| random_point(shape, center, rng))
+        .find(|&position| obstacles_map.get_value_at(position) == Some(BlockedStatus::Empty)
+            && occupied.iter().all(|other| other.distance_squared(position) >= min_distance_squared))
+}
+
+pub fn shape_mesh(shape: &Shape) -> Mesh {
+    match shape {
+        Shape::Circle(radius) => Circle { radius: *radius }.into(),
+        Shape::Rectangle(size) => Rectangle::from_size(*size).into(),
+        Shape::Polygon(points) => triangle_mesh(points.clone(), triangulate(points)),
+        Shape::Polyline { points, thickness } => {
+            let mut vertices = Vec::new();
+            let mut indices = Vec::new();
+
+            for pair in points.windows(2) {
+                let normal = (pair[1] - pair[0]).perp().normalize_or_zero() * *thickness / 2.;
+                let first = vertices.len() as u32;
+
+                vertices.extend([pair[0] - normal, pair[1] - normal, pair[1] + normal, pair[0] + normal]);
+                indices.extend([first, first + 1, first + 2, first, first + 2, first + 3]);
+            }
+
+            triangle_mesh(vertices, indices)
+        },
+    }
+}
+
+/// Whether `point` lies in the counter-clockwise triangle `a`, `b`, `c`.
+fn in_triangle(point: Vec2, a: Vec2, b: Vec2, c: Vec2) -> bool {
+    (b - a).perp_dot(point - a) >= 0. && (c - b).perp_dot(point - b) >= 0. && (a - c).perp_dot(point - c) >= 0.
+}
+
+fn triangle_mesh(vertices: Vec<Vec2>, indices: Vec<u32>) -> Mesh {
+    let count = vertices.len();
+
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, vertices.into_iter().map(|vertex| [vertex.x, vertex.y, 0.]).collect::<Vec<_>>())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0., 0., 1.]; count])
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0., 0.]; count])
+        .with_inserted_indices(Indices::U32(indices))
+}
+
+/// Triangles of a simple polygon, by ear clipping.
+fn triangulate(points: &[Vec2]) -> Vec<u32> {
+
+    let signed_area: f32 = points.iter().zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| a.perp_dot(*b))
+        .sum();
+
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+
+    if signed_area < 0. {
+        remaining.reverse();
+    }
+
+    let mut indices = Vec::new();
+
+    while remaining.len() > 3 {
+        let count = remaining.len();
+
+        let ear = (0..count).find(|&i| {
+            let [a, b, c] = [remaining[(i + count - 1) % count], remaining[i], remaining[(i + 1) % count]].map(|index| points[index]);
+
+            (b - a).perp_dot(c - b) > 0. && remaining.iter()
+                .map(|&index| points[index])
+                .filter(|point| ![a, b, c].contains(point))
+                .all(|point| !in_triangle(point, a, b, c))
+        });
+
+        // Degenerate outlines have no ear left, the rest is dropped.
+        let Some(i) = ear else {
+            break;
+        };
+
+        indices.extend([remaining[(i + count - 1) % count], remaining[i], remaining[(i + 1) % count]].map(|index| index as u32));
+        remaining.remove(i);
+    }
+
+    if remaining.len() == 3 {
+        indices.extend(remaining.iter().map(|&index| index as u32));
+    }
+
+    indices
+}
+
+/// Uniformly distributed point inside `shape`, by rejection from its bounding
+/// rectangle. Gives up after a few tries, which only happens for shapes
+/// covering a small part of their bounds.
+pub fn random_point(shape: &Shape, center: Vec2, rng: &mut fastrand::Rng) -> Option<Vec2> {
+    let bounds = shape.get_rectangle_with_center(center);
+
+    (0..PLACEMENT_ATTEMPTS)
+        .map(|_| bounds.min + bounds.size() * Vec2::new(rng.f32(), rng.f32()))
+        .find(|&point| shape.contains(center, point))
+}
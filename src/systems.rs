@@ -1,6 +1,6 @@
 use std::ops::Add;
 
-use crate::{components::*, consts::*};
+use crate::{components::*, consts::*, plugins::flow_field_pathfinding::{models::BlockedStatus, resources::RouteChoiceSettings}};
 use bevy::{
     color::palettes::{css::{BLUE, DARK_BLUE, DARK_RED, GREEN, PURPLE, RED, YELLOW}, tailwind::*}, math::{vec2, vec3, VectorSpace,}, prelude::*, sprite::{MaterialMesh2dBundle, Mesh2dHandle}, transform
 };
@@ -89,7 +89,7 @@ pub fn velocity_sytem(mut query: Query<(&mut Transform, &Speed), With<Agent>>) {
 }
 
 pub fn motivation_force_system(
-    mut agents: Query<(&mut MotivationForce, &Transform, &Speed), With<Agent>>,
+    mut agents: Query<(&mut MotivationForce, &Transform, &Speed, Option<&DesiredSpeed>), With<Agent>>,
     objectives: Query<&Transform, With<Objective>>,
 ) {
     let objective = objectives.get_single();
@@ -100,14 +100,13 @@ pub fn motivation_force_system(
 
     let objective = objective.unwrap();
 
-    for (mut motivation_force, transform, agent_speed) in &mut agents {
+    for (mut motivation_force, transform, agent_speed, desired_speed) in &mut agents {
         let direction = (objective.translation - transform.translation)
             .with_z(0.)
-            .normalize()
-            * AGENT_DESIRED_SPEED;
+            .normalize_or_zero();
 
         let final_force =
-            AGENT_DESIRED_SPEED * direction - vec3(agent_speed.0.x, agent_speed.0.y, 0.);
+            DesiredSpeed::of(desired_speed) * direction - vec3(agent_speed.0.x, agent_speed.0.y, 0.);
 
         motivation_force.0 = Vec2::new(final_force.x, final_force.y);
     }
@@ -115,23 +114,227 @@ pub fn motivation_force_system(
 
 pub fn agent_araived_at_destination_system(
     mut commands: Commands,
-    agents: Query<(Entity, &Transform), With<Agent>>,
-    destinations: Query<(&Transform, &Shape), With<Objective>>,
+    mut agents: Query<(Entity, &Transform, Option<&mut Itinerary>, Option<&Destination>), (With<Agent>, Without<Dwell>, Without<Queued>)>,
+    destinations: Query<(Entity, &Transform, &Shape), (With<Objective>, Without<Sink>)>,
 ) {
-    for (agent, agent_transform) in &agents {
+    for (agent, agent_transform, mut itinerary, heading) in &mut agents {
         let agent_position = agent_transform.translation;
 
-        for (dest_transform, dest_colider) in &destinations {
-            let destination_pos = dest_transform.translation;
-
-            match dest_colider {
-                Shape::Circle(radius) => {
-                    let distance = (destination_pos - agent_position).length() - radius;
-                    if distance <= 0. {
-                        commands.entity(agent).despawn();
-                    }
-                }
-                _ => todo!(),
+        for (destination, dest_transform, dest_colider) in &destinations {
+            let reached = dest_colider.contains(dest_transform.translation.truncate(), agent_position.truncate());
+
+            if !reached {
+                continue;
+            }
+
+            if !is_heading_to(itinerary.as_deref(), heading, destination) {
+                continue;
+            }
+
+            pass_objective(&mut commands, agent, itinerary.as_deref_mut());
+            break;
+        }
+    }
+}
+
+/// Whether an agent with this itinerary and destination is heading to
+/// `objective`. Agents with neither head to any objective.
+fn is_heading_to(itinerary: Option<&Itinerary>, destination: Option<&Destination>, objective: Entity) -> bool {
+    match (itinerary, destination) {
+        (Some(itinerary), _) => itinerary.current_stop().map(|stop| stop.destination) == Some(objective),
+        (None, Some(destination)) => destination.0 == objective,
+        (None, None) => true,
+    }
+}
+
+/// Sends an agent that reached its objective on: it leaves the simulation,
+/// or heads to its next itinerary stop once it has dwelt at this one.
+fn pass_objective(commands: &mut Commands, agent: Entity, itinerary: Option<&mut Itinerary>) {
+
+    let Some(itinerary) = itinerary else {
+        commands.entity(agent).despawn();
+        return;
+    };
+
+    let Some(stop) = itinerary.current_stop().copied() else {
+        return;
+    };
+
+    itinerary.next_stop += 1;
+
+    if stop.dwell_seconds > 0. {
+        commands.entity(agent).insert(Dwell(Timer::from_seconds(stop.dwell_seconds, TimerMode::Once)));
+    } else if itinerary.is_finished() {
+        commands.entity(agent).despawn();
+        return;
+    }
+
+    if let Some(next) = itinerary.current_stop() {
+        commands.entity(agent).insert(Destination(next.destination));
+    }
+}
+
+/// Lines agents up at the sink they are heading to once they come within the
+/// queue radius of it.
+pub fn join_sink_queues(
+    mut commands: Commands,
+    settings: Res<RouteChoiceSettings>,
+    mut sinks: Query<(Entity, &Transform, &Shape, &mut Sink)>,
+    agents: Query<(Entity, &Transform, Option<&Destination>, Option<&Itinerary>), (With<Agent>, Without<Dwell>, Without<Queued>)>,
+) {
+    for (agent, transform, destination, itinerary) in &agents {
+        let position = transform.translation.truncate();
+
+        for (sink_entity, sink_transform, shape, mut sink) in &mut sinks {
+            let center = sink_transform.translation.truncate();
+
+            if !is_heading_to(itinerary, destination, sink_entity) || shape.distance(center, position) > settings.queue_radius {
+                continue;
+            }
+
+            if sink.queue.is_empty() && sink.in_service.is_empty() {
+                sink.queue_direction = (position - center).try_normalize().unwrap_or(sink.queue_direction);
+            }
+
+            sink.queue.push(agent);
+            commands.entity(agent).insert(Queued(sink_entity));
+            break;
+        }
+    }
+}
+
+/// Lets the admitted agents that reached a sink through, one per server, and
+/// keeps those being served in place.
+pub fn sink_service_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut rng: Local<fastrand::Rng>,
+    mut sinks: Query<(Entity, &Transform, &Shape, &mut Sink)>,
+    mut agents: Query<(&Transform, &mut Speed, Option<&mut Itinerary>, &Queued)>,
+) {
+    for (sink_entity, transform, shape, mut sink) in &mut sinks {
+        let center = transform.translation.truncate();
+        let sink = &mut *sink;
+
+        let is_queued_here = |agent: Entity| agents.get(agent).is_ok_and(|(_, _, _, queued)| queued.0 == sink_entity);
+
+        sink.queue.retain(|&agent| is_queued_here(agent));
+        sink.in_service.retain(|&(agent, _)| is_queued_here(agent));
+
+        for (_, remaining) in &mut sink.in_service {
+            *remaining -= time.delta_seconds();
+        }
+
+        let (served, in_service): (Vec<_>, Vec<_>) = sink.in_service.iter().partition(|(_, remaining)| *remaining <= 0.);
+        sink.in_service = in_service;
+
+        for (agent, _) in served {
+            let Ok((_, _, mut itinerary, _)) = agents.get_mut(agent) else {
+                continue;
+            };
+
+            commands.entity(agent).remove::<Queued>();
+            pass_objective(&mut commands, agent, itinerary.as_deref_mut());
+        }
+
+        let arrived: Vec<Entity> = sink.queue.iter()
+            .take(sink.free_servers())
+            .copied()
+            .filter(|&agent| agents.get(agent).is_ok_and(|(agent_transform, _, _, _)| shape.contains(center, agent_transform.translation.truncate())))
+            .collect();
+
+        sink.queue.retain(|agent| !arrived.contains(agent));
+
+        for agent in arrived {
+            sink.in_service.push((agent, sink.service_seconds.sample(&mut rng).max(0.)));
+        }
+
+        for (agent, _) in &sink.in_service {
+            if let Ok((_, mut speed, _, _)) = agents.get_mut(*agent) {
+                speed.0 = Vec2::ZERO;
+            }
+        }
+    }
+}
+
+/// Walks the admitted agents up to their sink and the others to their place
+/// in line, instead of letting the crowd press against the sink.
+pub fn queue_steering_system(
+    sinks: Query<(&Transform, &Shape, &Sink)>,
+    obstacles_map: Res<GridMap<BlockedStatus>>,
+    mut agents: Query<(&mut MotivationForce, &Transform, &Speed, Option<&DesiredSpeed>), With<Queued>>,
+) {
+    for (transform, shape, sink) in &sinks {
+        let center = transform.translation.truncate();
+        let admitted = sink.free_servers();
+
+        let places = sink.waiting_positions(center, shape, sink.queue.len().saturating_sub(admitted), |position| {
+            obstacles_map.get_value_at(position) == Some(BlockedStatus::Empty)
+        });
+
+        for (index, agent) in sink.queue.iter().enumerate() {
+            let Ok((mut motivation_force, agent_transform, speed, desired_speed)) = agents.get_mut(*agent) else {
+                continue;
+            };
+
+            let target = match index.checked_sub(admitted) {
+                None => center,
+                Some(place) => places[place],
+            };
+
+            let desired_speed = DesiredSpeed::of(desired_speed);
+
+            motivation_force.0 = (target - agent_transform.translation.truncate()).clamp_length_max(desired_speed) - speed.0;
+        }
+    }
+}
+
+pub fn follow_system(
+    time: Res<Time>,
+    mut objectives: Query<(&mut Transform, &mut Follow), With<Objective>>,
+    followed: Query<&Transform, Without<Objective>>,
+) {
+    for (mut transform, mut follow) in &mut objectives {
+        if !follow.update.tick(time.delta()).just_finished() {
+            continue;
+        }
+
+        let Ok(followed_transform) = followed.get(follow.entity) else {
+            continue;
+        };
+
+        let position = followed_transform.translation.truncate() + follow.offset;
+
+        if position != transform.translation.truncate() {
+            transform.translation = position.extend(transform.translation.z);
+        }
+    }
+}
+
+pub fn start_itineraries(mut commands: Commands, agents: Query<(Entity, &Itinerary), Added<Itinerary>>) {
+    for (agent, itinerary) in &agents {
+        if let Some(stop) = itinerary.current_stop() {
+            commands.entity(agent).insert(Destination(stop.destination));
+        }
+    }
+}
+
+pub fn dwell_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut agents: Query<(Entity, &mut Dwell, &mut Speed, Option<&Itinerary>), With<Agent>>,
+) {
+    for (agent, mut dwell, mut speed, itinerary) in &mut agents {
+        speed.0 = Vec2::ZERO;
+
+        if !dwell.0.tick(time.delta()).finished() {
+            continue;
+        }
+
+        match itinerary {
+            Some(itinerary) if itinerary.is_finished() => commands.entity(agent).despawn(),
+            _ => {
+                commands.entity(agent).remove::<Dwell>();
             }
         }
     }
@@ -139,7 +342,7 @@ pub fn agent_araived_at_destination_system(
 
 pub fn obstacle_force(
     mut agents: Query<(&mut ObstacleForce, &Transform), With<Agent>>,
-    obstacles: Query<&Transform, With<Obstacle>>,
+    obstacles: Query<(&Transform, &Shape), With<Obstacle>>,
 ) {
 
     for (mut force, _)in &mut agents{
@@ -147,12 +350,10 @@ pub fn obstacle_force(
     }
     
     for (mut obstacle_force, agent_transform) in &mut agents {
-        for obstacle_transform in &obstacles {
-            let effective_distance = (obstacle_transform.translation.with_z(0.)
-                - agent_transform.translation.with_z(0.))
-            .length()
-                - AGENT_RADIUS
-                - 50.;
+        for (obstacle_transform, shape) in &obstacles {
+            let (distance, normal) = shape.distance_with_normal(obstacle_transform.translation.truncate(), agent_transform.translation.truncate());
+
+            let effective_distance = distance - AGENT_RADIUS;
             let effective_distance = effective_distance / PIXELS_PER_METER;
 
             let a = 2000.;
@@ -161,9 +362,7 @@ pub fn obstacle_force(
             let kappa = 240000.;
             let g = 0.;
 
-            let n = (agent_transform.translation - obstacle_transform.translation)
-                .with_z(0.)
-                .normalize();
+            let n = normal.extend(0.);
             let t = Vec3::new(-n.y, n.x, 0.);
 
             let repulsive_factor = a * (-effective_distance / b).exp();
@@ -218,9 +417,9 @@ pub fn apply_repulsive_forces(mut agents: Query<(&mut RepulsiveForce, &Transform
     }
 }
 
-pub fn agent_max_speed_system(mut agents: Query<&mut Speed, With<Agent>>) {
-    for mut speed in &mut agents {
-        speed.0 = speed.0.clamp_length_max(AGENT_DESIRED_SPEED);
+pub fn agent_max_speed_system(mut agents: Query<(&mut Speed, Option<&DesiredSpeed>), With<Agent>>) {
+    for (mut speed, desired_speed) in &mut agents {
+        speed.0 = speed.0.clamp_length_max(DesiredSpeed::of(desired_speed));
     }
 }
 
@@ -277,3 +476,30 @@ pub fn show_social_forces(
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_objective(world: &mut World, x: f32) -> Entity {
+        world.spawn((Objective, Transform::from_xyz(x, 0., 0.), Shape::Circle(1.))).id()
+    }
+
+    #[test]
+    fn agents_cross_objectives_they_are_not_heading_to() {
+        let mut app = App::new();
+        app.add_systems(Update, agent_araived_at_destination_system);
+
+        let target = spawn_objective(app.world_mut(), 10.);
+        spawn_objective(app.world_mut(), 0.);
+
+        let agent = app.world_mut().spawn((Agent, Transform::from_xyz(0., 0., 0.), Destination(target))).id();
+
+        app.update();
+        assert!(app.world().get_entity(agent).is_some(), "left through an objective it wasn't heading to");
+
+        app.world_mut().entity_mut(agent).insert(Transform::from_xyz(10., 0., 0.));
+        app.update();
+        assert!(app.world().get_entity(agent).is_none(), "didn't leave through its destination");
+    }
+}